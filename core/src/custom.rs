@@ -0,0 +1,243 @@
+use crate::toxic::{StreamDirection, BUILTIN_TOXIC_TYPES};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Sink, Stream};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+
+/// The chunks coming into a custom toxic, from the previous toxic or the socket
+pub type ToxicInput<'a> = Pin<&'a mut (dyn Stream<Item = Bytes> + Send)>;
+
+/// Where a custom toxic sends chunks to. Sending fails when the next toxic or the socket is gone
+pub type ToxicOutput<'a> = Pin<&'a mut (dyn Sink<Bytes, Error = io::Error> + Send)>;
+
+/// Builds a custom toxic from the `attributes` object of the toxic JSON.
+/// Returns an error message if the attributes are invalid.
+pub type CustomToxicFactory = dyn Fn(&Value) -> Result<Arc<dyn CustomToxic>, String> + Send + Sync;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<String, Arc<CustomToxicFactory>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// A toxic defined outside of this crate
+///
+/// Implementations read chunks from the input and write (possibly modified) chunks to the
+/// output, just like the built-in toxics. Returning ends this direction of the connection.
+#[async_trait]
+pub trait CustomToxic: Send + Sync {
+    /// Run the toxic on one direction of a client connection
+    async fn run(
+        &self,
+        input: ToxicInput<'_>,
+        output: ToxicOutput<'_>,
+        ctx: ToxicContext,
+    ) -> io::Result<()>;
+
+    /// The capacity of the channel feeding this toxic, in number of chunks
+    fn chunk_buffer_capacity(&self) -> usize {
+        1
+    }
+}
+
+/// Information about where a custom toxic is running
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToxicContext {
+    /// The unique name of the toxic
+    pub name: String,
+    /// The direction the toxic is installed on
+    pub direction: StreamDirection,
+    /// The address of the client connected to the proxy
+    pub client: SocketAddr,
+    /// The proxy random seed, if set. Use it to make random decisions reproducible
+    pub rand_seed: Option<u64>,
+}
+
+/// A custom toxic built from a registered type and its attributes
+#[derive(Clone)]
+pub struct CustomToxicKind {
+    type_name: String,
+    attributes: Value,
+    toxic: Arc<dyn CustomToxic>,
+}
+
+/// Errors returned when registering a custom toxic type
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum RegisterError {
+    /// A built-in toxic already uses this type name
+    #[error("{0} is a built-in toxic type")]
+    BuiltinType(String),
+}
+
+/// Register a custom toxic type whose `attributes` deserialize into `T`.
+/// Registering the same type name again replaces the previous registration.
+pub fn register_custom_toxic<T>(type_name: &str) -> Result<(), RegisterError>
+where
+    T: CustomToxic + DeserializeOwned + 'static,
+{
+    register_custom_toxic_factory(type_name, |attributes: &Value| {
+        serde_json::from_value::<T>(attributes.clone())
+            .map(|toxic| Arc::new(toxic) as Arc<dyn CustomToxic>)
+            .map_err(|err| err.to_string())
+    })
+}
+
+/// Register a custom toxic type with a factory that builds the toxic from its `attributes`.
+/// Registering the same type name again replaces the previous registration.
+pub fn register_custom_toxic_factory<F>(type_name: &str, factory: F) -> Result<(), RegisterError>
+where
+    F: Fn(&Value) -> Result<Arc<dyn CustomToxic>, String> + Send + Sync + 'static,
+{
+    if BUILTIN_TOXIC_TYPES.contains(&type_name) {
+        return Err(RegisterError::BuiltinType(type_name.to_owned()));
+    }
+    REGISTRY
+        .write()
+        .expect("Custom toxic registry poisoned")
+        .insert(type_name.to_owned(), Arc::new(factory));
+    Ok(())
+}
+
+/// Remove a custom toxic type. Toxics already added to proxies keep running.
+/// Returns false if the type was not registered.
+pub fn unregister_custom_toxic(type_name: &str) -> bool {
+    REGISTRY
+        .write()
+        .expect("Custom toxic registry poisoned")
+        .remove(type_name)
+        .is_some()
+}
+
+/// Returns true if there is a custom toxic registered with this type name
+pub fn is_custom_toxic_registered(type_name: &str) -> bool {
+    REGISTRY
+        .read()
+        .expect("Custom toxic registry poisoned")
+        .contains_key(type_name)
+}
+
+impl CustomToxicKind {
+    /// Build the custom toxic with the registered factory.
+    /// Returns None if the type is not registered.
+    pub(crate) fn build(type_name: &str, attributes: Value) -> Option<Result<Self, String>> {
+        let factory = REGISTRY
+            .read()
+            .expect("Custom toxic registry poisoned")
+            .get(type_name)
+            .cloned()?;
+        Some(factory(&attributes).map(|toxic| CustomToxicKind {
+            type_name: type_name.to_owned(),
+            attributes,
+            toxic,
+        }))
+    }
+
+    /// The registered type name
+    pub fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    /// The attributes the toxic was built from
+    pub fn attributes(&self) -> &Value {
+        &self.attributes
+    }
+
+    pub(crate) fn toxic(&self) -> &dyn CustomToxic {
+        self.toxic.as_ref()
+    }
+}
+
+impl fmt::Debug for CustomToxicKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomToxicKind")
+            .field("type_name", &self.type_name)
+            .field("attributes", &self.attributes)
+            .finish()
+    }
+}
+
+impl PartialEq for CustomToxicKind {
+    fn eq(&self, other: &Self) -> bool {
+        self.type_name == other.type_name && self.attributes == other.attributes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toxic::{Toxic, ToxicKind};
+    use futures::{SinkExt, StreamExt};
+    use serde::Deserialize;
+    use serde_json::{from_str, json, to_string};
+
+    #[derive(Debug, Deserialize)]
+    struct Repeat {
+        times: usize,
+    }
+
+    #[async_trait]
+    impl CustomToxic for Repeat {
+        async fn run(
+            &self,
+            mut input: ToxicInput<'_>,
+            mut output: ToxicOutput<'_>,
+            _ctx: ToxicContext,
+        ) -> io::Result<()> {
+            while let Some(chunk) = input.next().await {
+                for _ in 0..self.times {
+                    output.send(chunk.clone()).await?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejects_builtin_type_names() {
+        assert_eq!(
+            Err(RegisterError::BuiltinType("latency".to_owned())),
+            register_custom_toxic::<Repeat>("latency")
+        );
+    }
+
+    #[test]
+    fn deserializes_and_serializes_registered_type() {
+        register_custom_toxic::<Repeat>("test_repeat").unwrap();
+        let input = "{\"name\":\"rep\",\"type\":\"test_repeat\",\"attributes\":{\"times\":2}}";
+        let toxic: Toxic = from_str(input).unwrap();
+        match &toxic.kind {
+            ToxicKind::Custom(custom) => {
+                assert_eq!("test_repeat", custom.type_name());
+                assert_eq!(&json!({"times": 2}), custom.attributes());
+            }
+            other => panic!("unexpected toxic kind {:?}", other),
+        }
+        assert_eq!("rep: test_repeat({\"times\":2})", toxic.to_string());
+
+        let serialized = to_string(&toxic).unwrap();
+        assert_eq!(toxic, from_str::<Toxic>(&serialized).unwrap());
+    }
+
+    #[test]
+    fn reports_invalid_attributes() {
+        register_custom_toxic::<Repeat>("test_repeat_invalid").unwrap();
+        let input = "{\"type\":\"test_repeat_invalid\",\"attributes\":{\"times\":-1}}";
+        let err = from_str::<Toxic>(input).unwrap_err();
+        assert!(err.to_string().contains("test_repeat_invalid"));
+    }
+
+    #[test]
+    fn unknown_types_still_fail() {
+        assert!(!unregister_custom_toxic("test_not_registered"));
+        let input = "{\"type\":\"test_not_registered\",\"attributes\":{}}";
+        assert!(from_str::<Toxic>(input).is_err());
+    }
+}
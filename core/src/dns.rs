@@ -0,0 +1,149 @@
+use crate::socket::unix_socket_path;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::sleep;
+
+/// How a proxy resolves the host names of its upstreams. With these settings the proxy
+/// resolves the name itself on every connection, instead of leaving it to the connect call
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DnsConfig {
+    /// Host names to resolve to fixed addresses, like an /etc/hosts file for this proxy
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, Vec<IpAddr>>,
+    /// A fault to inject into every host name resolution
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault: Option<DnsFault>,
+}
+
+/// A resolution failure to simulate. IP addresses and Unix socket paths are never affected
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DnsFault {
+    /// The name does not exist
+    #[serde(rename = "nxdomain")]
+    NxDomain,
+    /// The name server failed to answer
+    #[serde(rename = "servfail")]
+    ServFail,
+    /// The answer arrives after a delay
+    #[serde(rename = "slow")]
+    Slow {
+        /// The delay in milliseconds
+        delay: u64,
+    },
+}
+
+/// Resolve the host name of an upstream address with the DNS settings of the proxy.
+/// Returns the address to connect to, unchanged if the proxy has no DNS settings
+pub(crate) async fn resolve(dns: Option<&DnsConfig>, upstream: &str) -> io::Result<String> {
+    let dns = match dns {
+        Some(dns) if unix_socket_path(upstream).is_none() => dns,
+        _ => return Ok(upstream.to_owned()),
+    };
+    let (host, port) = split_host_port(upstream)?;
+    if host.parse::<IpAddr>().is_ok() {
+        return Ok(upstream.to_owned());
+    }
+
+    match dns.fault {
+        Some(DnsFault::NxDomain) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("failed to resolve {}: NXDOMAIN", host),
+            ));
+        }
+        Some(DnsFault::ServFail) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to resolve {}: SERVFAIL", host),
+            ));
+        }
+        Some(DnsFault::Slow { delay }) => sleep(Duration::from_millis(delay)).await,
+        None => {}
+    }
+
+    let addr = match dns.hosts.get(host).and_then(|ips| ips.first()) {
+        Some(ip) => SocketAddr::new(*ip, port),
+        None => lookup_host((host, port)).await?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses for {}", host),
+            )
+        })?,
+    };
+    Ok(addr.to_string())
+}
+
+fn split_host_port(upstream: &str) -> io::Result<(&str, u16)> {
+    upstream
+        .rfind(':')
+        .and_then(|colon| {
+            let host = upstream[..colon]
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            Some((host, upstream[colon + 1..].parse().ok()?))
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid upstream address {}", upstream),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    fn dns(fault: Option<DnsFault>) -> DnsConfig {
+        let mut hosts = BTreeMap::new();
+        hosts.insert("db.internal".to_owned(), vec!["10.0.0.7".parse().unwrap()]);
+        DnsConfig { hosts, fault }
+    }
+
+    #[tokio::test]
+    async fn resolves_host_overrides() {
+        let dns = dns(None);
+        assert_eq!(
+            "10.0.0.7:5432",
+            resolve(Some(&dns), "db.internal:5432").await.unwrap()
+        );
+        assert_eq!(
+            "[::1]:5432",
+            resolve(Some(&dns), "[::1]:5432").await.unwrap()
+        );
+        assert_eq!(
+            "db.internal:5432",
+            resolve(None, "db.internal:5432").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn injects_failures() {
+        let dns = dns(Some(DnsFault::NxDomain));
+        let err = resolve(Some(&dns), "db.internal:5432").await.unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+        let dns = self::dns(Some(DnsFault::ServFail));
+        assert!(resolve(Some(&dns), "db.internal:5432").await.is_err());
+        assert!(resolve(Some(&dns), "10.0.0.1:5432").await.is_ok());
+        assert!(resolve(Some(&dns), "unix:/tmp/db.sock").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn delays_slow_resolution() {
+        tokio::time::pause();
+        let dns = dns(Some(DnsFault::Slow { delay: 3000 }));
+        let start = Instant::now();
+        assert_eq!(
+            "10.0.0.7:5432",
+            resolve(Some(&dns), "db.internal:5432").await.unwrap()
+        );
+        assert!(start.elapsed() >= Duration::from_millis(3000));
+    }
+}
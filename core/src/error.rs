@@ -11,11 +11,19 @@ pub enum ToxicUpdateError {
     /// No such toxic with the given name
     #[error("Toxic not found")]
     NotFound,
+    /// The toxic named in `before` or `after` is not in the chain
+    #[error("Toxic position target not found")]
+    PositionNotFound,
     /// Some other error
     #[error("Other error")]
     Other,
 }
 
+/// The string is not an IP address or a CIDR block
+#[derive(Debug, Clone, Error, PartialEq)]
+#[error("invalid IP address or CIDR block: {0}")]
+pub struct CidrParseError(pub String);
+
 impl From<NotFoundError> for ToxicUpdateError {
     fn from(_: NotFoundError) -> Self {
         ToxicUpdateError::NotFound
@@ -21,11 +21,19 @@
 
 //! # noxious
 
+/// Contains the trait and the registry for toxics defined outside of this crate
+pub mod custom;
+/// Contains the DNS settings of proxies
+pub mod dns;
 /// Contains the errors
 pub mod error;
 mod link;
+/// Contains the rules for applying toxics to some connections only
+pub mod matcher;
 /// Contains the proxy data types and runners
 pub mod proxy;
+/// Contains the HAProxy PROXY protocol support
+pub mod proxy_protocol;
 /// Contains the Stop and Close signals
 pub mod signal;
 /// Contains wrappers around Tokio types to make them mockable
@@ -34,6 +42,12 @@ pub mod socket;
 pub mod state;
 mod stream;
 mod tests;
+/// Contains the TLS settings of proxies
+pub mod tls;
 /// Contains the toxic data types
 pub mod toxic;
 mod toxics;
+/// Contains the UDP proxy runner
+pub mod udp;
+/// Contains the policies for spreading connections over upstreams
+pub mod upstream;
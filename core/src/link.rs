@@ -1,18 +1,22 @@
 use crate::{
+    custom::ToxicContext,
     proxy::ProxyConfig,
     signal::{Close, Closer, Stop, Stopper},
     state::{ToxicState, ToxicStateHolder},
     stream::{forward, forward_read, forward_write, Read, Write},
     toxic::ToxicKind,
-    toxic::{StreamDirection, Toxic},
+    toxic::{StreamDirection, Toxic, ToxicSampling},
     toxics,
 };
 use bytes::Bytes;
 use futures::channel::mpsc as futures_mpsc;
-use futures::StreamExt;
-use futures::{Sink, Stream};
+use futures::{future, stream, Sink, Stream};
+use futures::{SinkExt, StreamExt};
+use pin_project_lite::pin_project;
 use rand::{distributions::Standard, rngs::StdRng, Rng, SeedableRng};
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::{io, sync::Arc};
 use tokio::pin;
 use tokio::sync::{oneshot, Mutex as AsyncMutex};
@@ -23,6 +27,10 @@ use tracing::{debug, instrument};
 pub(crate) struct Link {
     config: ProxyConfig,
     upstream_addr: SocketAddr,
+    /// The order the proxy accepted this connection in, starting from 1
+    connection: u64,
+    /// The upstream address this connection goes to
+    upstream: String,
     direction: StreamDirection,
     stop: Stop,
     stopper: Stopper,
@@ -31,9 +39,40 @@ pub(crate) struct Link {
 
 type Ends = (Read, Write);
 
+pin_project! {
+    /// Passes chunks through to the inner sink but only flushes it on close, so a toxic
+    /// that runs on a single chunk can't close the output of the whole link
+    struct KeepOpen<S> {
+        #[pin]
+        inner: S,
+    }
+}
+
+impl<S: Sink<Bytes>> Sink<Bytes> for KeepOpen<S> {
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+}
+
 impl Link {
     pub(crate) fn new(
         upstream_addr: SocketAddr,
+        connection: u64,
+        upstream: String,
         direction: StreamDirection,
         config: ProxyConfig,
         stop: Stop,
@@ -42,6 +81,8 @@ impl Link {
         Link {
             config,
             upstream_addr,
+            connection,
+            upstream,
             direction,
             stop,
             stopper,
@@ -58,6 +99,18 @@ impl Link {
     ) -> JoinHandle<()> {
         let (disband_sender, disband_receiver) = oneshot::channel::<Ends>();
         self.disband_receiver = Some(disband_receiver);
+        let (client_addr, connection) = (self.upstream_addr, self.connection);
+        let direction = self.direction;
+        let upstream = &self.upstream;
+        let toxics: Vec<Toxic> = toxics
+            .into_iter()
+            .filter(|toxic| toxic.enabled && toxic.matches(client_addr, connection, upstream))
+            .map(|mut toxic| {
+                // Toxics on both directions run with the direction of this link
+                toxic.direction = direction;
+                toxic
+            })
+            .collect();
         if toxics.is_empty() {
             self.forward_direct(reader, writer, disband_sender)
         } else {
@@ -146,11 +199,12 @@ impl Link {
         prev_pipe_read_rx: futures_mpsc::Receiver<Bytes>,
         toxic_state_holder: Option<Arc<ToxicStateHolder>>,
     ) -> futures_mpsc::Receiver<Bytes> {
-        let toxic_name = runner.toxic_name();
-        let toxic_state =
-            toxic_state_holder.and_then(|holder| holder.get_state_for_toxic(toxic_name));
+        let toxic_state = toxic_state_holder.and_then(|holder| {
+            holder.get_state_for_toxic(runner.toxic_name(), runner.toxic_direction())
+        });
         let mut stop = stop.clone();
         let rand_seed = self.config.rand_seed;
+        let client_addr = self.upstream_addr;
         // Get the desired channel buffer capacity for the toxic (in number of chunks)
         // This is 1024 for the Latency toxic and 1 for others, similar
         // to the original Toxiproxy implementation.
@@ -158,7 +212,7 @@ impl Link {
             futures_mpsc::channel::<Bytes>(runner.toxic_kind().chunk_buffer_capacity());
         tokio::spawn(async move {
             let maybe_res = tokio::select! {
-                res = runner.run(prev_pipe_read_rx, pipe_tx, toxic_state, rand_seed) => Some(res),
+                res = runner.run(prev_pipe_read_rx, pipe_tx, toxic_state, rand_seed, client_addr) => Some(res),
                 _ = stop.recv() => None,
             };
             if let Some(Err(err)) = maybe_res {
@@ -282,7 +336,12 @@ impl Link {
         self.stopper.stop();
         let (reader, writer) = self
             .disband_receiver
-            .expect("State error: Link already disbanded, or never established")
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "State error: Link already disbanded, or never established",
+                )
+            })?
             .await
             .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "already closed?"))?;
 
@@ -302,26 +361,45 @@ pub(crate) struct ToxicRunner {
     toxic: Toxic,
     closer: Option<Closer>,
     override_stop: Option<Stop>,
+    /// The chunks are UDP datagrams
+    datagrams: bool,
 }
 
 impl ToxicRunner {
     pub fn new((toxic, threshold): (Toxic, f32)) -> Self {
+        // Per chunk sampling happens in `run`, so the runner is always active
+        let active = toxic.toxicity >= threshold || Self::samples_per_chunk(&toxic);
         ToxicRunner {
-            active: toxic.toxicity >= threshold,
+            active,
             toxic,
             closer: None,
             override_stop: None,
+            datagrams: false,
         }
     }
 
+    /// Run the toxics that count bytes, like bandwidth, on datagrams instead
+    pub fn for_datagrams(mut self) -> Self {
+        self.datagrams = true;
+        self
+    }
+
     pub fn is_active(&self) -> bool {
         self.active
     }
 
+    fn samples_per_chunk(toxic: &Toxic) -> bool {
+        toxic.sampling == ToxicSampling::PerChunk && toxic.kind.supports_per_chunk_sampling()
+    }
+
     pub fn toxic_name(&self) -> &str {
         &self.toxic.name
     }
 
+    pub fn toxic_direction(&self) -> StreamDirection {
+        self.toxic.direction
+    }
+
     pub fn toxic_kind(&self) -> &ToxicKind {
         &self.toxic.kind
     }
@@ -334,61 +412,150 @@ impl ToxicRunner {
         self.override_stop = Some(stop);
     }
 
-    fn take_override_stop(&mut self) -> Stop {
-        self.override_stop
-            .take()
-            .expect("State error: cannot run toxic without a override stop signal")
+    fn take_override_stop(&mut self) -> io::Result<Stop> {
+        self.override_stop.take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "State error: cannot run toxic without a override stop signal",
+            )
+        })
     }
 
     pub async fn run(
         &mut self,
-        input: impl Stream<Item = Bytes>,
-        output: impl Sink<Bytes>,
+        input: impl Stream<Item = Bytes> + Send,
+        output: impl Sink<Bytes> + Send,
         state: Option<Arc<AsyncMutex<ToxicState>>>,
         rand_seed: Option<u64>,
+        client_addr: SocketAddr,
     ) -> io::Result<()> {
         pin!(input);
         pin!(output);
-        let result = if self.active {
-            match self.toxic.kind {
-                ToxicKind::Noop => toxics::run_noop(input, output).await,
-                ToxicKind::Latency { latency, jitter } => {
-                    toxics::run_latency(input, output, latency, jitter, rand_seed).await
-                }
-                ToxicKind::Timeout { timeout } => toxics::run_timeout(input, output, timeout).await,
-                ToxicKind::Bandwidth { rate } => toxics::run_bandwidth(input, output, rate).await,
-                ToxicKind::SlowClose { delay } => {
-                    let stop = self.take_override_stop();
-                    toxics::run_slow_close(input, output, stop, delay).await
-                }
-                ToxicKind::Slicer {
-                    average_size,
-                    size_variation,
-                    delay,
-                } => {
-                    toxics::run_slicer(
-                        input,
-                        output,
-                        average_size,
-                        size_variation,
-                        delay,
-                        rand_seed,
-                    )
-                    .await
-                }
-                ToxicKind::LimitData { bytes } => {
-                    let stop = self.take_override_stop();
-                    toxics::run_limit_data(input, output, stop, bytes, state).await
-                }
-            }
-        } else {
+        let result = if !self.active {
             toxics::run_noop(input, output).await
+        } else if Self::samples_per_chunk(&self.toxic) {
+            self.run_per_chunk(input, output, rand_seed, client_addr)
+                .await
+        } else {
+            self.run_toxic(input, output, state, rand_seed, client_addr)
+                .await
         };
         if let Some(closer) = self.closer.take() {
             let _ = closer.close();
         }
         return result;
     }
+
+    /// Runs the toxic on each chunk separately, for the fraction of chunks given by the toxicity.
+    /// The other chunks are passed through as they are.
+    async fn run_per_chunk(
+        &mut self,
+        mut input: Pin<&mut (impl Stream<Item = Bytes> + Send)>,
+        mut output: Pin<&mut (impl Sink<Bytes> + Send)>,
+        rand_seed: Option<u64>,
+        client_addr: SocketAddr,
+    ) -> io::Result<()> {
+        let mut rand_gen = if let Some(seed) = rand_seed {
+            StdRng::seed_from_u64(seed)
+        } else {
+            StdRng::from_entropy()
+        };
+        while let Some(chunk) = input.next().await {
+            let threshold: f32 = rand_gen.gen();
+            if self.toxic.toxicity >= threshold {
+                // Derive a seed per chunk, so seeded toxics don't repeat the same random values
+                let chunk_seed = rand_seed.map(|_| rand_gen.gen());
+                if let ToxicKind::Timeout { .. } = self.toxic.kind {
+                    // A timed out chunk never arrives, the link itself stays up
+                    continue;
+                }
+                let chunk = stream::once(future::ready(chunk));
+                let output = KeepOpen {
+                    inner: output.as_mut(),
+                };
+                self.run_toxic(chunk, output, None, chunk_seed, client_addr)
+                    .await?;
+            } else {
+                toxics::send_chunk(&mut output, chunk).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_toxic(
+        &mut self,
+        input: impl Stream<Item = Bytes> + Send,
+        output: impl Sink<Bytes> + Send,
+        state: Option<Arc<AsyncMutex<ToxicState>>>,
+        rand_seed: Option<u64>,
+        client_addr: SocketAddr,
+    ) -> io::Result<()> {
+        pin!(input);
+        pin!(output);
+        match self.toxic.kind {
+            ToxicKind::Noop => toxics::run_noop(input, output).await,
+            ToxicKind::Latency { latency, jitter } => {
+                toxics::run_latency(input, output, latency, jitter, rand_seed).await
+            }
+            ToxicKind::Timeout { timeout } => toxics::run_timeout(input, output, timeout).await,
+            ToxicKind::Bandwidth { rate } if self.datagrams => {
+                toxics::run_datagram_bandwidth(input, output, rate).await
+            }
+            ToxicKind::Bandwidth { rate } => toxics::run_bandwidth(input, output, rate).await,
+            ToxicKind::SlowClose { delay } => {
+                let stop = self.take_override_stop()?;
+                toxics::run_slow_close(input, output, stop, delay).await
+            }
+            ToxicKind::Slicer {
+                average_size,
+                size_variation,
+                delay,
+            } => {
+                toxics::run_slicer(
+                    input,
+                    output,
+                    average_size,
+                    size_variation,
+                    delay,
+                    rand_seed,
+                )
+                .await
+            }
+            ToxicKind::LimitData { bytes } => {
+                let stop = self.take_override_stop()?;
+                toxics::run_limit_data(input, output, stop, bytes, state).await
+            }
+            ToxicKind::TlsHandshake {
+                stage,
+                action,
+                timeout,
+            } => toxics::run_tls_handshake(input, output, stage, action, timeout).await,
+            ToxicKind::WebSocket {
+                action,
+                every,
+                delay,
+                code,
+            } => toxics::run_websocket(input, output, action, every, delay, code).await,
+            ToxicKind::Drop => toxics::run_drop(input, output).await,
+            ToxicKind::Duplicate { copies } => toxics::run_duplicate(input, output, copies).await,
+            ToxicKind::Reorder { window, timeout } => {
+                toxics::run_reorder(input, output, window, timeout, rand_seed).await
+            }
+            ToxicKind::Custom(ref custom) => {
+                let ctx = ToxicContext {
+                    name: self.toxic.name.clone(),
+                    direction: self.toxic.direction,
+                    client: client_addr,
+                    rand_seed,
+                };
+                let output = output.sink_map_err(|_| {
+                    io::Error::new(io::ErrorKind::ConnectionReset, "Write channel closed")
+                });
+                pin!(output);
+                custom.toxic().run(input, output, ctx).await
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -397,6 +564,15 @@ mod tests {
     use tokio_test::{assert_err, assert_ok};
 
     use super::*;
+    use crate::custom::{
+        register_custom_toxic_factory, CustomToxic, CustomToxicKind, ToxicInput, ToxicOutput,
+    };
+    use async_trait::async_trait;
+    use std::time::Duration;
+
+    fn client_addr() -> SocketAddr {
+        "127.0.0.1:29991".parse().unwrap()
+    }
 
     #[test]
     fn toxic_runner_take_override_stop() {
@@ -405,11 +581,13 @@ mod tests {
             kind: ToxicKind::Noop,
             direction: StreamDirection::Upstream,
             toxicity: 1.0,
+            ..Default::default()
         };
         let mut runner = ToxicRunner::new((toxic, 0.9));
         let (stop, stopper) = Stop::new();
+        assert!(runner.take_override_stop().is_err());
         runner.set_override_stop(stop);
-        let _stop = runner.take_override_stop();
+        assert!(runner.take_override_stop().is_ok());
         stopper.stop();
     }
 
@@ -424,6 +602,7 @@ mod tests {
             },
             direction: StreamDirection::Upstream,
             toxicity: 1.0,
+            ..Default::default()
         };
 
         let mut runner = ToxicRunner::new((slicer, 1.0));
@@ -431,7 +610,7 @@ mod tests {
         let (tx2, mut rx2) = futures::channel::mpsc::channel::<Bytes>(1);
         assert_ok!(tx.send("chop chop".into()).await);
         let handle = tokio::spawn(async move {
-            let res = runner.run(rx, tx2, None, None).await;
+            let res = runner.run(rx, tx2, None, None, client_addr()).await;
             assert_ok!(res);
         });
         assert_eq!(Some("chop".into()), rx2.next().await);
@@ -442,6 +621,103 @@ mod tests {
         assert_ok!(handle.await);
     }
 
+    #[tokio::test]
+    async fn run_slicer_per_chunk() {
+        let slicer = Toxic {
+            name: "slicer slices".to_owned(),
+            kind: ToxicKind::Slicer {
+                average_size: 2,
+                size_variation: 0,
+                delay: 0,
+            },
+            direction: StreamDirection::Upstream,
+            toxicity: 0.5,
+            sampling: ToxicSampling::PerChunk,
+            ..Default::default()
+        };
+        let rand_seed = Some(7);
+
+        // Replay the sampling to know which chunks get sliced
+        let mut rand_gen = StdRng::seed_from_u64(7);
+        let mut expected: Vec<Bytes> = Vec::new();
+        for _ in 0..16 {
+            let threshold: f32 = rand_gen.gen();
+            if 0.5 >= threshold {
+                let _: u64 = rand_gen.gen();
+                expected.push("ab".into());
+                expected.push("cd".into());
+            } else {
+                expected.push("abcd".into());
+            }
+        }
+        assert!(expected.len() > 16 && expected.len() < 32);
+
+        let mut runner = ToxicRunner::new((slicer, 1.0));
+        assert!(runner.is_active());
+        let (mut tx, rx) = futures::channel::mpsc::channel::<Bytes>(1);
+        let (tx2, rx2) = futures::channel::mpsc::channel::<Bytes>(1);
+        let handle = tokio::spawn(async move {
+            let res = runner.run(rx, tx2, None, rand_seed, client_addr()).await;
+            assert_ok!(res);
+        });
+        tokio::spawn(async move {
+            for _ in 0..16 {
+                assert_ok!(tx.send("abcd".into()).await);
+            }
+        });
+        assert_eq!(expected, rx2.collect::<Vec<Bytes>>().await);
+        assert_ok!(handle.await);
+    }
+
+    /// Runs the toxic per chunk with toxicity 1.0 over three chunks, returns what came out
+    async fn run_every_chunk(kind: ToxicKind) -> (io::Result<()>, Vec<Bytes>) {
+        let toxic = Toxic {
+            name: "every chunk".to_owned(),
+            kind,
+            direction: StreamDirection::Upstream,
+            toxicity: 1.0,
+            sampling: ToxicSampling::PerChunk,
+            ..Default::default()
+        };
+        let mut runner = ToxicRunner::new((toxic, 1.0));
+        let (mut tx, rx) = futures::channel::mpsc::channel::<Bytes>(3);
+        let (tx2, rx2) = futures::channel::mpsc::channel::<Bytes>(3);
+        for chunk in &["one", "two", "three"] {
+            assert_ok!(tx.send(Bytes::from_static(chunk.as_bytes())).await);
+        }
+        drop(tx);
+        let result = runner.run(rx, tx2, None, None, client_addr()).await;
+        (result, rx2.collect().await)
+    }
+
+    #[tokio::test]
+    async fn run_noop_per_chunk() {
+        let (result, chunks) = run_every_chunk(ToxicKind::Noop).await;
+        assert_ok!(result);
+        assert_eq!(vec!["one", "two", "three"], chunks);
+    }
+
+    #[tokio::test]
+    async fn run_latency_per_chunk() {
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+        let (result, chunks) = run_every_chunk(ToxicKind::Latency {
+            latency: 100,
+            jitter: 0,
+        })
+        .await;
+        assert_ok!(result);
+        assert_eq!(vec!["one", "two", "three"], chunks);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn run_timeout_per_chunk() {
+        let (result, chunks) = run_every_chunk(ToxicKind::Timeout { timeout: 1000 }).await;
+        assert_ok!(result);
+        assert!(chunks.is_empty());
+    }
+
     #[tokio::test]
     async fn run_slicer_recv_drop() {
         let slicer = Toxic {
@@ -453,6 +729,7 @@ mod tests {
             },
             direction: StreamDirection::Upstream,
             toxicity: 1.0,
+            ..Default::default()
         };
 
         let mut runner = ToxicRunner::new((slicer, 1.0));
@@ -460,7 +737,7 @@ mod tests {
         let (tx2, mut rx2) = futures::channel::mpsc::channel::<Bytes>(1);
         assert_ok!(tx.send("chop chop".into()).await);
         let handle = tokio::spawn(async move {
-            let res = runner.run(rx, tx2, None, None).await;
+            let res = runner.run(rx, tx2, None, None, client_addr()).await;
             assert_err!(&res);
             assert_eq!(std::io::ErrorKind::ConnectionReset, res.unwrap_err().kind());
         });
@@ -481,6 +758,7 @@ mod tests {
             },
             direction: StreamDirection::Upstream,
             toxicity: 0.3,
+            ..Default::default()
         };
 
         let mut runner = ToxicRunner::new((slicer, 0.9));
@@ -488,7 +766,7 @@ mod tests {
         let (tx2, mut rx2) = futures::channel::mpsc::channel::<Bytes>(1);
         assert_ok!(tx.send("chop chop".into()).await);
         let handle = tokio::spawn(async move {
-            let res = runner.run(rx, tx2, None, None).await;
+            let res = runner.run(rx, tx2, None, None, client_addr()).await;
             assert_ok!(res);
         });
         assert_eq!(Some("chop chop".into()), rx2.next().await);
@@ -497,6 +775,45 @@ mod tests {
         assert_ok!(handle.await);
     }
 
+    #[tokio::test]
+    async fn run_limit_data_both_directions() {
+        let limiter = Toxic {
+            name: "limiter".to_owned(),
+            kind: ToxicKind::LimitData { bytes: 4 },
+            direction: StreamDirection::Both,
+            toxicity: 1.0,
+            ..Default::default()
+        };
+        let toxics = crate::proxy::Toxics {
+            upstream: vec![limiter.clone()],
+            downstream: vec![limiter.clone()],
+        };
+        let holder = ToxicStateHolder::for_toxics(&toxics).unwrap();
+        let (stop, stopper) = Stop::new();
+
+        let mut outputs = Vec::new();
+        for direction in [StreamDirection::Upstream, StreamDirection::Downstream].iter() {
+            let mut toxic = limiter.clone();
+            toxic.direction = *direction;
+            let state = holder.get_state_for_toxic("limiter", *direction);
+            let mut runner = ToxicRunner::new((toxic, 1.0));
+            runner.set_override_stop(stop.clone());
+            let (mut tx, rx) = futures::channel::mpsc::channel::<Bytes>(1);
+            let (tx2, rx2) = futures::channel::mpsc::channel::<Bytes>(1);
+            assert_ok!(tx.send("abcdef".into()).await);
+            tokio::spawn(async move {
+                let _tx = tx;
+                runner.run(rx, tx2, state, None, client_addr()).await
+            });
+            outputs.push(rx2);
+        }
+        for output in outputs.iter_mut() {
+            let chunk = tokio::time::timeout(Duration::from_secs(1), output.next()).await;
+            assert_eq!(Some("abcd".into()), assert_ok!(chunk));
+        }
+        stopper.stop();
+    }
+
     #[tokio::test]
     async fn run_with_closer() {
         let slicer = Toxic {
@@ -504,6 +821,7 @@ mod tests {
             kind: ToxicKind::Bandwidth { rate: 48000 },
             direction: StreamDirection::Upstream,
             toxicity: 0.3,
+            ..Default::default()
         };
 
         let mut runner = ToxicRunner::new((slicer, 0.9));
@@ -513,7 +831,7 @@ mod tests {
         let (tx2, mut rx2) = futures::channel::mpsc::channel::<Bytes>(1);
         assert_ok!(tx.send("chop chop".into()).await);
         let handle = tokio::spawn(async move {
-            let res = runner.run(rx, tx2, None, None).await;
+            let res = runner.run(rx, tx2, None, None, client_addr()).await;
             assert_ok!(res);
         });
         assert_eq!(Some("chop chop".into()), rx2.next().await);
@@ -522,4 +840,57 @@ mod tests {
         assert_ok!(handle.await);
         assert_ok!(close.recv().await);
     }
+
+    struct Tagger;
+
+    #[async_trait]
+    impl CustomToxic for Tagger {
+        async fn run(
+            &self,
+            mut input: ToxicInput<'_>,
+            mut output: ToxicOutput<'_>,
+            ctx: ToxicContext,
+        ) -> io::Result<()> {
+            while let Some(chunk) = input.next().await {
+                let tagged = format!(
+                    "{}@{}:{}",
+                    ctx.name,
+                    ctx.client,
+                    String::from_utf8_lossy(&chunk)
+                );
+                output.send(tagged.into()).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn run_custom() {
+        assert_ok!(register_custom_toxic_factory("test_tagger", |_| Ok(
+            Arc::new(Tagger)
+        )));
+        let custom = CustomToxicKind::build("test_tagger", serde_json::json!({}))
+            .unwrap()
+            .unwrap();
+        let toxic = Toxic {
+            name: "tag".to_owned(),
+            kind: ToxicKind::Custom(custom),
+            direction: StreamDirection::Downstream,
+            toxicity: 1.0,
+            ..Default::default()
+        };
+
+        let mut runner = ToxicRunner::new((toxic, 1.0));
+        let (mut tx, rx) = futures::channel::mpsc::channel::<Bytes>(1);
+        let (tx2, mut rx2) = futures::channel::mpsc::channel::<Bytes>(1);
+        assert_ok!(tx.send("hi".into()).await);
+        let handle = tokio::spawn(async move {
+            let res = runner.run(rx, tx2, None, None, client_addr()).await;
+            assert_ok!(res);
+        });
+        assert_eq!(Some("tag@127.0.0.1:29991:hi".into()), rx2.next().await);
+        drop(tx);
+        assert_eq!(None, rx2.next().await);
+        assert_ok!(handle.await);
+    }
 }
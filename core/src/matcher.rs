@@ -0,0 +1,276 @@
+use crate::error::CidrParseError;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Limits a toxic to some of the client connections.
+/// Connections that don't match get a chain without this toxic.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ToxicMatch {
+    /// Client IP addresses or CIDR blocks, like 10.0.0.5 or 10.0.0.0/8.
+    /// Empty matches every address
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Cidr>,
+    /// The client port range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<PortRange>,
+    /// Selects connections by the order the proxy accepted them in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connections: Option<ConnectionRange>,
+    /// Upstream addresses, for proxies with several upstreams.
+    /// Empty matches every upstream
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<String>,
+}
+
+/// An IP address block, or a single IP address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// An inclusive range of port numbers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PortRange {
+    /// The first port in the range
+    pub from: u16,
+    /// The last port in the range
+    pub to: u16,
+}
+
+/// A range of connections, numbered in the order the proxy accepted them, starting from 1.
+/// For example `{"to": 1}` is the first connection only, `{"every": 3}` is the 3rd, 6th, 9th...
+/// connection, and `{"from": 10, "to": 20}` is connections 10 to 20.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionRange {
+    /// The first connection in the range
+    #[serde(default = "default_one")]
+    pub from: u64,
+    /// The last connection in the range, inclusive. The range has no end if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u64>,
+    /// Only every Nth connection in the range, counting from `from`. 0 is treated as 1
+    #[serde(default = "default_one")]
+    pub every: u64,
+}
+
+fn default_one() -> u64 {
+    1
+}
+
+impl ConnectionRange {
+    /// Returns true if the connection with this ordinal is in the range
+    pub fn contains(&self, connection: u64) -> bool {
+        if connection < self.from || self.to.map_or(false, |to| connection > to) {
+            return false;
+        }
+        (connection - self.from + 1) % self.every.max(1) == 0
+    }
+}
+
+impl ToxicMatch {
+    /// Returns true if the client connection should get the toxic.
+    /// `connection` is the order the proxy accepted the connection in, starting from 1,
+    /// and `upstream` is the upstream address the connection goes to.
+    pub fn matches(&self, client: SocketAddr, connection: u64, upstream: &str) -> bool {
+        let source_matches =
+            self.sources.is_empty() || self.sources.iter().any(|cidr| cidr.contains(client.ip()));
+        let port_matches = self.ports.map_or(true, |range| {
+            (range.from..=range.to).contains(&client.port())
+        });
+        let connection_matches = self
+            .connections
+            .map_or(true, |range| range.contains(connection));
+        let upstream_matches =
+            self.upstreams.is_empty() || self.upstreams.iter().any(|addr| addr == upstream);
+        source_matches && port_matches && connection_matches && upstream_matches
+    }
+}
+
+impl Cidr {
+    /// Returns true if the address is in this block. IPv4-mapped IPv6 addresses
+    /// are compared as IPv4 addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => match v6.octets() {
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                    IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+                }
+                _ => ip,
+            },
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix_len(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = u32::from(bits - prefix_len);
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let err = || CidrParseError(input.to_owned());
+        let mut parts = input.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| err())?;
+        let max_prefix_len = Cidr::max_prefix_len(addr);
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(err)?,
+            None => max_prefix_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix_len == Cidr::max_prefix_len(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix_len)
+        }
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{from_str, to_string};
+
+    const UPSTREAM: &str = "127.0.0.1:5432";
+
+    fn addr(input: &str) -> SocketAddr {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_and_displays_cidr() {
+        assert_eq!(
+            "10.0.0.0/8",
+            "10.0.0.0/8".parse::<Cidr>().unwrap().to_string()
+        );
+        assert_eq!("10.1.2.3", "10.1.2.3".parse::<Cidr>().unwrap().to_string());
+        assert_eq!(
+            "fd00::/16",
+            "fd00::/16".parse::<Cidr>().unwrap().to_string()
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "192.168.4.0/22".parse().unwrap();
+        assert!(cidr.contains("192.168.7.255".parse().unwrap()));
+        assert!(!cidr.contains("192.168.8.0".parse().unwrap()));
+        assert!(cidr.contains("::ffff:192.168.5.1".parse().unwrap()));
+        assert!(!cidr.contains("fd00::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains("fdab::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_sources_and_ports() {
+        let matcher = ToxicMatch {
+            sources: vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+            ports: Some(PortRange {
+                from: 40000,
+                to: 40010,
+            }),
+            connections: None,
+            upstreams: Vec::new(),
+        };
+        assert!(matcher.matches(addr("127.0.0.1:40000"), 1, UPSTREAM));
+        assert!(matcher.matches(addr("10.2.3.4:40010"), 1, UPSTREAM));
+        assert!(!matcher.matches(addr("127.0.0.2:40000"), 1, UPSTREAM));
+        assert!(!matcher.matches(addr("127.0.0.1:40011"), 1, UPSTREAM));
+        assert!(ToxicMatch::default().matches(addr("127.0.0.1:1"), 1, UPSTREAM));
+    }
+
+    #[test]
+    fn matches_connection_ordinals() {
+        let first: ConnectionRange = from_str("{\"to\":1}").unwrap();
+        assert!(first.contains(1));
+        assert!(!first.contains(2));
+
+        let every_third: ConnectionRange = from_str("{\"every\":3}").unwrap();
+        let matched: Vec<u64> = (1..=10).filter(|n| every_third.contains(*n)).collect();
+        assert_eq!(vec![3, 6, 9], matched);
+
+        let range: ConnectionRange = from_str("{\"from\":10,\"to\":20}").unwrap();
+        assert!(!range.contains(9));
+        assert!(range.contains(10));
+        assert!(range.contains(20));
+        assert!(!range.contains(21));
+
+        let matcher = ToxicMatch {
+            connections: Some(first),
+            ..Default::default()
+        };
+        assert!(matcher.matches(addr("127.0.0.1:5000"), 1, UPSTREAM));
+        assert!(!matcher.matches(addr("127.0.0.1:5000"), 2, UPSTREAM));
+    }
+
+    #[test]
+    fn matches_upstreams() {
+        let matcher: ToxicMatch = from_str("{\"upstreams\":[\"10.0.0.2:80\"]}").unwrap();
+        assert!(matcher.matches(addr("127.0.0.1:5000"), 1, "10.0.0.2:80"));
+        assert!(!matcher.matches(addr("127.0.0.1:5000"), 1, "10.0.0.3:80"));
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let input = "{\"sources\":[\"10.0.0.0/8\"],\"ports\":{\"from\":1,\"to\":2}}";
+        let matcher: ToxicMatch = from_str(input).unwrap();
+        assert_eq!(input, to_string(&matcher).unwrap());
+        assert!(from_str::<ToxicMatch>("{\"sources\":[\"10.0.0.0/40\"]}").is_err());
+    }
+}
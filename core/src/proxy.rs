@@ -1,46 +1,184 @@
-use crate::socket::{SocketListener, SocketStream};
+use crate::socket::{unix_socket_path, SocketListener, SocketStream};
 use crate::{
-    error::NotFoundError,
+    dns::{resolve, DnsConfig},
+    error::ToxicUpdateError,
     link::Link,
+    proxy_protocol::{encode_header, read_header, ProxyProtocolVersion},
     signal::{Closer, Stop},
     state::{ProxyState, SharedProxyInfo, ToxicStateHolder},
-    stream::{Read, Write},
-    toxic::{update_toxic_list_in_place, StreamDirection, Toxic, ToxicEvent, ToxicEventResult},
+    stream::{FrameCodec, Read, Write},
+    tls::{ListenTls, TlsContext, UpstreamTls},
+    toxic::{
+        update_toxic_list_in_place, StreamDirection, Toxic, ToxicEvent, ToxicEventKind,
+        ToxicEventResult, ToxicSampling,
+    },
+    udp::UdpProxyRunner,
+    upstream::{connect_with_failover, BalancePolicy, UpstreamBalancer},
 };
 use async_trait::async_trait;
 use bmrng::{Payload, RequestReceiver};
 use futures::{stream, StreamExt};
 #[cfg(test)]
 use mockall::automock;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::{io, mem};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{io, iter, mem};
 use thiserror::Error;
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, error, info, instrument};
 
 /// The default Go io.Copy buffer size is 32K, so also use 32K buffers here to imitate Toxiproxy.
 const READ_BUFFER_SIZE: usize = 32768;
 
+/// How long to wait before trying the upstreams again, if the config doesn't say
+const DEFAULT_RETRY_BACKOFF: u64 = 100;
+
+/// How long a client behind a load balancer has to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// The immutable configuration for a proxy
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProxyConfig {
     /// An arbitrary name
     #[serde(default = "default_name")]
     pub name: String,
-    /// The host name and the port the proxy listens on, like 127.0.0.1:5431
+    /// The host name and the port the proxy listens on, like 127.0.0.1:5431,
+    /// or a Unix domain socket path like unix:/tmp/proxy.sock
     pub listen: String,
-    /// The host name and the port the proxy connects to, like 127.0.0:5432
+    /// The host name and the port the proxy connects to, like 127.0.0:5432,
+    /// or a Unix domain socket path like unix:/var/run/postgresql/.s.PGSQL.5432
     pub upstream: String,
+    /// More upstream addresses. The proxy spreads the connections over `upstream` and these
+    /// with the `balance` policy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<String>,
+    /// How to pick the upstream for each connection when there are several, round robin
+    /// by default
+    #[serde(default, skip_serializing_if = "BalancePolicy::is_round_robin")]
+    pub balance: BalancePolicy,
+    /// Upstream addresses to try in order when connecting to the picked upstream fails
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<String>,
+    /// Give up on an upstream connection attempt after this many milliseconds.
+    /// No timeout if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+    /// TCP only: how many more times to try the upstreams after connecting fails.
+    /// Zero by default
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub connect_retries: u32,
+    /// Wait this many milliseconds before each retry, 100 if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_backoff_ms: Option<u64>,
+    /// TCP only: when no upstream accepts, keep the client connected and keep trying
+    /// instead of closing the client connection. False by default
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub hold_client: bool,
+    /// Resolve upstream host names with these overrides and faults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DnsConfig>,
+    /// TCP only: send a PROXY protocol header with the client address to the upstream
+    /// before any data, like HAProxy does with `send-proxy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// TCP only: expect a PROXY protocol v1 or v2 header at the start of every client
+    /// connection, like HAProxy does with `accept-proxy`. The proxy strips the header and
+    /// uses the client address in it. False by default
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub accept_proxy_protocol: bool,
     /// The client can set the enabled field to false to stop this proxy.
     /// Proxies are enabled by default
     #[serde(default = "default_enabled")]
     pub enabled: bool,
+    /// How the byte stream is split into the chunks that toxics act on.
+    /// Raw by default
+    #[serde(default, skip_serializing_if = "Framing::is_raw")]
+    pub framing: Framing,
     /// A random seed. Not exposed in the API
     #[serde(skip)]
     pub rand_seed: Option<u64>,
+    /// The transport protocol, tcp by default
+    #[serde(default, skip_serializing_if = "Protocol::is_tcp")]
+    pub protocol: Protocol,
+    /// UDP only: end a client session after this many milliseconds without datagrams in
+    /// either direction. 60 seconds if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    /// Accept TLS connections from clients with this certificate.
+    /// Toxics see the decrypted bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ListenTls>,
+    /// Connect to the upstream with TLS, and encrypt the bytes again after the toxics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_tls: Option<UpstreamTls>,
+}
+
+/// The transport protocol of a proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Protocol {
+    /// Accept TCP connections, and open a TCP connection to the upstream for each
+    #[default]
+    #[serde(rename = "tcp")]
+    Tcp,
+    /// Forward UDP datagrams. Every client address gets a session with its own socket to the
+    /// upstream, and every datagram is a chunk for the toxics
+    #[serde(rename = "udp")]
+    Udp,
+}
+
+/// How the proxy splits the byte stream into chunks before passing them through toxics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Framing {
+    /// Whatever a single read returns, up to 32K. This is how Toxiproxy behaves
+    #[default]
+    #[serde(rename = "raw")]
+    Raw,
+    /// Messages end with a newline, which is kept in the chunk
+    #[serde(rename = "newline")]
+    Newline,
+    /// Messages start with an unsigned length prefix, which is kept in the chunk.
+    /// The length does not include the prefix itself
+    #[serde(rename = "length_prefixed")]
+    LengthPrefixed {
+        /// The size of the prefix in bytes: 1, 2, 4 or 8
+        #[serde(default = "default_prefix_width")]
+        width: u8,
+        /// The byte order of the prefix
+        #[serde(default)]
+        endianness: Endianness,
+    },
+}
+
+/// The byte order of a length prefix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Endianness {
+    /// Most significant byte first (network byte order)
+    #[default]
+    #[serde(rename = "big")]
+    Big,
+    /// Least significant byte first
+    #[serde(rename = "little")]
+    Little,
+}
+
+impl Framing {
+    fn is_raw(&self) -> bool {
+        *self == Framing::Raw
+    }
+}
+
+impl Protocol {
+    fn is_tcp(&self) -> bool {
+        *self == Protocol::Tcp
+    }
 }
 
 fn default_name() -> String {
@@ -51,6 +189,18 @@ fn default_enabled() -> bool {
     true
 }
 
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+fn default_prefix_width() -> u8 {
+    4
+}
+
 /// A holder for upstream and downstream links, as well as the per-connection state
 #[derive(Debug)]
 pub struct Links {
@@ -59,6 +209,10 @@ pub struct Links {
     /// Optional, connection-wide state for toxics that need such state (like LimitData)
     /// Toxic Name -> State
     state_holder: Option<Arc<ToxicStateHolder>>,
+    /// The order the proxy accepted this connection in, starting from 1
+    connection: u64,
+    /// The upstream address the client is connected to
+    upstream_addr: String,
 }
 
 /// Toxics applied on a proxy connection
@@ -80,15 +234,90 @@ pub struct ProxyWithToxics {
     pub toxics: Vec<Toxic>,
 }
 
+impl Default for ProxyConfig {
+    /// An enabled TCP proxy with the same defaults as a config deserialized without the
+    /// optional fields. The addresses are empty and need to be set
+    fn default() -> Self {
+        ProxyConfig {
+            name: default_name(),
+            listen: String::new(),
+            upstream: String::new(),
+            upstreams: Vec::new(),
+            balance: BalancePolicy::default(),
+            fallbacks: Vec::new(),
+            connect_timeout_ms: None,
+            connect_retries: 0,
+            retry_backoff_ms: None,
+            hold_client: false,
+            dns: None,
+            send_proxy_protocol: None,
+            accept_proxy_protocol: false,
+            enabled: default_enabled(),
+            framing: Framing::default(),
+            rand_seed: None,
+            protocol: Protocol::default(),
+            idle_timeout: None,
+            tls: None,
+            upstream_tls: None,
+        }
+    }
+}
+
 impl ProxyConfig {
+    /// The timeout of each upstream connection attempt
+    pub(crate) fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout_ms.map(Duration::from_millis)
+    }
+
+    /// The wait before trying the upstreams again
+    pub(crate) fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_backoff_ms.unwrap_or(DEFAULT_RETRY_BACKOFF))
+    }
+
+    /// All the upstream addresses, `upstream` first
+    pub fn upstream_list(&self) -> Vec<String> {
+        iter::once(&self.upstream)
+            .chain(&self.upstreams)
+            .cloned()
+            .collect()
+    }
+
     /// Validate the proxy config, return `ProxyValidateError` if invalid
     pub fn validate(&self) -> Result<(), ProxyValidateError> {
         if self.name.is_empty() {
             Err(ProxyValidateError::MissingName)
-        } else if self.upstream.is_empty() {
+        } else if self.upstream.is_empty()
+            || self.upstreams.iter().any(String::is_empty)
+            || self.fallbacks.iter().any(String::is_empty)
+        {
             Err(ProxyValidateError::MissingUpstream)
         } else if self.listen.is_empty() {
             Err(ProxyValidateError::MissingListen)
+        } else if self.protocol == Protocol::Udp && self.framing != Framing::Raw {
+            Err(ProxyValidateError::FramingWithUdp)
+        } else if self.protocol == Protocol::Udp
+            && (unix_socket_path(&self.listen).is_some()
+                || self
+                    .upstream_list()
+                    .iter()
+                    .chain(&self.fallbacks)
+                    .any(|upstream| unix_socket_path(upstream).is_some()))
+        {
+            Err(ProxyValidateError::UnixWithUdp)
+        } else if self.protocol == Protocol::Udp
+            && (self.tls.is_some() || self.upstream_tls.is_some())
+        {
+            Err(ProxyValidateError::TlsWithUdp)
+        } else if self.protocol == Protocol::Udp
+            && (self.send_proxy_protocol.is_some() || self.accept_proxy_protocol)
+        {
+            Err(ProxyValidateError::ProxyProtocolWithUdp)
+        } else if let Framing::LengthPrefixed { width, .. } = self.framing {
+            if matches!(width, 1 | 2 | 4 | 8) {
+                Ok(())
+            } else {
+                Err(ProxyValidateError::InvalidPrefixWidth(width))
+            }
         } else {
             Ok(())
         }
@@ -104,8 +333,12 @@ impl Toxics {
         }
     }
 
-    /// Consume this Toxics struct to combine upstream and downstream toxics in a flat unordered vec
+    /// Consume this Toxics struct to combine upstream and downstream toxics in a flat vec.
+    /// Upstream toxics come first, each direction is in chain order
+    /// Toxics on both directions are listed once.
     pub fn into_vec(mut self) -> Vec<Toxic> {
+        self.downstream
+            .retain(|toxic| toxic.direction != StreamDirection::Both);
         self.upstream.append(&mut self.downstream);
         self.upstream
     }
@@ -122,6 +355,31 @@ impl Toxics {
             })
             .map(|toxic| toxic.to_owned())
     }
+
+    /// Check that the toxic `before` or `after` refers to is in every chain the toxic goes in
+    pub fn check_position(&self, toxic: &Toxic) -> Result<(), ToxicUpdateError> {
+        let target = match toxic.position.target() {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let in_chain = |chain: &[Toxic], direction: StreamDirection| {
+            !toxic.direction.includes(direction) || chain.iter().any(|el| el.name == target)
+        };
+        if in_chain(&self.upstream, StreamDirection::Upstream)
+            && in_chain(&self.downstream, StreamDirection::Downstream)
+        {
+            Ok(())
+        } else {
+            Err(ToxicUpdateError::PositionNotFound)
+        }
+    }
+}
+
+impl Links {
+    /// The upstream address the client is connected to
+    pub(crate) fn upstream(&self) -> &str {
+        &self.upstream_addr
+    }
 }
 
 impl ProxyWithToxics {
@@ -129,7 +387,7 @@ impl ProxyWithToxics {
     pub fn from_shared_proxy_info(info: SharedProxyInfo) -> Self {
         let proxy_state = info.state.lock();
         ProxyWithToxics {
-            proxy: info.clone_config(),
+            proxy: info.resolved_config(),
             toxics: proxy_state.toxics.clone().into_vec(),
         }
     }
@@ -172,6 +430,21 @@ pub trait Runner {
     ) -> io::Result<()>
     where
         Listener: SocketListener + 'static;
+
+    /// Initialize a UDP proxy, bind the socket but don't start forwarding datagrams
+    async fn initialize_udp_proxy(
+        config: ProxyConfig,
+        initial_toxics: Toxics,
+    ) -> io::Result<(UdpSocket, SharedProxyInfo)>;
+
+    /// Run the initialized UDP proxy, start a session for every new client address
+    async fn run_udp_proxy(
+        socket: UdpSocket,
+        proxy_info: SharedProxyInfo,
+        receiver: RequestReceiver<ToxicEvent, ToxicEventResult>,
+        stop: Stop,
+        closer: Closer,
+    ) -> io::Result<()>;
 }
 
 /// The proxy runner
@@ -189,15 +462,19 @@ impl Runner for ProxyRunner {
     where
         Listener: SocketListener + 'static,
     {
+        // Fail early on missing or invalid certificates
+        TlsContext::for_config(&config)?;
         let listener = Listener::bind(&config.listen).await?;
+        let listen_addr = listener.local_addr().ok();
 
-        info!(name = ?config.name, proxy = ?config.listen, upstream = ?config.upstream, "Initialized proxy");
+        info!(name = ?config.name, proxy = ?listen_addr.as_ref().unwrap_or(&config.listen), upstream = ?config.upstream, "Initialized proxy");
 
         let state = Arc::new(ProxyState::new(initial_toxics));
 
         let proxy_info = SharedProxyInfo {
             state,
             config: Arc::new(config),
+            listen_addr,
         };
 
         Ok((listener, proxy_info))
@@ -217,6 +494,12 @@ impl Runner for ProxyRunner {
     {
         let state = proxy_info.state;
         let config = proxy_info.config;
+        let tls = TlsContext::for_config(&config)?;
+        let balancer = Arc::new(Mutex::new(UpstreamBalancer::new(
+            config.balance,
+            config.upstream_list(),
+            config.rand_seed,
+        )));
 
         tokio::spawn(listen_toxic_events(
             state.clone(),
@@ -236,48 +519,20 @@ impl Runner for ProxyRunner {
             }?;
 
             if let Some((client_stream, addr)) = maybe_connection {
-                debug!(proxy = ?&config, addr = ?&addr, "Accepted client {}", addr);
-                let upstream = match Listener::Stream::connect(&config.upstream).await {
-                    Ok(upstream) => upstream,
-                    Err(err) => {
-                        error!(err = ?err, proxy = ?&config.name, upstream = ?&config.upstream, listen = ?&config.listen, "Unable to open connection to upstream");
-                        // This is not a fatal error, can retry next time another client connects
-                        continue;
-                    }
-                };
-
-                let (client_read, client_write) = client_stream.into_split();
-                let (upstream_read, upstream_write) = upstream.into_split();
-
-                let client_read =
-                    FramedRead::with_capacity(client_read, BytesCodec::new(), READ_BUFFER_SIZE);
-                let client_write = FramedWrite::new(client_write, BytesCodec::new());
-                let upstream_read =
-                    FramedRead::with_capacity(upstream_read, BytesCodec::new(), READ_BUFFER_SIZE);
-                let upstream_write = FramedWrite::new(upstream_write, BytesCodec::new());
-
-                let toxics = state.lock().toxics.clone();
-
-                let streams = Streams {
-                    client_read,
-                    client_write,
-                    upstream_read,
-                    upstream_write,
-                };
-
-                let res = create_links(
-                    state.clone(),
+                let connection = state.next_connection();
+                debug!(proxy = ?&config, addr = ?&addr, connection, "Accepted client {}", addr);
+                // Connect in the background, so a slow or blackholed upstream
+                // doesn't hold up the clients after this one
+                tokio::spawn(connect_client::<Listener::Stream>(
+                    client_stream,
                     addr,
-                    &config,
-                    &mut stop,
-                    toxics,
-                    streams,
-                    None,
-                );
-                if let Err(err) = res {
-                    error!(err = ?err, proxy = ?&config.name, listen = ?&config.listen, "Unable to establish link for proxy");
-                    continue;
-                }
+                    connection,
+                    balancer.clone(),
+                    state.clone(),
+                    config.clone(),
+                    tls.clone(),
+                    stop.clone(),
+                ));
             } else {
                 break;
             }
@@ -287,12 +542,197 @@ impl Runner for ProxyRunner {
         debug!(proxy = ?&config.name, listen = ?&config.listen, "Shutting down proxy");
         Ok(())
     }
+
+    async fn initialize_udp_proxy(
+        config: ProxyConfig,
+        initial_toxics: Toxics,
+    ) -> io::Result<(UdpSocket, SharedProxyInfo)> {
+        UdpProxyRunner::initialize_proxy(config, initial_toxics).await
+    }
+
+    async fn run_udp_proxy(
+        socket: UdpSocket,
+        proxy_info: SharedProxyInfo,
+        receiver: RequestReceiver<ToxicEvent, ToxicEventResult>,
+        stop: Stop,
+        closer: Closer,
+    ) -> io::Result<()> {
+        UdpProxyRunner::run_proxy(socket, proxy_info, receiver, stop, closer).await
+    }
+}
+
+/// Connect an accepted client to an upstream, run the TLS handshakes and establish the links
+#[allow(clippy::too_many_arguments)]
+async fn connect_client<Stream>(
+    client_stream: Stream,
+    mut addr: SocketAddr,
+    connection: u64,
+    balancer: Arc<Mutex<UpstreamBalancer>>,
+    state: Arc<ProxyState>,
+    config: Arc<ProxyConfig>,
+    tls: TlsContext,
+    mut stop: Stop,
+) where
+    Stream: SocketStream + 'static,
+{
+    // The address the client connected to, for the PROXY protocol header.
+    // Not the listen address, which may be a wildcard like 0.0.0.0
+    let mut destination = if config.send_proxy_protocol.is_some() {
+        client_stream.local_addr()
+    } else {
+        None
+    };
+    let (mut client_read, client_write) = client_stream.into_split();
+    if config.accept_proxy_protocol {
+        let header = tokio::select! {
+            header = timeout(PROXY_HEADER_TIMEOUT, read_header(&mut client_read)) => header,
+            _ = stop.recv() => return,
+        };
+        match header {
+            Ok(Ok(Some((client_addr, client_destination)))) => {
+                debug!(proxy = ?&config.name, addr = ?&addr, client = ?&client_addr, connection, "PROXY protocol header from {} for {}", addr, client_addr);
+                addr = client_addr;
+                destination = Some(client_destination);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(err)) => {
+                debug!(err = ?err, proxy = ?&config.name, addr = ?&addr, "Unable to read the PROXY protocol header");
+                return;
+            }
+            Err(_) => {
+                debug!(proxy = ?&config.name, addr = ?&addr, "Timed out waiting for the PROXY protocol header");
+                return;
+            }
+        }
+    }
+
+    // Count the connection while it's being made, so least_connections spreads the
+    // clients that connect at the same time. Picking and counting under the balancer
+    // lock keeps two clients from picking before either is counted
+    let (picked, _connecting) = {
+        let mut balancer = balancer.lock().expect("upstream balancer poisoned");
+        let picked = balancer
+            .pick(addr.ip(), |upstream| state.open_connections(upstream))
+            .to_owned();
+        let connecting = state.start_connecting(&picked);
+        (picked, connecting)
+    };
+    let (upstream, upstream_addr) = match connect_upstream::<Stream>(&picked, &config, &mut stop)
+        .await
+    {
+        Some(Ok(connected)) => connected,
+        Some(Err(err)) => {
+            error!(err = ?err, proxy = ?&config.name, upstream = ?&picked, listen = ?&config.listen, "Unable to open connection to upstream");
+            // This is not a fatal error, can retry next time another client connects
+            return;
+        }
+        None => return,
+    };
+
+    let (client_read, client_write) = match tls.accept((client_read, client_write)).await {
+        Ok(halves) => halves,
+        Err(err) => {
+            debug!(err = ?err, proxy = ?&config.name, addr = ?&addr, "TLS handshake with client failed");
+            return;
+        }
+    };
+    let (upstream_read, mut upstream_write) = upstream.into_split();
+    if let Some(version) = config.send_proxy_protocol {
+        let header = encode_header(version, addr, destination);
+        if let Err(err) = upstream_write.write_all(&header).await {
+            error!(err = ?err, proxy = ?&config.name, upstream = ?&upstream_addr, "Unable to send the PROXY protocol header to upstream");
+            return;
+        }
+    }
+    let (upstream_read, upstream_write) = match tls
+        .connect((upstream_read, upstream_write), &upstream_addr)
+        .await
+    {
+        Ok(halves) => halves,
+        Err(err) => {
+            error!(err = ?err, proxy = ?&config.name, upstream = ?&upstream_addr, "TLS handshake with upstream failed");
+            return;
+        }
+    };
+
+    let codec = FrameCodec::new(config.framing);
+    let client_read = FramedRead::with_capacity(client_read, codec.clone(), READ_BUFFER_SIZE);
+    let client_write = FramedWrite::new(client_write, codec.clone());
+    let upstream_read = FramedRead::with_capacity(upstream_read, codec.clone(), READ_BUFFER_SIZE);
+    let upstream_write = FramedWrite::new(upstream_write, codec);
+
+    let toxics = state.lock().toxics.clone();
+
+    let streams = Streams {
+        client_read,
+        client_write,
+        upstream_read,
+        upstream_write,
+    };
+
+    let res = create_links(
+        state.clone(),
+        addr,
+        connection,
+        upstream_addr,
+        &config,
+        &mut stop,
+        toxics,
+        streams,
+        None,
+    );
+    if let Err(err) = res {
+        error!(err = ?err, proxy = ?&config.name, listen = ?&config.listen, "Unable to establish link for proxy");
+    }
 }
 
+/// Connect to the picked upstream or one of the fallbacks, retrying `connect_retries` times,
+/// or until a connection succeeds if the proxy holds clients.
+/// Returns None if the proxy stops meanwhile
+async fn connect_upstream<Stream>(
+    picked: &str,
+    config: &ProxyConfig,
+    stop: &mut Stop,
+) -> Option<io::Result<(Stream, String)>>
+where
+    Stream: SocketStream + 'static,
+{
+    let mut retries = config.connect_retries;
+    loop {
+        let attempt = connect_with_failover(
+            picked,
+            &config.fallbacks,
+            config.connect_timeout(),
+            |upstream| async move {
+                let addr = resolve(config.dns.as_ref(), &upstream).await?;
+                Stream::connect(&addr).await
+            },
+        );
+        let result = tokio::select! {
+            result = attempt => result,
+            _ = stop.recv() => return None,
+        };
+        match result {
+            Err(err) if retries > 0 || config.hold_client => {
+                debug!(err = ?err, proxy = ?&config.name, upstream = ?picked, retries, "No upstream available, retrying");
+                retries = retries.saturating_sub(1);
+                tokio::select! {
+                    _ = tokio::time::sleep(config.retry_backoff()) => {},
+                    _ = stop.recv() => return None,
+                }
+            }
+            result => return Some(result),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[instrument(level = "debug", skip(state, streams, stop))]
 fn create_links(
     state: Arc<ProxyState>,
     addr: SocketAddr,
+    connection: u64,
+    upstream: String,
     config: &ProxyConfig,
     stop: &mut Stop,
     toxics: Toxics,
@@ -312,18 +752,28 @@ fn create_links(
     }
 
     let (links_stop, links_stopper) = stop.fork();
+    let toxics = decide_bidirectional_toxics(toxics, config.rand_seed);
 
-    let toxics_state_holder =
-        previous_toxic_state_holder.or_else(|| ToxicStateHolder::for_toxics(&toxics));
+    let toxics_state_holder = match previous_toxic_state_holder {
+        Some(holder) => {
+            holder.init_missing_states(&toxics);
+            Some(holder)
+        }
+        None => ToxicStateHolder::for_toxics(&toxics),
+    };
 
     let mut upstream_link = Link::new(
         addr,
+        connection,
+        upstream.clone(),
         StreamDirection::Upstream,
         config.clone(),
         links_stop.clone(),
     );
     let mut client_link = Link::new(
         addr,
+        connection,
+        upstream.clone(),
         StreamDirection::Downstream,
         config.clone(),
         links_stop,
@@ -367,6 +817,8 @@ fn create_links(
             upstream: upstream_link,
             client: client_link,
             state_holder: toxics_state_holder,
+            connection,
+            upstream_addr: upstream,
         },
     );
     Ok(())
@@ -407,7 +859,7 @@ async fn process_toxic_event(
     let new_toxics = {
         let mut current_state = state.lock();
         if let Err(err) = update_toxics(request, &mut current_state.toxics) {
-            let _ = responder.respond(Err(err.into()));
+            let _ = responder.respond(Err(err));
             return;
         }
         current_state.toxics.clone()
@@ -455,6 +907,8 @@ async fn recreate_links(
     create_links(
         state.clone(),
         addr,
+        links.connection,
+        links.upstream_addr,
         config,
         &mut stop.clone(),
         new_toxics,
@@ -464,12 +918,101 @@ async fn recreate_links(
 }
 
 /// Update the toxics collection in place
-fn update_toxics(event: ToxicEvent, toxics: &mut Toxics) -> Result<(), NotFoundError> {
-    update_toxic_list_in_place(&mut toxics.upstream, event.kind, StreamDirection::Upstream)
-        .or_else(|kind| {
-            update_toxic_list_in_place(&mut toxics.downstream, kind, StreamDirection::Downstream)
+#[allow(clippy::result_large_err)]
+pub(crate) fn update_toxics(
+    event: ToxicEvent,
+    toxics: &mut Toxics,
+) -> Result<(), ToxicUpdateError> {
+    if let ToxicEventKind::AddToxic(ref toxic) | ToxicEventKind::UpdateToxic(ref toxic) = event.kind
+    {
+        toxics.check_position(toxic)?;
+    }
+    match event.kind {
+        ToxicEventKind::RemoveToxic(_)
+        | ToxicEventKind::SetToxicEnabled(..)
+        | ToxicEventKind::RemoveAllToxics => {
+            // Toxics on both directions are in both lists
+            let upstream = update_toxic_list_in_place(
+                &mut toxics.upstream,
+                event.kind.clone(),
+                StreamDirection::Upstream,
+            );
+            let downstream = update_toxic_list_in_place(
+                &mut toxics.downstream,
+                event.kind,
+                StreamDirection::Downstream,
+            );
+            upstream.or(downstream).or(Err(ToxicUpdateError::NotFound))
+        }
+        ToxicEventKind::AddToxic(ref toxic) | ToxicEventKind::UpdateToxic(ref toxic)
+            if is_on_both_directions(toxic, toxics) =>
+        {
+            // Change both lists, or neither
+            let mut upstream = toxics.upstream.clone();
+            let mut downstream = toxics.downstream.clone();
+            update_toxic_list_in_place(
+                &mut upstream,
+                event.kind.clone(),
+                StreamDirection::Upstream,
+            )
+            .or(Err(ToxicUpdateError::NotFound))?;
+            update_toxic_list_in_place(&mut downstream, event.kind, StreamDirection::Downstream)
+                .or(Err(ToxicUpdateError::NotFound))?;
+            toxics.upstream = upstream;
+            toxics.downstream = downstream;
+            Ok(())
+        }
+        kind => update_toxic_list_in_place(&mut toxics.upstream, kind, StreamDirection::Upstream)
+            .or_else(|kind| {
+                update_toxic_list_in_place(
+                    &mut toxics.downstream,
+                    kind,
+                    StreamDirection::Downstream,
+                )
+            })
+            .or(Err(ToxicUpdateError::NotFound)),
+    }
+}
+
+/// Returns true if the toxic, or the existing toxic with the same name, is on both directions
+fn is_on_both_directions(toxic: &Toxic, toxics: &Toxics) -> bool {
+    toxic.direction == StreamDirection::Both
+        || toxics.find_by_name(&toxic.name).map_or(false, |existing| {
+            existing.direction == StreamDirection::Both
+        })
+}
+
+/// Toxics on both directions get one activation decision per connection, shared by the two
+/// links. Drops the inactive ones and makes the active ones always active on both links.
+/// Toxics sampled per chunk keep their toxicity, the decision is made for every chunk instead.
+pub(crate) fn decide_bidirectional_toxics(mut toxics: Toxics, rand_seed: Option<u64>) -> Toxics {
+    let mut rand_gen = if let Some(seed) = rand_seed {
+        StdRng::seed_from_u64(seed)
+    } else {
+        StdRng::from_entropy()
+    };
+    let decisions: HashMap<String, bool> = toxics
+        .upstream
+        .iter()
+        .filter(|toxic| {
+            toxic.direction == StreamDirection::Both
+                && (toxic.sampling == ToxicSampling::PerConnection
+                    || !toxic.kind.supports_per_chunk_sampling())
         })
-        .or(Err(NotFoundError))
+        .map(|toxic| (toxic.name.clone(), toxic.toxicity >= rand_gen.gen::<f32>()))
+        .collect();
+    if decisions.is_empty() {
+        return toxics;
+    }
+    for direction_toxics in [&mut toxics.upstream, &mut toxics.downstream].iter_mut() {
+        direction_toxics.retain(|toxic| decisions.get(&toxic.name).copied().unwrap_or(true));
+        for toxic in direction_toxics.iter_mut() {
+            if decisions.contains_key(&toxic.name) {
+                toxic.toxicity = 1.0;
+            }
+        }
+    }
+    toxics
 }
 
 /// Errors return when ProxyConfig validation fails
@@ -484,6 +1027,21 @@ pub enum ProxyValidateError {
     /// The listen field is empty
     #[error("listen address missing")]
     MissingListen,
+    /// The length prefix width of the framing is not 1, 2, 4 or 8
+    #[error("invalid length prefix width {0}, must be 1, 2, 4 or 8")]
+    InvalidPrefixWidth(u8),
+    /// UDP proxies don't split the datagrams, every datagram is a chunk
+    #[error("framing is not supported with udp")]
+    FramingWithUdp,
+    /// UDP proxies only listen on and connect to IP addresses
+    #[error("unix sockets are not supported with udp")]
+    UnixWithUdp,
+    /// UDP proxies forward datagrams as they are
+    #[error("tls is not supported with udp")]
+    TlsWithUdp,
+    /// UDP proxies have no stream to read or write a header on
+    #[error("proxy protocol is not supported with udp")]
+    ProxyProtocolWithUdp,
 }
 
 #[cfg(test)]
@@ -498,7 +1056,9 @@ mod serde_tests {
             listen: "127.0.0.1:5431".to_owned(),
             upstream: "127.0.0.1:5432".to_owned(),
             enabled: false,
+            framing: Framing::Raw,
             rand_seed: Some(3),
+            ..Default::default()
         };
         let serialized = to_string(&config).unwrap();
         let expected = "{\"name\":\"foo\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"127.0.0.1:5432\",\"enabled\":false}";
@@ -509,7 +1069,9 @@ mod serde_tests {
             listen: "127.0.0.1:5431".to_owned(),
             upstream: "127.0.0.1:5432".to_owned(),
             enabled: false,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
 
         let deserialized = from_str(&serialized).unwrap();
@@ -523,13 +1085,44 @@ mod serde_tests {
             listen: "127.0.0.1:5431".to_owned(),
             upstream: "127.0.0.1:5432".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         let input =
             "{\"name\":\"foo\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"127.0.0.1:5432\"}";
         let deserialized = from_str(&input).unwrap();
         assert_eq!(expected, deserialized);
     }
+
+    #[test]
+    fn test_length_prefixed_framing() {
+        let config = ProxyConfig {
+            name: "foo".to_owned(),
+            listen: "127.0.0.1:5431".to_owned(),
+            upstream: "127.0.0.1:5432".to_owned(),
+            enabled: true,
+            framing: Framing::LengthPrefixed {
+                width: 2,
+                endianness: Endianness::Little,
+            },
+            rand_seed: None,
+            ..Default::default()
+        };
+        let serialized = to_string(&config).unwrap();
+        let expected = "{\"name\":\"foo\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"127.0.0.1:5432\",\"enabled\":true,\"framing\":{\"type\":\"length_prefixed\",\"width\":2,\"endianness\":\"little\"}}";
+        assert_eq!(expected, serialized);
+
+        let input = "{\"name\":\"foo\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"127.0.0.1:5432\",\"framing\":{\"type\":\"length_prefixed\"}}";
+        let deserialized: ProxyConfig = from_str(input).unwrap();
+        assert_eq!(
+            Framing::LengthPrefixed {
+                width: 4,
+                endianness: Endianness::Big
+            },
+            deserialized.framing
+        );
+    }
 }
 
 #[cfg(test)]
@@ -543,7 +1136,9 @@ mod config_tests {
             listen: "".to_owned(),
             upstream: "".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         assert_eq!(config.validate(), Err(ProxyValidateError::MissingName))
     }
@@ -555,7 +1150,9 @@ mod config_tests {
             listen: "".to_owned(),
             upstream: "bogus_addr".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         assert_eq!(config.validate(), Err(ProxyValidateError::MissingListen))
     }
@@ -567,7 +1164,9 @@ mod config_tests {
             listen: "bogus_addr".to_owned(),
             upstream: "".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         assert_eq!(config.validate(), Err(ProxyValidateError::MissingUpstream))
     }
@@ -579,8 +1178,252 @@ mod config_tests {
             listen: "bogus_addr".to_owned(),
             upstream: "bogus_upstream".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         assert_eq!(config.validate(), Ok(()))
     }
+
+    #[test]
+    fn validates_prefix_width() {
+        let config = ProxyConfig {
+            name: "name".to_owned(),
+            listen: "bogus_addr".to_owned(),
+            upstream: "bogus_upstream".to_owned(),
+            enabled: true,
+            framing: Framing::LengthPrefixed {
+                width: 3,
+                endianness: Endianness::Big,
+            },
+            rand_seed: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ProxyValidateError::InvalidPrefixWidth(3))
+        )
+    }
+
+    #[test]
+    fn validates_udp_framing() {
+        let mut config: ProxyConfig = serde_json::from_str(
+            "{\"name\":\"dns\",\"listen\":\"127.0.0.1:5353\",\"upstream\":\"127.0.0.1:53\",\"protocol\":\"udp\",\"idle_timeout\":5000}",
+        )
+        .unwrap();
+        assert_eq!(Protocol::Udp, config.protocol);
+        assert_eq!(Some(5000), config.idle_timeout);
+        assert_eq!(config.validate(), Ok(()));
+
+        config.framing = Framing::Newline;
+        assert_eq!(config.validate(), Err(ProxyValidateError::FramingWithUdp));
+
+        config.framing = Framing::Raw;
+        config.upstream_tls = Some(UpstreamTls::default());
+        assert_eq!(config.validate(), Err(ProxyValidateError::TlsWithUdp));
+    }
+
+    #[test]
+    fn parses_upstream_list() {
+        let input = "{\"name\":\"db\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"10.0.0.1:5432\",\"upstreams\":[\"10.0.0.2:5432\"],\"balance\":\"least_connections\",\"enabled\":true}";
+        let mut config: ProxyConfig = serde_json::from_str(input).unwrap();
+        assert_eq!(BalancePolicy::LeastConnections, config.balance);
+        assert_eq!(
+            vec!["10.0.0.1:5432".to_owned(), "10.0.0.2:5432".to_owned()],
+            config.upstream_list()
+        );
+        assert_eq!(input, serde_json::to_string(&config).unwrap());
+        assert_eq!(config.validate(), Ok(()));
+
+        config.upstreams.push(String::new());
+        assert_eq!(config.validate(), Err(ProxyValidateError::MissingUpstream));
+    }
+
+    #[test]
+    fn validates_unix_addresses() {
+        let mut config: ProxyConfig = serde_json::from_str(
+            "{\"name\":\"pg\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"unix:/var/run/postgresql/.s.PGSQL.5432\"}",
+        )
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.protocol = Protocol::Udp;
+        assert_eq!(config.validate(), Err(ProxyValidateError::UnixWithUdp));
+    }
+
+    #[test]
+    fn parses_send_proxy_protocol() {
+        let input = "{\"name\":\"app\",\"listen\":\"127.0.0.1:8080\",\"upstream\":\"10.0.0.1:80\",\"send_proxy_protocol\":\"v2\",\"enabled\":true}";
+        let mut config: ProxyConfig = serde_json::from_str(input).unwrap();
+        assert_eq!(Some(ProxyProtocolVersion::V2), config.send_proxy_protocol);
+        assert_eq!(input, serde_json::to_string(&config).unwrap());
+        assert_eq!(Ok(()), config.validate());
+
+        config.protocol = Protocol::Udp;
+        assert_eq!(
+            Err(ProxyValidateError::ProxyProtocolWithUdp),
+            config.validate()
+        );
+    }
+
+    #[test]
+    fn parses_accept_proxy_protocol() {
+        let input = "{\"name\":\"app\",\"listen\":\"127.0.0.1:8080\",\"upstream\":\"10.0.0.1:80\",\"accept_proxy_protocol\":true,\"enabled\":true}";
+        let mut config: ProxyConfig = serde_json::from_str(input).unwrap();
+        assert!(config.accept_proxy_protocol);
+        assert_eq!(input, serde_json::to_string(&config).unwrap());
+        assert_eq!(Ok(()), config.validate());
+
+        config.protocol = Protocol::Udp;
+        assert_eq!(
+            Err(ProxyValidateError::ProxyProtocolWithUdp),
+            config.validate()
+        );
+    }
+
+    #[test]
+    fn parses_failover_options() {
+        let input = "{\"name\":\"db\",\"listen\":\"127.0.0.1:5431\",\"upstream\":\"10.0.0.1:5432\",\"fallbacks\":[\"10.0.0.2:5432\"],\"connect_timeout_ms\":500,\"connect_retries\":3,\"retry_backoff_ms\":250,\"hold_client\":true,\"enabled\":true}";
+        let mut config: ProxyConfig = serde_json::from_str(input).unwrap();
+        assert_eq!(vec!["10.0.0.2:5432".to_owned()], config.fallbacks);
+        assert_eq!(Some(Duration::from_millis(500)), config.connect_timeout());
+        assert_eq!(3, config.connect_retries);
+        assert_eq!(Duration::from_millis(250), config.retry_backoff());
+        assert!(config.hold_client);
+        assert_eq!(Ok(()), config.validate());
+        assert_eq!(input, serde_json::to_string(&config).unwrap());
+
+        config.fallbacks.push("".to_owned());
+        assert_eq!(Err(ProxyValidateError::MissingUpstream), config.validate());
+    }
+}
+
+#[cfg(test)]
+mod toxics_tests {
+    use super::*;
+    use crate::toxic::ToxicKind;
+
+    fn latency(name: &str, direction: StreamDirection, latency: u64) -> Toxic {
+        Toxic {
+            kind: ToxicKind::Latency { latency, jitter: 0 },
+            name: name.to_owned(),
+            toxicity: 0.5,
+            direction,
+            ..Default::default()
+        }
+    }
+
+    fn event(kind: ToxicEventKind) -> ToxicEvent {
+        ToxicEvent::new("proxy".to_owned(), kind)
+    }
+
+    #[test]
+    fn updates_toxics_on_both_directions_as_a_pair() {
+        let mut toxics = Toxics::empty();
+        let both = latency("lat", StreamDirection::Both, 10);
+        let up = latency("up", StreamDirection::Upstream, 10);
+        assert!(update_toxics(event(ToxicEventKind::AddToxic(up)), &mut toxics).is_ok());
+        assert!(update_toxics(event(ToxicEventKind::AddToxic(both)), &mut toxics).is_ok());
+        assert_eq!(2, toxics.upstream.len());
+        assert_eq!(1, toxics.downstream.len());
+        assert_eq!(2, toxics.clone().into_vec().len());
+
+        let updated = latency("lat", StreamDirection::Both, 20);
+        assert!(update_toxics(
+            event(ToxicEventKind::UpdateToxic(updated.clone())),
+            &mut toxics
+        )
+        .is_ok());
+        assert_eq!(updated, toxics.upstream[1]);
+        assert_eq!(updated, toxics.downstream[0]);
+
+        // Can't replace one half of the pair
+        let half = latency("lat", StreamDirection::Downstream, 30);
+        assert!(update_toxics(event(ToxicEventKind::UpdateToxic(half)), &mut toxics).is_err());
+        assert_eq!(updated, toxics.downstream[0]);
+
+        assert!(update_toxics(
+            event(ToxicEventKind::RemoveToxic("lat".to_owned())),
+            &mut toxics
+        )
+        .is_ok());
+        assert_eq!(1, toxics.upstream.len());
+        assert!(toxics.downstream.is_empty());
+    }
+
+    #[test]
+    fn rejects_missing_position_targets() {
+        let mut toxics = Toxics::empty();
+        let up = latency("up", StreamDirection::Upstream, 10);
+        assert!(update_toxics(event(ToxicEventKind::AddToxic(up)), &mut toxics).is_ok());
+
+        let mut down = latency("down", StreamDirection::Downstream, 10);
+        down.position.before = Some("up".to_owned());
+        assert_eq!(
+            Err(ToxicUpdateError::PositionNotFound),
+            update_toxics(event(ToxicEventKind::AddToxic(down.clone())), &mut toxics)
+        );
+        down.position.before = None;
+        assert!(update_toxics(event(ToxicEventKind::AddToxic(down)), &mut toxics).is_ok());
+
+        // The pair needs the target in both chains
+        let mut both = latency("lat", StreamDirection::Both, 10);
+        both.position.after = Some("up".to_owned());
+        assert_eq!(
+            Err(ToxicUpdateError::PositionNotFound),
+            update_toxics(event(ToxicEventKind::AddToxic(both)), &mut toxics)
+        );
+        let missing = latency("missing", StreamDirection::Upstream, 10);
+        assert_eq!(
+            Err(ToxicUpdateError::NotFound),
+            update_toxics(event(ToxicEventKind::UpdateToxic(missing)), &mut toxics)
+        );
+    }
+
+    #[test]
+    fn sets_enabled_in_place() {
+        let mut toxics = Toxics {
+            upstream: vec![
+                latency("up", StreamDirection::Upstream, 10),
+                latency("lat", StreamDirection::Both, 10),
+            ],
+            downstream: vec![latency("lat", StreamDirection::Both, 10)],
+        };
+        let disable = ToxicEventKind::SetToxicEnabled("lat".to_owned(), false);
+        assert!(update_toxics(event(disable), &mut toxics).is_ok());
+        assert!(toxics.upstream[0].enabled);
+        assert!(!toxics.upstream[1].enabled);
+        assert!(!toxics.downstream[0].enabled);
+
+        let missing = ToxicEventKind::SetToxicEnabled("nope".to_owned(), true);
+        assert_eq!(
+            Err(ToxicUpdateError::NotFound),
+            update_toxics(event(missing), &mut toxics)
+        );
+    }
+
+    #[test]
+    fn removes_all_toxics_on_both_directions() {
+        let mut toxics = Toxics {
+            upstream: vec![latency("up", StreamDirection::Upstream, 10)],
+            downstream: vec![latency("down", StreamDirection::Downstream, 10)],
+        };
+        assert!(update_toxics(event(ToxicEventKind::RemoveAllToxics), &mut toxics).is_ok());
+        assert!(toxics.upstream.is_empty());
+        assert!(toxics.downstream.is_empty());
+    }
+
+    #[test]
+    fn shares_activation_decision_between_links() {
+        for seed in 0..20 {
+            let both = latency("lat", StreamDirection::Both, 10);
+            let toxics = Toxics {
+                upstream: vec![both.clone()],
+                downstream: vec![both],
+            };
+            let decided = decide_bidirectional_toxics(toxics, Some(seed));
+            assert_eq!(decided.upstream, decided.downstream);
+            assert!(decided.upstream.iter().all(|toxic| toxic.toxicity == 1.0));
+        }
+    }
 }
@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The first 12 bytes of every version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command
+const V2_PROXY_COMMAND: u8 = 0x21;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+const V2_FAMILY_UDP4: u8 = 0x12;
+const V2_FAMILY_UDP6: u8 = 0x22;
+/// The longest version 1 header allowed by the spec, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// A version of the HAProxy PROXY protocol
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ProxyProtocolVersion {
+    /// The human readable header, like `PROXY TCP4 10.0.0.1 10.0.0.2 51234 5432\r\n`
+    #[serde(rename = "v1")]
+    V1,
+    /// The binary header
+    #[serde(rename = "v2")]
+    V2,
+}
+
+/// Encode the header that tells the upstream the real client address.
+/// The addresses are unknown to the upstream if the destination is None,
+/// like when the proxy listens on a Unix socket
+pub(crate) fn encode_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: Option<SocketAddr>,
+) -> Vec<u8> {
+    let addrs = destination.map(|destination| same_family(source, destination));
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(V2_PROXY_COMMAND);
+            let (family, mut body) = match addrs {
+                Some((source, destination)) => {
+                    let mut body = Vec::with_capacity(36);
+                    let family = match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                            body.extend_from_slice(&src.octets());
+                            body.extend_from_slice(&dst.octets());
+                            V2_FAMILY_TCP4
+                        }
+                        (src, dst) => {
+                            body.extend_from_slice(&to_ipv6(src).octets());
+                            body.extend_from_slice(&to_ipv6(dst).octets());
+                            V2_FAMILY_TCP6
+                        }
+                    };
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    (family, body)
+                }
+                None => (V2_FAMILY_UNSPEC, Vec::new()),
+            };
+            header.push(family);
+            header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            header.append(&mut body);
+            header
+        }
+    }
+}
+
+/// Read a version 1 or version 2 header off the start of a client stream, and nothing more.
+/// Returns the client and destination addresses in the header, or None if the sender didn't know them
+pub(crate) async fn read_header<R>(reader: &mut R) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut start = [0u8; 5];
+    reader.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid_header("version 1 header is too long"));
+            }
+            line.push(reader.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else if start == V2_SIGNATURE[..5] {
+        let mut rest = [0u8; 11];
+        reader.read_exact(&mut rest).await?;
+        if rest[..7] != V2_SIGNATURE[5..] || rest[7] >> 4 != 2 {
+            return Err(invalid_header("bad version 2 signature"));
+        }
+        let command = rest[7] & 0x0f;
+        let family = rest[8];
+        let len = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).await?;
+        // The LOCAL command is for health checks from the load balancer itself
+        if command == 0 {
+            return Ok(None);
+        }
+        parse_v2(family, &body)
+    } else {
+        Err(invalid_header("missing PROXY protocol header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid_header("not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, destination, port, destination_port]
+        | ["PROXY", "TCP6", source, destination, port, destination_port] => {
+            let source = parse_v1_addr(source, port, "source")?;
+            let destination = parse_v1_addr(destination, destination_port, "destination")?;
+            Ok(Some((source, destination)))
+        }
+        _ => Err(invalid_header("malformed version 1 header")),
+    }
+}
+
+fn parse_v1_addr(ip: &str, port: &str, which: &str) -> io::Result<SocketAddr> {
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| invalid_header(&format!("bad {} address", which)))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| invalid_header(&format!("bad {} port", which)))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn parse_v2(family: u8, body: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let (source_ip, destination_ip, port_at) = match family {
+        V2_FAMILY_TCP4 | V2_FAMILY_UDP4 if body.len() >= 12 => {
+            let mut source = [0u8; 4];
+            let mut destination = [0u8; 4];
+            source.copy_from_slice(&body[..4]);
+            destination.copy_from_slice(&body[4..8]);
+            (
+                IpAddr::V4(Ipv4Addr::from(source)),
+                IpAddr::V4(Ipv4Addr::from(destination)),
+                8,
+            )
+        }
+        V2_FAMILY_TCP6 | V2_FAMILY_UDP6 if body.len() >= 36 => {
+            let mut source = [0u8; 16];
+            let mut destination = [0u8; 16];
+            source.copy_from_slice(&body[..16]);
+            destination.copy_from_slice(&body[16..32]);
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(destination)),
+                32,
+            )
+        }
+        V2_FAMILY_TCP4 | V2_FAMILY_UDP4 | V2_FAMILY_TCP6 | V2_FAMILY_UDP6 => {
+            return Err(invalid_header("version 2 addresses are too short"));
+        }
+        // Unix sockets and unspecified families carry no usable client address
+        _ => return Ok(None),
+    };
+    let port = u16::from_be_bytes([body[port_at], body[port_at + 1]]);
+    let destination_port = u16::from_be_bytes([body[port_at + 2], body[port_at + 3]]);
+    Ok(Some((
+        SocketAddr::new(source_ip, port),
+        SocketAddr::new(destination_ip, destination_port),
+    )))
+}
+
+fn invalid_header(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {}", reason),
+    )
+}
+
+/// Map both addresses to IPv6 if only one of them is
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (
+            SocketAddr::new(IpAddr::V6(to_ipv6(source.ip())), source.port()),
+            SocketAddr::new(IpAddr::V6(to_ipv6(destination.ip())), destination.port()),
+        )
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(input: &str) -> SocketAddr {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn encodes_v1_headers() {
+        assert_eq!(
+            b"PROXY TCP4 10.0.0.1 10.0.0.2 51234 5432\r\n".to_vec(),
+            encode_header(
+                ProxyProtocolVersion::V1,
+                addr("10.0.0.1:51234"),
+                Some(addr("10.0.0.2:5432"))
+            )
+        );
+        assert_eq!(
+            b"PROXY TCP6 ::ffff:10.0.0.1 ::1 51234 5432\r\n".to_vec(),
+            encode_header(
+                ProxyProtocolVersion::V1,
+                addr("10.0.0.1:51234"),
+                Some(addr("[::1]:5432"))
+            )
+        );
+        assert_eq!(
+            b"PROXY UNKNOWN\r\n".to_vec(),
+            encode_header(ProxyProtocolVersion::V1, addr("10.0.0.1:51234"), None)
+        );
+    }
+
+    #[test]
+    fn encodes_v2_headers() {
+        let header = encode_header(
+            ProxyProtocolVersion::V2,
+            addr("10.0.0.1:51234"),
+            Some(addr("10.0.0.2:5432")),
+        );
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        expected.extend_from_slice(&51234u16.to_be_bytes());
+        expected.extend_from_slice(&5432u16.to_be_bytes());
+        assert_eq!(expected, header);
+
+        let header = encode_header(
+            ProxyProtocolVersion::V2,
+            addr("[::1]:51234"),
+            Some(addr("[::2]:5432")),
+        );
+        assert_eq!(16 + 36, header.len());
+        assert_eq!([0x21, 0x21, 0, 36], header[12..16]);
+
+        let header = encode_header(ProxyProtocolVersion::V2, addr("[::1]:51234"), None);
+        assert_eq!([0x21, 0x00, 0, 0], header[12..16]);
+    }
+
+    async fn read(input: &[u8]) -> (io::Result<Option<(SocketAddr, SocketAddr)>>, Vec<u8>) {
+        let mut reader = input;
+        let result = read_header(&mut reader).await;
+        (result, reader.to_vec())
+    }
+
+    #[tokio::test]
+    async fn reads_headers_it_writes() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2].iter() {
+            for (source, destination) in [
+                ("10.0.0.1:51234", "10.0.0.2:5432"),
+                ("[::1]:51234", "[::2]:5432"),
+            ]
+            .iter()
+            {
+                let mut input = encode_header(*version, addr(source), Some(addr(destination)));
+                input.extend_from_slice(b"hello");
+                let (result, rest) = read(&input).await;
+                assert_eq!(Some((addr(source), addr(destination))), result.unwrap());
+                assert_eq!(b"hello".to_vec(), rest);
+            }
+            let input = encode_header(*version, addr("10.0.0.1:51234"), None);
+            assert_eq!(None, read(&input).await.0.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_headers() {
+        assert!(read(b"GET / HTTP/1.1\r\n").await.0.is_err());
+        assert!(read(b"PROXY TCP4 10.0.0.1\r\n").await.0.is_err());
+        let mut too_long = b"PROXY TCP4 ".to_vec();
+        too_long.resize(200, b'1');
+        assert!(read(&too_long).await.0.is_err());
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 4, 1, 2, 3, 4]);
+        assert!(read(&input).await.0.is_err());
+    }
+}
@@ -1,15 +1,39 @@
+use crate::tls::TlsStream;
 use async_trait::async_trait;
 #[cfg(test)]
 use mockall::automock;
 use pin_project_lite::pin_project;
-use std::{io, net::SocketAddr};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(unix)]
+use tokio::net::{UnixListener as TokioUnixListener, UnixStream as TokioUnixStream};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream},
 };
 
+use std::pin::Pin;
 #[cfg(not(test))]
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+#[cfg(all(unix, not(test)))]
+use tokio::net::unix::{OwnedReadHalf as UnixOwnedReadHalf, OwnedWriteHalf as UnixOwnedWriteHalf};
+
+/// The prefix of Unix domain socket addresses, like `unix:/var/run/app.sock`
+pub const UNIX_ADDR_PREFIX: &str = "unix:";
+
+/// Unix socket clients have no IP address, so each one gets a unique address in the
+/// discard-only prefix `100::/64` to tell the clients apart
+const UNIX_PEER_PREFIX: u128 = 0x0100 << 112;
+
+static NEXT_UNIX_PEER: AtomicU64 = AtomicU64::new(1);
+
+/// Returns the socket path if the address is a Unix domain socket address
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_ADDR_PREFIX)
+}
 
 /// The TcpListener interface we need to mock
 #[cfg_attr(test, automock(type Stream=TcpStream;))]
@@ -25,6 +49,16 @@ pub trait SocketListener: Sized + Send + Sync {
 
     /// Accepts a new incoming connection from this listener.
     async fn accept(&self) -> io::Result<(Self::Stream, SocketAddr)>;
+
+    /// Returns the address this listener is bound to, in the format of the listen address.
+    /// The port is resolved if the listener was bound to port 0.
+    /// Listeners that can't tell return an error, and the proxy reports its listen address.
+    fn local_addr(&self) -> io::Result<String> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "listener does not report its local address",
+        ))
+    }
 }
 
 /// The TcpStream interface we need to mock
@@ -39,6 +73,13 @@ pub trait SocketStream: Sized + Send + Sync {
     /// Splits the inner `TcpStream` into a read half and a write half, which
     /// can be used to read and write the stream concurrently.
     fn into_split(self) -> (ReadStream, WriteStream);
+
+    /// Returns the local address of a TCP connection,
+    /// or None for a Unix socket or a stream that can't tell.
+    /// This is the address the client connected to, even if the listener is bound to a wildcard.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
 }
 
 /// A simple wrapper around Tokio TcpListener to make it mockable
@@ -53,6 +94,43 @@ pub struct TcpStream {
     inner: TokioTcpStream,
 }
 
+/// A wrapper around Tokio UnixListener. Removes the socket file when dropped
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixListener {
+    inner: TokioUnixListener,
+    path: PathBuf,
+}
+
+/// A wrapper around Tokio UnixStream
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixStream {
+    // Only split in the real build, the tests mock the halves
+    #[cfg_attr(test, allow(dead_code))]
+    inner: TokioUnixStream,
+}
+
+/// Listens on a Unix domain socket if the address starts with `unix:`, on TCP otherwise
+#[derive(Debug)]
+pub enum AnyListener {
+    /// A TCP listener
+    Tcp(TcpListener),
+    /// A Unix domain socket listener
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Connects to a Unix domain socket if the address starts with `unix:`, over TCP otherwise
+#[derive(Debug)]
+pub enum AnyStream {
+    /// A TCP connection
+    Tcp(TcpStream),
+    /// A Unix domain socket connection
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
 #[async_trait]
 impl SocketListener for TcpListener {
     type Stream = TcpStream;
@@ -71,6 +149,10 @@ impl SocketListener for TcpListener {
         let wrapper = TcpStream { inner: stream };
         Ok((wrapper, addr))
     }
+
+    fn local_addr(&self) -> io::Result<String> {
+        Ok(self.inner.local_addr()?.to_string())
+    }
 }
 #[async_trait]
 impl SocketStream for TcpStream {
@@ -82,10 +164,97 @@ impl SocketStream for TcpStream {
         Ok(TcpStream { inner })
     }
 
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr().ok()
+    }
+
+    #[cfg(not(test))]
+    fn into_split(self) -> (ReadStream, WriteStream) {
+        let (read_half, write_half) = self.inner.into_split();
+        (
+            ReadStream::new(ReadHalf::Tcp(read_half)),
+            WriteStream::new(WriteHalf::Tcp(write_half)),
+        )
+    }
+
+    #[cfg(test)]
+    fn into_split(self) -> (ReadStream, WriteStream) {
+        unimplemented!("must mock")
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl SocketListener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn bind(addr: &str) -> io::Result<UnixListener>
+    where
+        Self: Sized,
+    {
+        let path = PathBuf::from(unix_socket_path(addr).unwrap_or(addr));
+        remove_stale_socket(&path)?;
+        Ok(UnixListener {
+            inner: TokioUnixListener::bind(&path)?,
+            path,
+        })
+    }
+
+    async fn accept(&self) -> io::Result<(Self::Stream, SocketAddr)> {
+        let (stream, _) = self.inner.accept().await?;
+        Ok((UnixStream { inner: stream }, next_unix_peer_addr()))
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        Ok(format!("{}{}", UNIX_ADDR_PREFIX, self.path.display()))
+    }
+}
+
+/// Removes a socket file left behind by a listener that didn't shut down cleanly.
+/// Other files, and sockets something is still listening on, are left alone and fail the bind
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream as StdUnixStream};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata)
+            if metadata.file_type().is_socket() && StdUnixStream::connect(path).is_err() =>
+        {
+            std::fs::remove_file(path)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl SocketStream for UnixStream {
+    async fn connect(addr: &str) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let inner = TokioUnixStream::connect(unix_socket_path(addr).unwrap_or(addr)).await?;
+        Ok(UnixStream { inner })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
     #[cfg(not(test))]
     fn into_split(self) -> (ReadStream, WriteStream) {
         let (read_half, write_half) = self.inner.into_split();
-        (ReadStream::new(read_half), WriteStream::new(write_half))
+        (
+            ReadStream::new(ReadHalf::Unix(read_half)),
+            WriteStream::new(WriteHalf::Unix(write_half)),
+        )
     }
 
     #[cfg(test)]
@@ -94,16 +263,189 @@ impl SocketStream for TcpStream {
     }
 }
 
+#[async_trait]
+impl SocketListener for AnyListener {
+    type Stream = AnyStream;
+
+    async fn bind(addr: &str) -> io::Result<AnyListener>
+    where
+        Self: Sized,
+    {
+        if unix_socket_path(addr).is_some() {
+            #[cfg(unix)]
+            return Ok(AnyListener::Unix(UnixListener::bind(addr).await?));
+            #[cfg(not(unix))]
+            return Err(unix_unsupported());
+        }
+        Ok(AnyListener::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    async fn accept(&self) -> io::Result<(Self::Stream, SocketAddr)> {
+        match self {
+            AnyListener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, addr)| (AnyStream::Tcp(stream), addr)),
+            #[cfg(unix)]
+            AnyListener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, addr)| (AnyStream::Unix(stream), addr)),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        match self {
+            AnyListener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            AnyListener::Unix(listener) => listener.local_addr(),
+        }
+    }
+}
+
+#[async_trait]
+impl SocketStream for AnyStream {
+    async fn connect(addr: &str) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        if unix_socket_path(addr).is_some() {
+            #[cfg(unix)]
+            return Ok(AnyStream::Unix(UnixStream::connect(addr).await?));
+            #[cfg(not(unix))]
+            return Err(unix_unsupported());
+        }
+        Ok(AnyStream::Tcp(TcpStream::connect(addr).await?))
+    }
+
+    fn into_split(self) -> (ReadStream, WriteStream) {
+        match self {
+            AnyStream::Tcp(stream) => stream.into_split(),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.into_split(),
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            AnyStream::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            AnyStream::Unix(stream) => stream.local_addr(),
+        }
+    }
+}
+
+/// Splits a TLS session back into a read half and a write half
+#[cfg(not(test))]
+pub(crate) fn split_tls(stream: TlsStream) -> (ReadStream, WriteStream) {
+    let (read_half, write_half) = tokio::io::split(stream);
+    (
+        ReadStream::new(ReadHalf::Tls(read_half)),
+        WriteStream::new(WriteHalf::Tls(write_half)),
+    )
+}
+
+#[cfg(test)]
+pub(crate) fn split_tls(_stream: TlsStream) -> (ReadStream, WriteStream) {
+    unimplemented!("must mock")
+}
+
+fn next_unix_peer_addr() -> SocketAddr {
+    let peer = NEXT_UNIX_PEER.fetch_add(1, Ordering::Relaxed);
+    SocketAddr::new(
+        Ipv6Addr::from(UNIX_PEER_PREFIX | u128::from(peer)).into(),
+        0,
+    )
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "unix sockets are not supported on this platform",
+    )
+}
+
+/// The read half of a TCP or a Unix socket, or of a TLS session on top of one
 #[cfg(not(test))]
-type ReadHalf = OwnedReadHalf;
+#[derive(Debug)]
+pub(crate) enum ReadHalf {
+    Tcp(OwnedReadHalf),
+    #[cfg(unix)]
+    Unix(UnixOwnedReadHalf),
+    Tls(tokio::io::ReadHalf<TlsStream>),
+}
 #[cfg(test)]
 type ReadHalf = tokio_test::io::Mock;
 
+/// The write half of a TCP or a Unix socket, or of a TLS session on top of one
 #[cfg(not(test))]
-type WriteHalf = OwnedWriteHalf;
+#[derive(Debug)]
+pub(crate) enum WriteHalf {
+    Tcp(OwnedWriteHalf),
+    #[cfg(unix)]
+    Unix(UnixOwnedWriteHalf),
+    Tls(tokio::io::WriteHalf<TlsStream>),
+}
 #[cfg(test)]
 type WriteHalf = tokio_test::io::Mock;
 
+#[cfg(not(test))]
+impl AsyncRead for ReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        match self.get_mut() {
+            ReadHalf::Tcp(half) => Pin::new(half).poll_read(cx, buf),
+            #[cfg(unix)]
+            ReadHalf::Unix(half) => Pin::new(half).poll_read(cx, buf),
+            ReadHalf::Tls(half) => Pin::new(half).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl AsyncWrite for WriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, io::Error>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_write(cx, buf),
+            #[cfg(unix)]
+            WriteHalf::Unix(half) => Pin::new(half).poll_write(cx, buf),
+            WriteHalf::Tls(half) => Pin::new(half).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_flush(cx),
+            #[cfg(unix)]
+            WriteHalf::Unix(half) => Pin::new(half).poll_flush(cx),
+            WriteHalf::Tls(half) => Pin::new(half).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_shutdown(cx),
+            #[cfg(unix)]
+            WriteHalf::Unix(half) => Pin::new(half).poll_shutdown(cx),
+            WriteHalf::Tls(half) => Pin::new(half).poll_shutdown(cx),
+        }
+    }
+}
+
 pin_project! {
     /// Wrapper for OwnedReadHalf for mocking
     #[derive(Debug)]
@@ -139,7 +481,7 @@ impl WriteStream {
 
 impl AsyncRead for ReadStream {
     fn poll_read(
-        self: std::pin::Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<io::Result<()>> {
@@ -149,7 +491,7 @@ impl AsyncRead for ReadStream {
 
 impl AsyncWrite for WriteStream {
     fn poll_write(
-        self: std::pin::Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, io::Error>> {
@@ -157,14 +499,14 @@ impl AsyncWrite for WriteStream {
     }
 
     fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
         self.project().inner.poll_flush(cx)
     }
 
     fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), io::Error>> {
         self.project().inner.poll_shutdown(cx)
@@ -193,4 +535,93 @@ mod tests {
         let _stream = TcpStream::connect("127.0.0.1:9909").await.unwrap();
         // let _ = stream.into_split();
     }
+
+    #[tokio::test]
+    async fn resolves_ephemeral_port() {
+        let listener = AnyListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.starts_with("127.0.0.1:"));
+        assert_ne!("127.0.0.1:0", addr);
+    }
+
+    #[tokio::test]
+    async fn accepted_stream_knows_the_connected_address() {
+        let listener = AnyListener::bind("0.0.0.0:0").await.unwrap();
+        let port = listener
+            .local_addr()
+            .unwrap()
+            .rsplit(':')
+            .next()
+            .unwrap()
+            .to_owned();
+        let connect_addr = format!("127.0.0.1:{}", port);
+        let connect = tokio::spawn(async move { AnyStream::connect(&connect_addr).await });
+        let (stream, _) = listener.accept().await.unwrap();
+        assert_ok!(connect.await.unwrap());
+        assert_eq!(
+            Some(format!("127.0.0.1:{}", port).parse().unwrap()),
+            stream.local_addr()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_stream() {
+        let path = std::env::temp_dir().join(format!("noxious-test-{}.sock", std::process::id()));
+        let addr = format!("unix:{}", path.display());
+        let listener = AnyListener::bind(&addr).await.unwrap();
+        assert!(matches!(listener, AnyListener::Unix(_)));
+
+        let connect_addr = addr.clone();
+        let connect = tokio::spawn(async move { AnyStream::connect(&connect_addr).await });
+        let (stream, first) = listener.accept().await.unwrap();
+        assert!(matches!(stream, AnyStream::Unix(_)));
+        assert_eq!(None, stream.local_addr());
+        assert!(matches!(connect.await.unwrap(), Ok(AnyStream::Unix(_))));
+        assert_ne!(first, next_unix_peer_addr());
+
+        assert_eq!(addr, listener.local_addr().unwrap());
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_over_a_leftover_socket_file() {
+        let path = std::env::temp_dir().join(format!("noxious-stale-{}.sock", std::process::id()));
+        let addr = format!("unix:{}", path.display());
+        // Like a crashed proxy, the std listener leaves its socket file behind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = AnyListener::bind(&addr).await.unwrap();
+        let connect_addr = addr.clone();
+        let connect = tokio::spawn(async move { AnyStream::connect(&connect_addr).await });
+        assert_ok!(listener.accept().await);
+        assert_ok!(connect.await.unwrap());
+
+        // A live socket is not taken over
+        assert_eq!(
+            io::ErrorKind::AddrInUse,
+            AnyListener::bind(&addr).await.unwrap_err().kind()
+        );
+        drop(listener);
+
+        // Neither is a file that isn't a socket
+        std::fs::write(&path, b"data").unwrap();
+        assert_eq!(
+            io::ErrorKind::AddrInUse,
+            AnyListener::bind(&addr).await.unwrap_err().kind()
+        );
+        assert_eq!(b"data".to_vec(), std::fs::read(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_unix_addresses() {
+        assert_eq!(Some("/tmp/a.sock"), unix_socket_path("unix:/tmp/a.sock"));
+        assert_eq!(None, unix_socket_path("127.0.0.1:80"));
+        let cidr: crate::matcher::Cidr = "100::/64".parse().unwrap();
+        assert!(cidr.contains(next_unix_peer_addr().ip()));
+    }
 }
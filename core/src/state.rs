@@ -1,20 +1,34 @@
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use tokio::sync::Mutex as AsyncMutex;
 
 use crate::{
     proxy::{Links, ProxyConfig, Toxics},
-    toxic::{Toxic, ToxicKind},
+    toxic::{StreamDirection, Toxic, ToxicKind},
 };
 
 /// The wrapper for the proxy state
 #[derive(Debug)]
 pub struct ProxyState {
     inner: Mutex<ProxyStateInner>,
+    /// The number of connections accepted so far
+    accepted_connections: AtomicU64,
+    /// Upstream address -> the number of clients still connecting to it
+    connecting: Mutex<HashMap<String, usize>>,
+}
+
+/// Counts a client as connected to an upstream while the proxy connects to it, until dropped
+#[derive(Debug)]
+pub(crate) struct Connecting {
+    state: Arc<ProxyState>,
+    upstream: String,
 }
 
 /// The inner state of proxy, holding the list of connected clients and currently active toxics
@@ -34,6 +48,9 @@ pub struct SharedProxyInfo {
     pub config: Arc<ProxyConfig>,
     /// The current proxy state containing the current collection of toxics and the connected clients
     pub state: Arc<ProxyState>,
+    /// The address the proxy is bound to, with the port resolved if the config has port 0.
+    /// The listen address in the config is reported if None
+    pub listen_addr: Option<String>,
 }
 
 impl ProxyState {
@@ -44,6 +61,45 @@ impl ProxyState {
                 clients: HashMap::new(),
                 toxics,
             }),
+            accepted_connections: AtomicU64::new(0),
+            connecting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a newly accepted connection, returns its order starting from 1
+    pub fn next_connection(&self) -> u64 {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The number of clients connected or connecting to this upstream address
+    pub(crate) fn open_connections(&self, upstream: &str) -> usize {
+        let connected = self
+            .lock()
+            .clients
+            .values()
+            .filter(|links| links.upstream() == upstream)
+            .count();
+        let connecting = self
+            .connecting
+            .lock()
+            .expect("ProxyState poisoned")
+            .get(upstream)
+            .copied()
+            .unwrap_or(0);
+        connected + connecting
+    }
+
+    /// Count a client as connecting to this upstream address until the guard is dropped
+    pub(crate) fn start_connecting(self: &Arc<Self>, upstream: &str) -> Connecting {
+        *self
+            .connecting
+            .lock()
+            .expect("ProxyState poisoned")
+            .entry(upstream.to_owned())
+            .or_insert(0) += 1;
+        Connecting {
+            state: self.clone(),
+            upstream: upstream.to_owned(),
         }
     }
 
@@ -53,11 +109,32 @@ impl ProxyState {
     }
 }
 
+impl Drop for Connecting {
+    fn drop(&mut self) {
+        let mut connecting = self.state.connecting.lock().expect("ProxyState poisoned");
+        if let Some(count) = connecting.get_mut(&self.upstream) {
+            *count -= 1;
+            if *count == 0 {
+                connecting.remove(&self.upstream);
+            }
+        }
+    }
+}
+
 impl SharedProxyInfo {
     /// Return a new clone of the inner proxy config with owned strings
     pub fn clone_config(&self) -> ProxyConfig {
         (*self.config).clone()
     }
+
+    /// Return a new clone of the proxy config with the address the proxy is bound to
+    pub fn resolved_config(&self) -> ProxyConfig {
+        let mut config = self.clone_config();
+        if let Some(listen_addr) = &self.listen_addr {
+            config.listen = listen_addr.clone();
+        }
+        config
+    }
 }
 
 #[allow(missing_copy_implementations)]
@@ -83,49 +160,69 @@ impl ToxicState {
     }
 }
 
+/// Toxic name and link direction -> the toxic state
+type ToxicStates = HashMap<(String, StreamDirection), Arc<AsyncMutex<ToxicState>>>;
+
 #[derive(Debug)]
 pub(crate) struct ToxicStateHolder {
-    inner: Mutex<HashMap<String, Arc<AsyncMutex<ToxicState>>>>,
+    /// Keyed by the toxic name and the link direction, so a toxic on both directions
+    /// keeps a separate state for each
+    inner: Mutex<ToxicStates>,
 }
 
 impl ToxicStateHolder {
     pub(crate) fn for_toxics(toxics: &Toxics) -> Option<Arc<ToxicStateHolder>> {
-        let toxics_pair: [&[Toxic]; 2] = [&toxics.upstream, &toxics.downstream];
-        let stateful_toxics: Vec<&Toxic> = toxics_pair
+        let holder = ToxicStateHolder {
+            inner: Mutex::new(HashMap::new()),
+        };
+        holder.init_missing_states(toxics);
+        if holder
+            .inner
+            .lock()
+            .expect("ToxicStateHolder lock poisoned")
+            .is_empty()
+        {
+            None
+        } else {
+            Some(Arc::new(holder))
+        }
+    }
+
+    /// Initialize the state of the stateful toxics that don't have one yet, like the toxics
+    /// added after the connection was established. Existing states are kept.
+    pub(crate) fn init_missing_states(&self, toxics: &Toxics) {
+        let mut inner = self.inner.lock().expect("ToxicStateHolder lock poisoned");
+        let toxics_pair: [(StreamDirection, &[Toxic]); 2] = [
+            (StreamDirection::Upstream, &toxics.upstream),
+            (StreamDirection::Downstream, &toxics.downstream),
+        ];
+        for (direction, toxic) in toxics_pair
             .iter()
-            .flat_map(|direction_toxics| {
+            .flat_map(|(direction, direction_toxics)| {
                 direction_toxics
                     .iter()
-                    .filter(|toxic| toxic.kind.is_stateful())
+                    .map(move |toxic| (*direction, toxic))
             })
-            .collect();
-
-        if stateful_toxics.is_empty() {
-            None
-        } else {
-            let mut state_map: HashMap<String, Arc<AsyncMutex<ToxicState>>> = HashMap::new();
-
-            for toxic in stateful_toxics {
-                if let Some(initial_toxic_state) = ToxicState::for_toxic_kind(&toxic.kind) {
-                    state_map.insert(
-                        toxic.name.to_owned(),
-                        Arc::new(AsyncMutex::new(initial_toxic_state)),
-                    );
-                }
+            .filter(|(_, toxic)| toxic.kind.is_stateful())
+        {
+            let key = (toxic.name.to_owned(), direction);
+            if inner.contains_key(&key) {
+                continue;
+            }
+            if let Some(initial_toxic_state) = ToxicState::for_toxic_kind(&toxic.kind) {
+                inner.insert(key, Arc::new(AsyncMutex::new(initial_toxic_state)));
             }
-            Some(Arc::new(ToxicStateHolder {
-                inner: Mutex::new(state_map),
-            }))
         }
     }
 
     pub(crate) fn get_state_for_toxic(
         &self,
         toxic_name: &str,
+        direction: StreamDirection,
     ) -> Option<Arc<AsyncMutex<ToxicState>>> {
         let inner = self.inner.lock().expect("ToxicStateHolder lock poisoned");
         inner
-            .get(toxic_name)
+            .get(&(toxic_name.to_owned(), direction))
             .map(|toxic_state| Arc::clone(toxic_state))
     }
 }
@@ -133,7 +230,24 @@ impl ToxicStateHolder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::toxic::StreamDirection;
+
+    #[test]
+    fn resolves_listen_address() {
+        let config: ProxyConfig = serde_json::from_str(
+            "{\"name\":\"eph\",\"listen\":\"127.0.0.1:0\",\"upstream\":\"127.0.0.1:5432\"}",
+        )
+        .unwrap();
+        let mut info = SharedProxyInfo {
+            config: Arc::new(config),
+            state: Arc::new(ProxyState::new(Toxics::empty())),
+            listen_addr: None,
+        };
+        assert_eq!("127.0.0.1:0", info.resolved_config().listen);
+
+        info.listen_addr = Some("127.0.0.1:40123".to_owned());
+        assert_eq!("127.0.0.1:40123", info.resolved_config().listen);
+        assert_eq!("127.0.0.1:0", info.config.listen);
+    }
 
     #[tokio::test]
     async fn initializes_toxic_state_for_limit_data() {
@@ -144,6 +258,7 @@ mod tests {
                 name: "limiter".to_owned(),
                 toxicity: 0.5,
                 direction: StreamDirection::Downstream,
+                ..Default::default()
             }],
         };
         let holder = ToxicStateHolder::for_toxics(&toxics);
@@ -153,10 +268,12 @@ mod tests {
             holder
                 .clone()
                 .unwrap()
-                .get_state_for_toxic("wrong")
+                .get_state_for_toxic("wrong", StreamDirection::Downstream)
                 .is_none()
         );
-        let state = holder.unwrap().get_state_for_toxic("limiter");
+        let state = holder
+            .unwrap()
+            .get_state_for_toxic("limiter", StreamDirection::Downstream);
         assert_eq!(true, state.is_some());
         let state = state.unwrap();
         let data = state.lock().await;
@@ -180,9 +297,48 @@ mod tests {
                 name: "lat".to_owned(),
                 toxicity: 0.5,
                 direction: StreamDirection::Downstream,
+                ..Default::default()
             }],
         };
         let holder = ToxicStateHolder::for_toxics(&toxics);
         assert_eq!(true, holder.is_none());
     }
+
+    #[test]
+    fn initializes_state_for_toxics_added_later() {
+        let limiter = |name: &str| Toxic {
+            kind: ToxicKind::LimitData { bytes: 100 },
+            name: name.to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Upstream,
+            ..Default::default()
+        };
+        let mut toxics = Toxics {
+            upstream: vec![limiter("first")],
+            downstream: Vec::new(),
+        };
+        let holder = ToxicStateHolder::for_toxics(&toxics).unwrap();
+        let first = holder
+            .get_state_for_toxic("first", StreamDirection::Upstream)
+            .unwrap();
+
+        toxics.upstream.push(limiter("second"));
+        holder.init_missing_states(&toxics);
+        assert!(Arc::ptr_eq(
+            &first,
+            &holder
+                .get_state_for_toxic("first", StreamDirection::Upstream)
+                .unwrap()
+        ));
+        assert!(holder
+            .get_state_for_toxic("second", StreamDirection::Upstream)
+            .is_some());
+    }
+
+    #[test]
+    fn counts_connections_from_one() {
+        let state = ProxyState::new(Toxics::empty());
+        assert_eq!(1, state.next_connection());
+        assert_eq!(2, state.next_connection());
+    }
 }
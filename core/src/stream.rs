@@ -1,3 +1,4 @@
+use crate::proxy::{Endianness, Framing};
 use crate::signal::Stop;
 use crate::socket::{ReadStream, WriteStream};
 use bytes::{Bytes, BytesMut};
@@ -5,10 +6,100 @@ use futures::{Sink, SinkExt, Stream, StreamExt};
 use std::io;
 use std::pin::Pin;
 
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
-pub(crate) type Read = FramedRead<ReadStream, BytesCodec>;
-pub(crate) type Write = FramedWrite<WriteStream, BytesCodec>;
+/// Frames larger than this are treated as a protocol error, instead of buffering forever
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+pub(crate) type Read = FramedRead<ReadStream, FrameCodec>;
+pub(crate) type Write = FramedWrite<WriteStream, FrameCodec>;
+
+/// Splits the read side into chunks according to the proxy framing, and writes chunks as-is.
+/// Delimiters and length prefixes are kept in the chunks, so the bytes on the wire do not change.
+#[derive(Debug, Clone)]
+pub(crate) struct FrameCodec {
+    framing: Framing,
+}
+
+impl FrameCodec {
+    pub(crate) fn new(framing: Framing) -> Self {
+        FrameCodec { framing }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let frame_len = match self.framing {
+            Framing::Raw => src.len(),
+            Framing::Newline => match src.iter().position(|byte| *byte == b'\n') {
+                Some(pos) => pos + 1,
+                None => {
+                    check_frame_length(src.len() as u64)?;
+                    return Ok(None);
+                }
+            },
+            Framing::LengthPrefixed { width, endianness } => {
+                let width = width as usize;
+                if src.len() < width {
+                    return Ok(None);
+                }
+                let payload_len = read_prefix(&src[..width], endianness);
+                check_frame_length(payload_len)?;
+                let frame_len = width + payload_len as usize;
+                if src.len() < frame_len {
+                    src.reserve(frame_len - src.len());
+                    return Ok(None);
+                }
+                frame_len
+            }
+        };
+        if frame_len == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(src.split_to(frame_len)))
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        // Pass the trailing partial frame through instead of failing, the peer sees what was sent
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Ok(Some(src.split())),
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+fn read_prefix(prefix: &[u8], endianness: Endianness) -> u64 {
+    let fold = |len: u64, byte: &u8| (len << 8) | *byte as u64;
+    match endianness {
+        Endianness::Big => prefix.iter().fold(0, fold),
+        Endianness::Little => prefix.iter().rev().fold(0, fold),
+    }
+}
+
+fn check_frame_length(len: u64) -> io::Result<()> {
+    if len > MAX_FRAME_LENGTH as u64 {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame exceeds the maximum frame length",
+        ))
+    } else {
+        Ok(())
+    }
+}
 
 pub(crate) async fn forward(
     reader: &mut Read,
@@ -93,3 +184,85 @@ pub(crate) async fn forward_write(
     }
     Ok(writer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(framing: Framing, input: &[u8]) -> Vec<BytesMut> {
+        let mut codec = FrameCodec::new(framing);
+        let mut src = BytesMut::from(input);
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode_eof(&mut src).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn raw_returns_everything() {
+        assert_eq!(
+            vec![BytesMut::from("a\nbc")],
+            decode_all(Framing::Raw, b"a\nbc")
+        );
+    }
+
+    #[test]
+    fn newline_keeps_delimiter() {
+        let mut codec = FrameCodec::new(Framing::Newline);
+        let mut src = BytesMut::from("one\ntw");
+        assert_eq!(
+            Some(BytesMut::from("one\n")),
+            codec.decode(&mut src).unwrap()
+        );
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        src.extend_from_slice(b"o\n");
+        assert_eq!(
+            Some(BytesMut::from("two\n")),
+            codec.decode(&mut src).unwrap()
+        );
+    }
+
+    #[test]
+    fn length_prefixed_big_endian() {
+        let framing = Framing::LengthPrefixed {
+            width: 2,
+            endianness: Endianness::Big,
+        };
+        let input = [0, 2, b'h', b'i', 0, 1, b'!', 0, 5, b'x'];
+        assert_eq!(
+            vec![
+                BytesMut::from(&input[..4]),
+                BytesMut::from(&input[4..7]),
+                BytesMut::from(&input[7..]),
+            ],
+            decode_all(framing, &input)
+        );
+    }
+
+    #[test]
+    fn length_prefixed_little_endian() {
+        let mut codec = FrameCodec::new(Framing::LengthPrefixed {
+            width: 4,
+            endianness: Endianness::Little,
+        });
+        let mut src = BytesMut::from(&[3, 0, 0, 0, 1, 2][..]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        src.extend_from_slice(&[3]);
+        assert_eq!(
+            Some(BytesMut::from(&[3, 0, 0, 0, 1, 2, 3][..])),
+            codec.decode(&mut src).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut codec = FrameCodec::new(Framing::LengthPrefixed {
+            width: 8,
+            endianness: Endianness::Big,
+        });
+        let mut src = BytesMut::from(&[0xff; 8][..]);
+        let err = codec.decode(&mut src).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
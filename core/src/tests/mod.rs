@@ -4,3 +4,5 @@
 mod proxy;
 #[cfg(test)]
 mod socket_mocks;
+#[cfg(test)]
+mod udp;
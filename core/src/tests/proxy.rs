@@ -1,10 +1,15 @@
+use crate::dns::{DnsConfig, DnsFault};
 use crate::signal::{Close, Stop};
 use crate::socket::{ReadStream, WriteStream};
+use crate::state::ProxyState;
 use crate::tests::socket_mocks::*;
 use crate::toxic::{StreamDirection, Toxic, ToxicKind};
+use crate::upstream::BalancePolicy;
 use crate::{
     link::Link,
-    proxy::{ProxyConfig, ProxyRunner, Runner, Toxics},
+    proxy::{Framing, ProxyConfig, ProxyRunner, Runner, Toxics},
+    proxy_protocol::ProxyProtocolVersion,
+    stream::FrameCodec,
 };
 use lazy_static::lazy_static;
 use mockall::predicate;
@@ -12,10 +17,11 @@ use std::{
     io,
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio::sync::Mutex as AsyncMutex;
 use tokio_test::{assert_err, assert_ok, io as test_io};
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 lazy_static! {
     static ref MOCK_LOCK: AsyncMutex<()> = AsyncMutex::new(());
@@ -30,12 +36,16 @@ async fn initialize_proxy_no_toxics_accept_fails() {
         listen: listen.to_owned(),
         upstream: "127.0.0.1:5432".to_owned(),
         enabled: true,
+        framing: Framing::Raw,
         rand_seed: None,
+        ..Default::default()
     };
     let expected_config = config.clone();
     let ctx = MockMemoryListener::bind_context();
     ctx.expect().with(predicate::eq(listen)).returning(|_c| {
         let mut m = MockMemoryListener::default();
+        m.expect_local_addr()
+            .returning(|| Ok("127.0.0.1:5431".to_owned()));
         m.expect_accept()
             .returning(|| Err(io::Error::new(io::ErrorKind::Other, "oopsie")));
         Ok(m)
@@ -46,6 +56,7 @@ async fn initialize_proxy_no_toxics_accept_fails() {
     assert_ok!(&proxy);
     let (listener, info) = proxy.unwrap();
     assert_eq!(expected_config, *info.config);
+    assert_eq!(Some(listen.to_owned()), info.listen_addr);
 
     let (_event_sender, event_receiver) = bmrng::channel(1);
 
@@ -59,6 +70,30 @@ async fn initialize_proxy_no_toxics_accept_fails() {
     assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Other,);
 }
 
+#[tokio::test]
+async fn initialize_proxy_reports_listen_address_if_listener_cannot_tell() {
+    let _lock = MOCK_LOCK.lock().await;
+    let listen = "127.0.0.1:5431";
+    let config = ProxyConfig {
+        name: "foo".to_owned(),
+        listen: listen.to_owned(),
+        upstream: "127.0.0.1:5432".to_owned(),
+        ..Default::default()
+    };
+    let ctx = MockMemoryListener::bind_context();
+    ctx.expect().with(predicate::eq(listen)).returning(|_c| {
+        let mut m = MockMemoryListener::default();
+        m.expect_local_addr()
+            .returning(|| Err(io::Error::new(io::ErrorKind::Other, "unknown")));
+        Ok(m)
+    });
+
+    let proxy = ProxyRunner::initialize_proxy::<MockMemoryListener>(config, Toxics::empty()).await;
+    let (_listener, info) = proxy.unwrap();
+    assert_eq!(None, info.listen_addr);
+    assert_eq!(listen, info.resolved_config().listen);
+}
+
 #[tokio::test]
 async fn run_proxy_no_toxics_forward() {
     let _lock = MOCK_LOCK.lock().await;
@@ -69,7 +104,9 @@ async fn run_proxy_no_toxics_forward() {
         listen: listen.to_owned(),
         upstream: upstream.to_owned(),
         enabled: true,
+        framing: Framing::Raw,
         rand_seed: None,
+        ..Default::default()
     };
     let expected_config = config.clone();
     let listener_ctx = MockMemoryListener::bind_context();
@@ -84,6 +121,9 @@ async fn run_proxy_no_toxics_forward() {
             let listeners = listeners.clone();
 
             let mut listener = MockMemoryListener::default();
+            listener
+                .expect_local_addr()
+                .returning(|| Ok("127.0.0.1:5431".to_owned()));
             listener.expect_accept().returning(move || {
                 let mut val = listeners.lock().unwrap();
                 // only accept one connection
@@ -161,7 +201,9 @@ async fn run_proxy_with_slicer() {
         listen: listen.to_owned(),
         upstream: upstream.to_owned(),
         enabled: true,
+        framing: Framing::Raw,
         rand_seed: None,
+        ..Default::default()
     };
     let expected_config = config.clone();
     let listener_ctx = MockMemoryListener::bind_context();
@@ -176,6 +218,9 @@ async fn run_proxy_with_slicer() {
             let listeners = listeners.clone();
 
             let mut listener = MockMemoryListener::default();
+            listener
+                .expect_local_addr()
+                .returning(|| Ok("127.0.0.1:5431".to_owned()));
             listener.expect_accept().returning(move || {
                 let mut val = listeners.lock().unwrap();
                 // only accept one connection
@@ -232,6 +277,7 @@ async fn run_proxy_with_slicer() {
             },
             direction: StreamDirection::Upstream,
             toxicity: 1.0,
+            ..Default::default()
         }],
         downstream: Vec::new(),
     };
@@ -254,14 +300,299 @@ async fn run_proxy_with_slicer() {
     let _ = close.recv().await;
 }
 
+#[tokio::test]
+async fn run_proxy_sends_connected_address_from_wildcard_listener() {
+    let _lock = MOCK_LOCK.lock().await;
+    let listen = "0.0.0.0:5431";
+    let upstream = "127.0.0.1:5432";
+    let config = ProxyConfig {
+        name: "foo".to_owned(),
+        listen: listen.to_owned(),
+        upstream: upstream.to_owned(),
+        enabled: true,
+        framing: Framing::Raw,
+        rand_seed: None,
+        send_proxy_protocol: Some(ProxyProtocolVersion::V1),
+        ..Default::default()
+    };
+    let listener_ctx = MockMemoryListener::bind_context();
+
+    let listeners = Arc::new(Mutex::new(0));
+    // Keep the client handles alive, so the client stays connected
+    let client_handles = Arc::new(Mutex::new(Vec::new()));
+    let handles = client_handles.clone();
+
+    listener_ctx
+        .expect()
+        .with(predicate::eq(listen))
+        .returning(move |_c| {
+            let listeners = listeners.clone();
+            let handles = handles.clone();
+
+            let mut listener = MockMemoryListener::default();
+            listener
+                .expect_local_addr()
+                .returning(|| Ok("0.0.0.0:5431".to_owned()));
+            listener.expect_accept().returning(move || {
+                let mut val = listeners.lock().unwrap();
+                // only accept one connection
+                if *val > 0 {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "done"));
+                }
+                *val += 1;
+                let (client_read, client_handle_read) = test_io::Builder::new().build_with_handle();
+                let (client_write, client_handle_write) =
+                    test_io::Builder::new().build_with_handle();
+                handles
+                    .lock()
+                    .unwrap()
+                    .extend(vec![client_handle_read, client_handle_write]);
+
+                let mut stream = MockMemoryStream::default();
+                stream
+                    .expect_local_addr()
+                    .returning(|| Some(SocketAddr::from(([127, 0, 0, 1], 5431))));
+                stream.expect_into_split().return_once_st(|| {
+                    (ReadStream::new(client_read), WriteStream::new(client_write))
+                });
+                Ok((stream, SocketAddr::from(([127, 0, 0, 1], 29991))))
+            });
+            Ok(listener)
+        });
+
+    let upstream_ctx = MockMemoryStream::connect_context();
+    let (upstream_read, _upstream_handle_read) = test_io::Builder::new().build_with_handle();
+    let (upstream_write, mut upstream_handle_write) = test_io::Builder::new().build_with_handle();
+
+    // The header names the address the client connected to, not 0.0.0.0
+    upstream_handle_write.write(b"PROXY TCP4 127.0.0.1 127.0.0.1 29991 5431\r\n");
+    upstream_ctx
+        .expect()
+        .with(predicate::eq(upstream))
+        .return_once(move |_c| {
+            let mut stream = MockMemoryStream::default();
+            stream.expect_into_split().return_once_st(|| {
+                (
+                    ReadStream::new(upstream_read),
+                    WriteStream::new(upstream_write),
+                )
+            });
+            Ok(stream)
+        });
+
+    let proxy = ProxyRunner::initialize_proxy::<MockMemoryListener>(config, Toxics::empty()).await;
+    assert_ok!(&proxy);
+    let (listener, info) = proxy.unwrap();
+    let state = info.state.clone();
+
+    let (_event_sender, event_receiver) = bmrng::channel(1);
+
+    let (stop, stopper) = Stop::new();
+    let (close, closer) = Close::new();
+
+    let handle = tokio::spawn(async move {
+        let result = ProxyRunner::run_proxy(listener, info, event_receiver, stop, closer).await;
+        assert_err!(result);
+    });
+    assert_ok!(handle.await);
+    // The client is linked only after the upstream took the header
+    assert!(linked_within(&state, SocketAddr::from(([127, 0, 0, 1], 29991)), 1000).await);
+    stopper.stop();
+    let _ = close.recv().await;
+}
+
+/// Wait until the client is linked to an upstream, returns false if it takes too long
+async fn linked_within(state: &ProxyState, client: SocketAddr, millis: u64) -> bool {
+    let linked = tokio::time::timeout(Duration::from_millis(millis), async {
+        while !state.lock().clients.contains_key(&client) {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await;
+    linked.is_ok()
+}
+
+/// A stream that stays open without data, keeping its handles in `handles`
+fn idle_stream(handles: &Arc<Mutex<Vec<test_io::Handle>>>) -> MockMemoryStream {
+    let (read, read_handle) = test_io::Builder::new().build_with_handle();
+    let (write, write_handle) = test_io::Builder::new().build_with_handle();
+    handles
+        .lock()
+        .unwrap()
+        .extend(vec![read_handle, write_handle]);
+    let mut stream = MockMemoryStream::default();
+    stream
+        .expect_into_split()
+        .return_once_st(|| (ReadStream::new(read), WriteStream::new(write)));
+    stream
+}
+
+/// A listener that accepts an idle client from each of these ports, then fails
+fn idle_clients_listener(
+    listen: &'static str,
+    ports: Vec<u16>,
+    handles: Arc<Mutex<Vec<test_io::Handle>>>,
+) -> MockMemoryListener {
+    let mut listener = MockMemoryListener::default();
+    listener
+        .expect_local_addr()
+        .returning(move || Ok(listen.to_owned()));
+    let mut ports = ports.into_iter();
+    listener
+        .expect_accept()
+        .returning(move || match ports.next() {
+            Some(port) => Ok((
+                idle_stream(&handles),
+                SocketAddr::from(([127, 0, 0, 1], port)),
+            )),
+            None => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "done")),
+        });
+    listener
+}
+
+#[tokio::test]
+async fn run_proxy_blackholed_upstream_does_not_block_accepts() {
+    let _lock = MOCK_LOCK.lock().await;
+    let listen = "127.0.0.1:5431";
+    // The host name takes a minute to resolve, like an upstream that drops the SYN packets
+    let config = ProxyConfig {
+        name: "foo".to_owned(),
+        listen: listen.to_owned(),
+        upstream: "blackhole.test:5432".to_owned(),
+        upstreams: vec!["127.0.0.1:5433".to_owned()],
+        balance: BalancePolicy::LeastConnections,
+        dns: Some(DnsConfig {
+            hosts: vec![(
+                "blackhole.test".to_owned(),
+                vec!["127.0.0.1".parse().unwrap()],
+            )]
+            .into_iter()
+            .collect(),
+            fault: Some(DnsFault::Slow { delay: 60_000 }),
+        }),
+        enabled: true,
+        framing: Framing::Raw,
+        rand_seed: None,
+        ..Default::default()
+    };
+    let handles = Arc::new(Mutex::new(Vec::new()));
+    let listener_ctx = MockMemoryListener::bind_context();
+    let listener_handles = handles.clone();
+    listener_ctx
+        .expect()
+        .with(predicate::eq(listen))
+        .return_once(move |_c| {
+            Ok(idle_clients_listener(
+                listen,
+                vec![29991, 29992],
+                listener_handles,
+            ))
+        });
+
+    let upstream_ctx = MockMemoryStream::connect_context();
+    let upstream_handles = handles.clone();
+    upstream_ctx
+        .expect()
+        .with(predicate::eq("127.0.0.1:5433"))
+        .returning(move |_c| Ok(idle_stream(&upstream_handles)));
+
+    let proxy = ProxyRunner::initialize_proxy::<MockMemoryListener>(config, Toxics::empty()).await;
+    let (listener, info) = proxy.unwrap();
+    let state = info.state.clone();
+
+    let (_event_sender, event_receiver) = bmrng::channel(1);
+    let (stop, stopper) = Stop::new();
+    let (close, closer) = Close::new();
+    let handle = tokio::spawn(async move {
+        let result = ProxyRunner::run_proxy(listener, info, event_receiver, stop, closer).await;
+        assert_err!(result);
+    });
+    assert_ok!(handle.await);
+
+    // The second client goes to the other upstream, because the first one counts as
+    // connected to the blackholed upstream while it connects
+    assert!(linked_within(&state, SocketAddr::from(([127, 0, 0, 1], 29992)), 1000).await);
+    assert!(!state
+        .lock()
+        .clients
+        .contains_key(&SocketAddr::from(([127, 0, 0, 1], 29991))));
+    assert_eq!(1, state.open_connections("blackhole.test:5432"));
+    stopper.stop();
+    let _ = close.recv().await;
+}
+
+#[tokio::test]
+async fn run_proxy_retries_upstream_with_backoff() {
+    let _lock = MOCK_LOCK.lock().await;
+    let listen = "127.0.0.1:5431";
+    let upstream = "127.0.0.1:5432";
+    let config = ProxyConfig {
+        name: "foo".to_owned(),
+        listen: listen.to_owned(),
+        upstream: upstream.to_owned(),
+        connect_retries: 2,
+        retry_backoff_ms: Some(50),
+        enabled: true,
+        framing: Framing::Raw,
+        rand_seed: None,
+        ..Default::default()
+    };
+    let handles = Arc::new(Mutex::new(Vec::new()));
+    let listener_ctx = MockMemoryListener::bind_context();
+    let listener_handles = handles.clone();
+    listener_ctx
+        .expect()
+        .with(predicate::eq(listen))
+        .return_once(move |_c| Ok(idle_clients_listener(listen, vec![29991], listener_handles)));
+
+    // Refuse the first two attempts, accept the last retry
+    let attempts = Arc::new(Mutex::new(Vec::new()));
+    let upstream_ctx = MockMemoryStream::connect_context();
+    let upstream_handles = handles.clone();
+    let upstream_attempts = attempts.clone();
+    upstream_ctx
+        .expect()
+        .with(predicate::eq(upstream))
+        .returning(move |_c| {
+            let mut attempts = upstream_attempts.lock().unwrap();
+            attempts.push(std::time::Instant::now());
+            if attempts.len() < 3 {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+            }
+            Ok(idle_stream(&upstream_handles))
+        });
+
+    let proxy = ProxyRunner::initialize_proxy::<MockMemoryListener>(config, Toxics::empty()).await;
+    let (listener, info) = proxy.unwrap();
+    let state = info.state.clone();
+
+    let (_event_sender, event_receiver) = bmrng::channel(1);
+    let (stop, stopper) = Stop::new();
+    let (close, closer) = Close::new();
+    let handle = tokio::spawn(async move {
+        let result = ProxyRunner::run_proxy(listener, info, event_receiver, stop, closer).await;
+        assert_err!(result);
+    });
+    assert_ok!(handle.await);
+
+    assert!(linked_within(&state, SocketAddr::from(([127, 0, 0, 1], 29991)), 1000).await);
+    let attempts = attempts.lock().unwrap().clone();
+    assert_eq!(3, attempts.len());
+    for pair in attempts.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(50));
+    }
+    stopper.stop();
+    let _ = close.recv().await;
+}
+
 #[tokio::test]
 async fn test_link_disband() {
     let (read, _handle_read) = test_io::Builder::new().build_with_handle();
     let (write, _handle_write) = test_io::Builder::new().build_with_handle();
     let read = ReadStream::new(read);
     let write = WriteStream::new(write);
-    let read = FramedRead::with_capacity(read, BytesCodec::new(), 1024);
-    let write = FramedWrite::new(write, BytesCodec::new());
+    let read = FramedRead::with_capacity(read, FrameCodec::new(Framing::Raw), 1024);
+    let write = FramedWrite::new(write, FrameCodec::new(Framing::Raw));
 
     let (stop, stopper) = Stop::new();
     let listen = "127.0.0.1:5431";
@@ -271,11 +602,20 @@ async fn test_link_disband() {
         listen: listen.to_owned(),
         upstream: upstream.to_owned(),
         enabled: true,
+        framing: Framing::Raw,
         rand_seed: None,
+        ..Default::default()
     };
 
     let addr: SocketAddr = SocketAddr::from(([127, 0, 0, 1], 29991));
-    let mut link = Link::new(addr, StreamDirection::Upstream, config, stop);
+    let mut link = Link::new(
+        addr,
+        1,
+        upstream.to_owned(),
+        StreamDirection::Upstream,
+        config,
+        stop,
+    );
     link.establish(read, write, Vec::new(), None);
     stopper.stop();
     let res = link.disband().await;
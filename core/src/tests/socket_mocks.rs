@@ -15,6 +15,8 @@ mock! {
             Self: Sized;
 
         async fn accept(&self) -> io::Result<(MockMemoryStream, SocketAddr)>;
+
+        fn local_addr(&self) -> io::Result<String>;
     }
 }
 
@@ -28,5 +30,7 @@ mock! {
             Self: Sized + 'static;
 
         fn into_split(self) -> (ReadStream, WriteStream);
+
+        fn local_addr(&self) -> Option<SocketAddr>;
     }
 }
@@ -0,0 +1,179 @@
+use crate::dns::{DnsConfig, DnsFault};
+use crate::signal::{Close, Stop};
+use crate::toxic::{StreamDirection, Toxic, ToxicEvent, ToxicEventKind, ToxicKind};
+use crate::{
+    proxy::{Framing, Protocol, ProxyConfig, Toxics},
+    udp::UdpProxyRunner,
+};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_test::assert_ok;
+
+/// Start an upstream that sends every datagram back, returns its address
+async fn echo_upstream() -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..len], from).await;
+        }
+    });
+    addr
+}
+
+fn config(upstream: String) -> ProxyConfig {
+    ProxyConfig {
+        name: "udp".to_owned(),
+        listen: "127.0.0.1:0".to_owned(),
+        upstream,
+        enabled: true,
+        framing: Framing::Raw,
+        rand_seed: None,
+        protocol: Protocol::Udp,
+        ..Default::default()
+    }
+}
+
+fn toxic(name: &str, kind: ToxicKind, direction: StreamDirection) -> Toxic {
+    Toxic {
+        kind,
+        name: name.to_owned(),
+        toxicity: 1.0,
+        direction,
+        ..Default::default()
+    }
+}
+
+async fn recv_within(client: &UdpSocket, millis: u64) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 1024];
+    match timeout(Duration::from_millis(millis), client.recv(&mut buf)).await {
+        Ok(Ok(len)) => Some(buf[..len].to_vec()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn forwards_datagrams_through_toxics() {
+    let upstream = echo_upstream().await;
+    let toxics = Toxics {
+        upstream: Vec::new(),
+        downstream: vec![toxic(
+            "dup",
+            ToxicKind::Duplicate { copies: 1 },
+            StreamDirection::Downstream,
+        )],
+    };
+    let (socket, info) = UdpProxyRunner::initialize_proxy(config(upstream), toxics)
+        .await
+        .unwrap();
+    let proxy_addr = socket.local_addr().unwrap();
+    let (_event_sender, event_receiver) = bmrng::channel(1);
+    let (stop, stopper) = Stop::new();
+    let (close, closer) = Close::new();
+    let handle = tokio::spawn(UdpProxyRunner::run_proxy(
+        socket,
+        info,
+        event_receiver,
+        stop,
+        closer,
+    ));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(proxy_addr).await.unwrap();
+    assert_ok!(client.send(b"hello").await);
+    assert_eq!(Some(b"hello".to_vec()), recv_within(&client, 1000).await);
+    assert_eq!(Some(b"hello".to_vec()), recv_within(&client, 1000).await);
+
+    stopper.stop();
+    assert_ok!(handle.await.unwrap());
+    assert_ok!(close.recv().await);
+}
+
+#[tokio::test]
+async fn applies_toxic_events_to_sessions() {
+    let upstream = echo_upstream().await;
+    let (socket, info) = UdpProxyRunner::initialize_proxy(config(upstream), Toxics::empty())
+        .await
+        .unwrap();
+    let proxy_addr = socket.local_addr().unwrap();
+    let (event_sender, event_receiver) = bmrng::channel(1);
+    let (stop, stopper) = Stop::new();
+    let (_close, closer) = Close::new();
+    tokio::spawn(UdpProxyRunner::run_proxy(
+        socket,
+        info,
+        event_receiver,
+        stop,
+        closer,
+    ));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(proxy_addr).await.unwrap();
+    assert_ok!(client.send(b"one").await);
+    assert_eq!(Some(b"one".to_vec()), recv_within(&client, 1000).await);
+
+    let event = ToxicEvent::new(
+        "udp".to_owned(),
+        ToxicEventKind::AddToxic(toxic("drop", ToxicKind::Drop, StreamDirection::Upstream)),
+    );
+    assert_ok!(event_sender.send_receive(event).await.unwrap());
+    assert_ok!(client.send(b"two").await);
+    assert_eq!(None, recv_within(&client, 200).await);
+
+    let event = ToxicEvent::new(
+        "udp".to_owned(),
+        ToxicEventKind::RemoveToxic("drop".to_owned()),
+    );
+    assert_ok!(event_sender.send_receive(event).await.unwrap());
+    assert_ok!(client.send(b"three").await);
+    assert_eq!(Some(b"three".to_vec()), recv_within(&client, 1000).await);
+
+    stopper.stop();
+}
+
+#[tokio::test]
+async fn slow_session_connect_does_not_hold_up_other_clients() {
+    let upstream = echo_upstream().await;
+    let port = upstream.rsplit(':').next().unwrap().to_owned();
+    // Round robin sends the first client to the slow host name, the second to the IP address
+    let mut config = config(format!("slow.test:{}", port));
+    config.upstreams = vec![upstream];
+    config.dns = Some(DnsConfig {
+        hosts: vec![("slow.test".to_owned(), vec!["127.0.0.1".parse().unwrap()])]
+            .into_iter()
+            .collect(),
+        fault: Some(DnsFault::Slow { delay: 500 }),
+    });
+    let (socket, info) = UdpProxyRunner::initialize_proxy(config, Toxics::empty())
+        .await
+        .unwrap();
+    let proxy_addr = socket.local_addr().unwrap();
+    let (_event_sender, event_receiver) = bmrng::channel(1);
+    let (stop, stopper) = Stop::new();
+    let (_close, closer) = Close::new();
+    tokio::spawn(UdpProxyRunner::run_proxy(
+        socket,
+        info,
+        event_receiver,
+        stop,
+        closer,
+    ));
+
+    let slow = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    slow.connect(proxy_addr).await.unwrap();
+    assert_ok!(slow.send(b"one").await);
+
+    let fast = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    fast.connect(proxy_addr).await.unwrap();
+    assert_ok!(fast.send(b"hello").await);
+    assert_eq!(Some(b"hello".to_vec()), recv_within(&fast, 200).await);
+
+    // Datagrams sent while the session connects wait for it
+    assert_ok!(slow.send(b"two").await);
+    assert_eq!(Some(b"one".to_vec()), recv_within(&slow, 2000).await);
+    assert_eq!(Some(b"two".to_vec()), recv_within(&slow, 1000).await);
+
+    stopper.stop();
+}
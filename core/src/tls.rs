@@ -0,0 +1,358 @@
+use crate::proxy::ProxyConfig;
+use crate::socket::{split_tls, unix_socket_path, ReadStream, WriteStream};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// TLS settings for the proxy listener. Clients connect to the proxy with TLS,
+/// and the toxics see the decrypted bytes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListenTls {
+    /// Path to the PEM certificate chain the proxy presents to clients
+    pub cert: String,
+    /// Path to the PEM private key of the certificate
+    pub key: String,
+}
+
+/// TLS settings for the connection to the upstream
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamTls {
+    /// Path to a PEM bundle of CA certificates to verify the upstream with.
+    /// The system trust store is used if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<String>,
+    /// The server name to send and to verify. The host of the upstream address if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+}
+
+/// A TLS session on either side of the proxy
+pub(crate) type TlsStream = tokio_rustls::TlsStream<JoinedStream>;
+
+pin_project! {
+    /// The two halves of a socket joined back, for a TLS session to run on
+    #[derive(Debug)]
+    pub(crate) struct JoinedStream {
+        #[pin]
+        read: ReadStream,
+        #[pin]
+        write: WriteStream,
+    }
+}
+
+/// The loaded certificates and keys of a proxy, ready for handshakes
+#[derive(Clone, Default)]
+pub(crate) struct TlsContext {
+    acceptor: Option<TlsAcceptor>,
+    connector: Option<(TlsConnector, UpstreamTls)>,
+}
+
+impl TlsContext {
+    /// Load the certificates and keys in the proxy config
+    pub(crate) fn for_config(config: &ProxyConfig) -> io::Result<Self> {
+        let acceptor = match &config.tls {
+            Some(tls) => Some(TlsAcceptor::from(Arc::new(server_config(tls)?))),
+            None => None,
+        };
+        let connector = match &config.upstream_tls {
+            Some(tls) => {
+                // Check every upstream has a server name now, rather than on the first client
+                for upstream in config.upstream_list().iter().chain(&config.fallbacks) {
+                    server_name(tls, upstream)?;
+                }
+                let connector = TlsConnector::from(Arc::new(client_config(tls)?));
+                Some((connector, tls.clone()))
+            }
+            None => None,
+        };
+        Ok(TlsContext {
+            acceptor,
+            connector,
+        })
+    }
+
+    /// Run the server side of the TLS handshake with the client, if the listener has TLS
+    pub(crate) async fn accept(
+        &self,
+        (read, write): (ReadStream, WriteStream),
+    ) -> io::Result<(ReadStream, WriteStream)> {
+        match &self.acceptor {
+            Some(acceptor) => {
+                let stream = acceptor.accept(JoinedStream { read, write }).await?;
+                Ok(split_tls(TlsStream::Server(stream)))
+            }
+            None => Ok((read, write)),
+        }
+    }
+
+    /// Run the client side of the TLS handshake with the upstream, if the upstream has TLS.
+    /// `upstream` is the address the connection was made to, for the server name
+    pub(crate) async fn connect(
+        &self,
+        (read, write): (ReadStream, WriteStream),
+        upstream: &str,
+    ) -> io::Result<(ReadStream, WriteStream)> {
+        match &self.connector {
+            Some((connector, tls)) => {
+                let stream = connector
+                    .connect(server_name(tls, upstream)?, JoinedStream { read, write })
+                    .await?;
+                Ok(split_tls(TlsStream::Client(stream)))
+            }
+            None => Ok((read, write)),
+        }
+    }
+}
+
+impl fmt::Debug for TlsContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsContext")
+            .field("listen", &self.acceptor.is_some())
+            .field("upstream", &self.connector.as_ref().map(|(_, tls)| tls))
+            .finish()
+    }
+}
+
+fn server_config(tls: &ListenTls) -> io::Result<ServerConfig> {
+    let certs = load_certs(&tls.cert)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = load_key(&tls.key)?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn client_config(tls: &UpstreamTls) -> io::Result<ClientConfig> {
+    let certs = match &tls.ca {
+        Some(path) => load_certs(path)?,
+        None => rustls_native_certs::load_native_certs()?
+            .into_iter()
+            .map(|cert| cert.0)
+            .collect(),
+    };
+    let mut roots = RootCertStore::empty();
+    let (added, _ignored) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no usable CA certificates",
+        ));
+    }
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// The SNI setting, or the host of the upstream address
+fn server_name(tls: &UpstreamTls, upstream: &str) -> io::Result<ServerName> {
+    let name = match &tls.sni {
+        Some(sni) => sni.as_str(),
+        None if unix_socket_path(upstream).is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sni is required for unix socket upstreams",
+            ));
+        }
+        None => upstream
+            .rfind(':')
+            .map_or(upstream, |colon| &upstream[..colon])
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    ServerName::try_from(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Vec<u8>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {}", path),
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no private key in {}", path),
+                ))
+            }
+        }
+    }
+}
+
+impl AsyncRead for JoinedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().read.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for JoinedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().write.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().write.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().write.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Framing;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    fn cert_path(name: &str) -> String {
+        format!("{}/src/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn config(upstream: &str, sni: Option<&str>) -> ProxyConfig {
+        ProxyConfig {
+            name: "tls".to_owned(),
+            listen: "127.0.0.1:0".to_owned(),
+            upstream: upstream.to_owned(),
+            enabled: true,
+            framing: Framing::Raw,
+            rand_seed: None,
+            tls: Some(ListenTls {
+                cert: cert_path("leaf.pem"),
+                key: cert_path("leaf.key"),
+            }),
+            upstream_tls: Some(UpstreamTls {
+                ca: Some(cert_path("ca.pem")),
+                sni: sni.map(str::to_owned),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_self_signed_certificates() {
+        let ctx = TlsContext::for_config(&config("localhost:8443", None)).unwrap();
+        let acceptor = ctx.acceptor.clone().unwrap();
+        let (connector, tls) = ctx.connector.clone().unwrap();
+        let server_name = server_name(&tls, "localhost:8443").unwrap();
+
+        let (client, server) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        let mut client = connector.connect(server_name, client).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        assert_eq!(b"hello", &server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_each_upstream_host() {
+        let mut config = config("localhost:8443", None);
+        config.upstreams = vec!["127.0.0.1:8443".to_owned()];
+        config.fallbacks = vec!["[::1]:8443".to_owned()];
+        let ctx = TlsContext::for_config(&config).unwrap();
+        let acceptor = ctx.acceptor.clone().unwrap();
+        let (connector, tls) = ctx.connector.clone().unwrap();
+
+        // The certificate is for localhost and 127.0.0.1, so the second upstream only
+        // verifies if its own address is the server name
+        for (upstream, valid) in [
+            ("localhost:8443", true),
+            ("127.0.0.1:8443", true),
+            ("[::1]:8443", false),
+        ]
+        .iter()
+        {
+            let (client, server) = duplex(4096);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move { acceptor.accept(server).await });
+            let result = connector
+                .connect(server_name(&tls, upstream).unwrap(), client)
+                .await;
+            assert_eq!(*valid, result.is_ok(), "{}", upstream);
+        }
+    }
+
+    #[test]
+    fn checks_server_names_of_every_upstream() {
+        let mut config = config("localhost:8443", None);
+        config.fallbacks = vec!["unix:/tmp/app.sock".to_owned()];
+        assert!(TlsContext::for_config(&config).is_err());
+        let mut config = self::config("localhost:8443", Some("app.internal"));
+        config.fallbacks = vec!["unix:/tmp/app.sock".to_owned()];
+        assert!(TlsContext::for_config(&config).is_ok());
+    }
+
+    #[test]
+    fn derives_server_name_from_upstream() {
+        let tls = UpstreamTls::default();
+        assert_eq!(
+            ServerName::try_from("example.com").unwrap(),
+            server_name(&tls, "example.com:443").unwrap()
+        );
+        assert!(server_name(&tls, "[::1]:443").is_ok());
+        assert!(server_name(&tls, "unix:/tmp/app.sock").is_err());
+        let tls = UpstreamTls {
+            ca: None,
+            sni: Some("app.internal".to_owned()),
+        };
+        assert!(server_name(&tls, "unix:/tmp/app.sock").is_ok());
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let mut config = config("localhost:8443", Some("localhost"));
+        config.tls = Some(ListenTls {
+            cert: cert_path("missing.pem"),
+            key: cert_path("leaf.key"),
+        });
+        assert!(TlsContext::for_config(&config).is_err());
+
+        let mut config = self::config("localhost:8443", None);
+        config.upstream_tls = Some(UpstreamTls {
+            ca: Some(cert_path("leaf.key")),
+            sni: None,
+        });
+        assert!(TlsContext::for_config(&config).is_err());
+    }
+}
@@ -1,10 +1,15 @@
+use crate::custom::CustomToxicKind;
 use crate::error::ToxicUpdateError;
-use serde::{Deserialize, Serialize};
+use crate::matcher::ToxicMatch;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::fmt;
 use std::mem;
+use std::net::SocketAddr;
+use thiserror::Error;
 
 ///
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum StreamDirection {
     /// Represents an I/O channel from server to the client
     #[serde(rename = "downstream")]
@@ -12,9 +17,13 @@ pub enum StreamDirection {
     /// Represents an I/O channel from the client to the server
     #[serde(rename = "upstream")]
     Upstream,
+    /// Installs the toxic on both channels under one name. Links always run toxics with
+    /// their own direction, upstream or downstream
+    #[serde(rename = "both")]
+    Both,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", content = "attributes")]
 /// Toxic kind and toxic-specific attributes
 pub enum ToxicKind {
@@ -39,7 +48,7 @@ pub enum ToxicKind {
     /// Passes data through at a limited rate
     #[serde(rename = "bandwidth")]
     Bandwidth {
-        /// in KB/S
+        /// in KB/S, or in datagrams per second on UDP proxies
         rate: u64,
     },
     /// Stops the TCP connection from closing until after a delay
@@ -66,12 +75,142 @@ pub enum ToxicKind {
         /// the limit
         bytes: u64,
     },
+    /// Parses the TLS record layer without terminating TLS, and stalls, closes or corrupts
+    /// the connection at a handshake stage
+    #[serde(rename = "tls_handshake")]
+    TlsHandshake {
+        /// The handshake stage to act on
+        stage: TlsHandshakeStage,
+        /// What to do to the connection at that stage
+        action: TlsHandshakeAction,
+        /// For the stall action, in milliseconds. The connection is closed after the timeout,
+        /// or never if it is 0
+        #[serde(default = "default_zero")]
+        timeout: u64,
+    },
+    /// Detects an HTTP Upgrade to WebSocket, then drops, delays or closes on individual frames
+    #[serde(rename = "websocket")]
+    WebSocket {
+        /// What to do with the frames
+        action: WebSocketAction,
+        /// Act on every Nth data message, counting a fragmented message once. 0 is treated as 1
+        #[serde(default = "default_one")]
+        every: u64,
+        /// For the delay action, in milliseconds
+        #[serde(default = "default_zero")]
+        delay: u64,
+        /// For the close action, the status code sent in the Close frame
+        #[serde(default = "default_close_code")]
+        code: u16,
+    },
+    /// Drops all the chunks. With per chunk sampling, drops the fraction of the chunks given
+    /// by the toxicity. Meant for UDP datagrams and framed messages
+    #[serde(rename = "drop")]
+    Drop,
+    /// Sends every chunk more than once
+    #[serde(rename = "duplicate")]
+    Duplicate {
+        /// The number of extra copies sent after each chunk
+        #[serde(default = "default_one")]
+        copies: u64,
+    },
+    /// Holds chunks back and sends them in a random order
+    #[serde(rename = "reorder")]
+    Reorder {
+        /// The number of chunks shuffled together. 0 and 1 keep the order
+        #[serde(default = "default_reorder_window")]
+        window: u64,
+        /// In milliseconds, how long to wait for the window to fill before sending the
+        /// chunks held back
+        #[serde(default = "default_reorder_timeout")]
+        timeout: u64,
+    },
+    /// A toxic type registered by the library user, see the `custom` module
+    #[serde(skip)]
+    Custom(CustomToxicKind),
+}
+
+/// The type names of the toxics built into noxious. Custom toxics cannot use these names
+pub(crate) const BUILTIN_TOXIC_TYPES: &[&str] = &[
+    "noop",
+    "latency",
+    "timeout",
+    "bandwidth",
+    "slow_close",
+    "slicer",
+    "limit_data",
+    "tls_handshake",
+    "websocket",
+    "drop",
+    "duplicate",
+    "reorder",
+];
+
+/// The point in the TLS handshake a `TlsHandshake` toxic acts on
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TlsHandshakeStage {
+    /// Right after the ClientHello is passed through. ClientHello only travels upstream
+    #[serde(rename = "after_client_hello")]
+    AfterClientHello,
+    /// Right before the ServerHello is passed through. ServerHello only travels downstream
+    #[serde(rename = "before_server_hello")]
+    BeforeServerHello,
+}
+
+/// What a `TlsHandshake` toxic does to the connection at the handshake stage
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TlsHandshakeAction {
+    /// Stop passing any data through
+    #[serde(rename = "stall")]
+    Stall,
+    /// Close the connection
+    #[serde(rename = "reset")]
+    Reset,
+    /// Flip the bytes of the ClientHello or ServerHello record, then pass everything through
+    #[serde(rename = "corrupt")]
+    Corrupt,
+}
+
+/// What a `WebSocket` toxic does to the frames once the upgrade is done
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WebSocketAction {
+    /// Drop the data frame
+    #[serde(rename = "drop")]
+    Drop,
+    /// Hold the data frame back for `delay` milliseconds
+    #[serde(rename = "delay")]
+    Delay,
+    /// Drop all Ping and Pong frames, pass data frames through
+    #[serde(rename = "swallow_ping_pong")]
+    SwallowPingPong,
+    /// Send a Close frame with the status `code` instead of the data frame, then close
+    #[serde(rename = "close")]
+    Close,
+}
+
+/// How often the toxicity of a toxic is sampled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ToxicSampling {
+    /// Once when the connection is established, the toxic affects all of its chunks or none
+    #[default]
+    #[serde(rename = "per_connection")]
+    PerConnection,
+    /// For every chunk, the toxic affects a fraction of the chunks on every connection.
+    /// Only the noop, latency, timeout, bandwidth, slicer, drop and duplicate toxics sample
+    /// per chunk, the others fall back to per connection sampling. A timeout toxic swallows the chunks
+    /// it affects instead of timing out the connection
+    #[serde(rename = "per_chunk")]
+    PerChunk,
 }
 
 /// Something that can be attached to a link to modify the way the data is passed through
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Toxic {
-    #[serde(flatten)]
+    #[serde(
+        flatten,
+        serialize_with = "serialize_kind",
+        deserialize_with = "deserialize_kind"
+    )]
     /// The kind which also contains kind-specific attributes
     pub kind: ToxicKind,
     /// The unique name for this toxic
@@ -83,6 +222,58 @@ pub struct Toxic {
     #[serde(alias = "stream", default = "default_direction")]
     /// The direction this toxic is install on
     pub direction: StreamDirection,
+    /// Disabled toxics keep their place in the chain and their state, but pass data through
+    #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+    /// Limits the toxic to the matching client connections. Applies to all connections if None
+    #[serde(rename = "match", default, skip_serializing_if = "Option::is_none")]
+    pub matcher: Option<ToxicMatch>,
+    /// Whether the toxicity is sampled per connection or per chunk
+    #[serde(default, skip_serializing_if = "ToxicSampling::is_per_connection")]
+    pub sampling: ToxicSampling,
+    /// Wait this many milliseconds before adding the toxic to the proxy.
+    /// The server store schedules this, the proxy itself ignores it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_after: Option<u64>,
+    /// Remove the toxic this many milliseconds after it was added to the proxy.
+    /// The server store schedules this, the proxy itself ignores it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_after: Option<u64>,
+    /// Turns the toxic on and off periodically.
+    /// The server store schedules this, the proxy itself ignores it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ToxicSchedule>,
+    /// Where to insert the toxic in the chain when it is added or updated.
+    /// Only used in requests, it's cleared when the toxic is stored
+    #[serde(flatten)]
+    pub position: ToxicPosition,
+}
+
+/// The position of a toxic in the chain of its direction. At most one of the fields should be
+/// set. New toxics go to the end of the chain, updated toxics keep their place if none is set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ToxicPosition {
+    /// The index in the chain, 0 is the first toxic to see the data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    /// Insert right before the toxic with this name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    /// Insert right after the toxic with this name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+/// Active windows for a toxic, like "on for 30 seconds every 5 minutes".
+/// All durations are in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ToxicSchedule {
+    /// The time between the starts of two active windows
+    pub every: u64,
+    /// How long the toxic stays active in each window, less than `every`
+    pub duration: u64,
+    /// The delay before the first window starts
+    pub offset: u64,
 }
 
 /// The inners of a proxy state update event passed to the proxy runner task
@@ -95,6 +286,8 @@ pub enum ToxicEventKind {
     UpdateToxic(Toxic),
     /// Remove a toxic by name
     RemoveToxic(String),
+    /// Switch a toxic on or off by name, keeping its place in the chain and its state
+    SetToxicEnabled(String, bool),
     /// Reset. Remove all toxics
     RemoveAllToxics,
 }
@@ -117,6 +310,14 @@ fn default_toxicity() -> f32 {
     1.0
 }
 
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
 fn default_direction() -> StreamDirection {
     StreamDirection::Downstream
 }
@@ -125,11 +326,84 @@ fn default_zero() -> u64 {
     0
 }
 
+fn default_one() -> u64 {
+    1
+}
+
+fn default_close_code() -> u16 {
+    1000
+}
+
+fn default_reorder_window() -> u64 {
+    4
+}
+
+fn default_reorder_timeout() -> u64 {
+    100
+}
+
+impl StreamDirection {
+    /// Returns true if a toxic with this direction is installed on the link with the given direction
+    pub fn includes(self, link_direction: StreamDirection) -> bool {
+        self == link_direction || self == StreamDirection::Both
+    }
+}
+
 impl fmt::Display for StreamDirection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             StreamDirection::Downstream => write!(f, "downstream"),
             StreamDirection::Upstream => write!(f, "upstream"),
+            StreamDirection::Both => write!(f, "both"),
+        }
+    }
+}
+
+impl fmt::Display for TlsHandshakeStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsHandshakeStage::AfterClientHello => write!(f, "after_client_hello"),
+            TlsHandshakeStage::BeforeServerHello => write!(f, "before_server_hello"),
+        }
+    }
+}
+
+impl fmt::Display for TlsHandshakeAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsHandshakeAction::Stall => write!(f, "stall"),
+            TlsHandshakeAction::Reset => write!(f, "reset"),
+            TlsHandshakeAction::Corrupt => write!(f, "corrupt"),
+        }
+    }
+}
+
+impl fmt::Display for WebSocketAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketAction::Drop => write!(f, "drop"),
+            WebSocketAction::Delay => write!(f, "delay"),
+            WebSocketAction::SwallowPingPong => write!(f, "swallow_ping_pong"),
+            WebSocketAction::Close => write!(f, "close"),
+        }
+    }
+}
+
+impl Default for Toxic {
+    /// A noop toxic with the same defaults as a toxic deserialized without the optional fields
+    fn default() -> Self {
+        Toxic {
+            kind: ToxicKind::Noop,
+            name: default_name(),
+            toxicity: default_toxicity(),
+            direction: default_direction(),
+            enabled: default_enabled(),
+            matcher: None,
+            sampling: ToxicSampling::default(),
+            start_after: None,
+            expires_after: None,
+            schedule: None,
+            position: ToxicPosition::default(),
         }
     }
 }
@@ -140,6 +414,14 @@ impl Toxic {
         &self.name
     }
 
+    /// Returns true if the toxic applies to the connection from this client to this upstream.
+    /// `connection` is the order the proxy accepted the connection in, starting from 1.
+    pub fn matches(&self, client: SocketAddr, connection: u64, upstream: &str) -> bool {
+        self.matcher.as_ref().map_or(true, |matcher| {
+            matcher.matches(client, connection, upstream)
+        })
+    }
+
     /// Sets a name to this Toxic if the name is an empty string.
     /// The default name format is {type}_{direction}
     pub fn set_default_name(&mut self) {
@@ -147,6 +429,143 @@ impl Toxic {
             self.name = format!("{}_{}", self.kind.get_name(), self.direction);
         }
     }
+
+    /// Returns true if the toxic is added to the proxy later, after `start_after` or in its
+    /// schedule windows, instead of right away
+    pub fn is_deferred(&self) -> bool {
+        self.start_after.is_some() || self.schedule.is_some()
+    }
+
+    /// Validate the toxic attributes, return `ToxicValidateError` if invalid
+    pub fn validate(&self) -> Result<(), ToxicValidateError> {
+        if !(0.0..=1.0).contains(&self.toxicity) {
+            return Err(ToxicValidateError::InvalidToxicity(self.toxicity));
+        }
+        if let ToxicKind::Slicer {
+            average_size,
+            size_variation,
+            ..
+        } = self.kind
+        {
+            if average_size == 0 {
+                return Err(ToxicValidateError::ZeroAverageSize);
+            }
+            if size_variation >= average_size {
+                return Err(ToxicValidateError::SizeVariationTooLarge);
+            }
+        }
+        if let ToxicKind::TlsHandshake { stage, .. } = self.kind {
+            let expected = match stage {
+                TlsHandshakeStage::AfterClientHello => StreamDirection::Upstream,
+                TlsHandshakeStage::BeforeServerHello => StreamDirection::Downstream,
+            };
+            if self.direction != expected {
+                return Err(ToxicValidateError::TlsStageDirection(stage, expected));
+            }
+        }
+        if let Some(matcher) = &self.matcher {
+            if matcher.ports.map_or(false, |ports| ports.from > ports.to) {
+                return Err(ToxicValidateError::InvalidPortRange);
+            }
+            if let Some(connections) = matcher.connections {
+                if connections.from == 0 {
+                    return Err(ToxicValidateError::ZeroConnection);
+                }
+                if connections.to.map_or(false, |to| connections.from > to) {
+                    return Err(ToxicValidateError::InvalidConnectionRange);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Errors returned when Toxic validation fails
+#[derive(Debug, Clone, Copy, Error, PartialEq)]
+pub enum ToxicValidateError {
+    /// The toxicity is not a probability
+    #[error("toxicity must be between 0 and 1, got {0}")]
+    InvalidToxicity(f32),
+    /// The slicer toxic would produce empty slices forever
+    #[error("attributes.average_size must be greater than 0")]
+    ZeroAverageSize,
+    /// The slicer toxic size variation is not less than the average size
+    #[error("attributes.size_variation must be less than attributes.average_size")]
+    SizeVariationTooLarge,
+    /// The TLS handshake stage never travels in the toxic direction
+    #[error("stage {0} only happens in the {1} direction")]
+    TlsStageDirection(TlsHandshakeStage, StreamDirection),
+    /// The first port of the range is after the last one
+    #[error("match.ports.from must not be greater than match.ports.to")]
+    InvalidPortRange,
+    /// Connections are counted from 1
+    #[error("match.connections.from must be at least 1")]
+    ZeroConnection,
+    /// The first connection of the range is after the last one
+    #[error("match.connections.from must not be greater than match.connections.to")]
+    InvalidConnectionRange,
+}
+
+impl<'de> Deserialize<'de> for ToxicSchedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct RawSchedule {
+            every: u64,
+            duration: u64,
+            #[serde(default)]
+            offset: u64,
+        }
+
+        let raw = RawSchedule::deserialize(deserializer)?;
+        if raw.duration == 0 || raw.duration >= raw.every {
+            return Err(de::Error::custom(format!(
+                "schedule duration must be more than 0 and less than every ({}), got {}",
+                raw.every, raw.duration
+            )));
+        }
+        Ok(ToxicSchedule {
+            every: raw.every,
+            duration: raw.duration,
+            offset: raw.offset,
+        })
+    }
+}
+
+impl ToxicPosition {
+    /// Returns true if no position is requested
+    pub fn is_unset(&self) -> bool {
+        self.index.is_none() && self.before.is_none() && self.after.is_none()
+    }
+
+    /// The name of the toxic `before` or `after` refers to, unless an index is given
+    pub fn target(&self) -> Option<&str> {
+        if self.index.is_some() {
+            return None;
+        }
+        self.before.as_deref().or(self.after.as_deref())
+    }
+
+    /// Resolve the index to insert at in this chain, the toxic being placed must not be in it.
+    /// Returns None if the toxic referred to by `before` or `after` is not in the chain.
+    fn resolve(&self, toxics: &[Toxic]) -> Option<usize> {
+        let find = |name: &str| toxics.iter().position(|toxic| toxic.get_name() == name);
+        if let Some(index) = self.index {
+            Some(index.min(toxics.len()))
+        } else if let Some(before) = &self.before {
+            find(before)
+        } else if let Some(after) = &self.after {
+            find(after).map(|index| index + 1)
+        } else {
+            Some(toxics.len())
+        }
+    }
+}
+
+impl ToxicSampling {
+    /// Returns true for the default, per connection sampling
+    pub fn is_per_connection(&self) -> bool {
+        *self == ToxicSampling::PerConnection
+    }
 }
 
 impl ToxicEvent {
@@ -164,6 +583,19 @@ impl ToxicKind {
         )
     }
 
+    pub(crate) fn supports_per_chunk_sampling(&self) -> bool {
+        matches!(
+            self,
+            ToxicKind::Noop
+                | ToxicKind::Latency { .. }
+                | ToxicKind::Timeout { .. }
+                | ToxicKind::Bandwidth { .. }
+                | ToxicKind::Slicer { .. }
+                | ToxicKind::Drop
+                | ToxicKind::Duplicate { .. }
+        )
+    }
+
     pub(crate) fn is_stateful(&self) -> bool {
         matches!(self, ToxicKind::LimitData { .. })
     }
@@ -171,12 +603,13 @@ impl ToxicKind {
     pub(crate) fn chunk_buffer_capacity(&self) -> usize {
         match self {
             ToxicKind::Latency { .. } => 1024,
+            ToxicKind::Custom(custom) => custom.toxic().chunk_buffer_capacity(),
             _ => 1,
         }
     }
 
     /// Returns the URL-safe name for the toxic kind
-    pub fn get_name(&self) -> &'static str {
+    pub fn get_name(&self) -> &str {
         match self {
             ToxicKind::Noop => "noop",
             ToxicKind::Latency { .. } => "latency",
@@ -185,35 +618,63 @@ impl ToxicKind {
             ToxicKind::SlowClose { .. } => "slow_close",
             ToxicKind::Slicer { .. } => "slicer",
             ToxicKind::LimitData { .. } => "limit_data",
+            ToxicKind::TlsHandshake { .. } => "tls_handshake",
+            ToxicKind::WebSocket { .. } => "websocket",
+            ToxicKind::Drop => "drop",
+            ToxicKind::Duplicate { .. } => "duplicate",
+            ToxicKind::Reorder { .. } => "reorder",
+            ToxicKind::Custom(custom) => custom.type_name(),
         }
     }
 }
 
+// The event kind is handed back to try the other list, it's not an error to propagate
+#[allow(clippy::result_large_err)]
 pub(super) fn update_toxic_list_in_place(
     toxics: &mut Vec<Toxic>,
     event_kind: ToxicEventKind,
     direction: StreamDirection,
 ) -> Result<(), ToxicEventKind> {
     match event_kind {
-        ToxicEventKind::AddToxic(toxic) => {
-            if toxic.direction == direction {
-                toxics.push(toxic);
+        ToxicEventKind::AddToxic(mut toxic) => {
+            let index = if toxic.direction.includes(direction) {
+                toxic.position.resolve(toxics)
+            } else {
+                None
+            };
+            if let Some(index) = index {
+                toxic.position = ToxicPosition::default();
+                toxics.insert(index, toxic);
             } else {
                 return Err(ToxicEventKind::AddToxic(toxic));
             }
         }
-        ToxicEventKind::UpdateToxic(toxic) => {
-            let old_toxic = if toxic.direction == direction {
+        ToxicEventKind::UpdateToxic(mut toxic) => {
+            let old_index = if toxic.direction.includes(direction) {
                 toxics
-                    .iter_mut()
-                    .find(|el| el.get_name() == toxic.get_name())
+                    .iter()
+                    .position(|el| el.get_name() == toxic.get_name())
             } else {
                 None
             };
-            if let Some(old_toxic) = old_toxic {
-                let _ = mem::replace(old_toxic, toxic);
+            let old_index = match old_index {
+                Some(old_index) => old_index,
+                None => return Err(ToxicEventKind::UpdateToxic(toxic)),
+            };
+            if toxic.position.is_unset() {
+                let _ = mem::replace(&mut toxics[old_index], toxic);
             } else {
-                return Err(ToxicEventKind::UpdateToxic(toxic));
+                let old_toxic = toxics.remove(old_index);
+                match toxic.position.resolve(toxics) {
+                    Some(index) => {
+                        toxic.position = ToxicPosition::default();
+                        toxics.insert(index, toxic);
+                    }
+                    None => {
+                        toxics.insert(old_index, old_toxic);
+                        return Err(ToxicEventKind::UpdateToxic(toxic));
+                    }
+                }
             }
         }
         ToxicEventKind::RemoveToxic(toxic_name) => {
@@ -223,6 +684,12 @@ pub(super) fn update_toxic_list_in_place(
                 .ok_or(ToxicEventKind::RemoveToxic(toxic_name))?;
             toxics.remove(index);
         }
+        ToxicEventKind::SetToxicEnabled(toxic_name, enabled) => {
+            match toxics.iter_mut().find(|el| el.get_name() == toxic_name) {
+                Some(toxic) => toxic.enabled = enabled,
+                None => return Err(ToxicEventKind::SetToxicEnabled(toxic_name, enabled)),
+            }
+        }
         ToxicEventKind::RemoveAllToxics => {
             toxics.clear();
         }
@@ -230,6 +697,45 @@ pub(super) fn update_toxic_list_in_place(
     Ok(())
 }
 
+#[derive(Serialize)]
+struct CustomToxicRepr<'a> {
+    #[serde(rename = "type")]
+    type_name: &'a str,
+    attributes: &'a Value,
+}
+
+fn serialize_kind<S: Serializer>(kind: &ToxicKind, serializer: S) -> Result<S::Ok, S::Error> {
+    match kind {
+        ToxicKind::Custom(custom) => CustomToxicRepr {
+            type_name: custom.type_name(),
+            attributes: custom.attributes(),
+        }
+        .serialize(serializer),
+        _ => kind.serialize(serializer),
+    }
+}
+
+/// Deserialize a built-in toxic kind, or a custom toxic if the type is registered
+fn deserialize_kind<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ToxicKind, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    let type_name = value
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if !BUILTIN_TOXIC_TYPES.contains(&type_name) {
+        let attributes = value
+            .get("attributes")
+            .cloned()
+            .unwrap_or_else(|| Value::Object(Default::default()));
+        if let Some(custom) = CustomToxicKind::build(type_name, attributes) {
+            return custom.map(ToxicKind::Custom).map_err(|err| {
+                de::Error::custom(format!("invalid attributes for {}: {}", type_name, err))
+            });
+        }
+    }
+    ToxicKind::deserialize(value).map_err(de::Error::custom)
+}
+
 impl fmt::Display for Toxic {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}: {}", self.name, self.kind)
@@ -264,6 +770,33 @@ impl fmt::Display for ToxicKind {
             ToxicKind::LimitData { bytes } => {
                 write!(f, "LimitData({})", bytes)
             }
+            ToxicKind::TlsHandshake {
+                stage,
+                action,
+                timeout,
+            } => {
+                write!(f, "TlsHandshake({}, {}, {})", stage, action, timeout)
+            }
+            ToxicKind::WebSocket {
+                action,
+                every,
+                delay,
+                code,
+            } => {
+                write!(f, "WebSocket({}, {}, {}, {})", action, every, delay, code)
+            }
+            ToxicKind::Drop => {
+                write!(f, "Drop")
+            }
+            ToxicKind::Duplicate { copies } => {
+                write!(f, "Duplicate({})", copies)
+            }
+            ToxicKind::Reorder { window, timeout } => {
+                write!(f, "Reorder({}, {})", window, timeout)
+            }
+            ToxicKind::Custom(custom) => {
+                write!(f, "{}({})", custom.type_name(), custom.attributes())
+            }
         }
     }
 }
@@ -280,6 +813,7 @@ mod tests {
             name: "boo".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "boo: Noop";
         assert_eq!(expected, toxic.to_string());
@@ -295,6 +829,7 @@ mod tests {
             name: "t2".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "t2: Latency(49, 5)";
         assert_eq!(expected, toxic.to_string());
@@ -307,6 +842,7 @@ mod tests {
             name: "t3".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "t3: Timeout(2000)";
         assert_eq!(expected, toxic.to_string());
@@ -319,6 +855,7 @@ mod tests {
             name: "t4".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "t4: Bandwidth(2345)";
         assert_eq!(expected, toxic.to_string());
@@ -335,6 +872,7 @@ mod tests {
             name: "t5".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "t5: Slicer(128, 64, 100)";
         assert_eq!(expected, toxic.to_string());
@@ -347,6 +885,7 @@ mod tests {
             name: "t6".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "t6: SlowClose(1200)";
         assert_eq!(expected, toxic.to_string());
@@ -359,11 +898,47 @@ mod tests {
             name: "t7".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let expected = "t7: LimitData(64500)";
         assert_eq!(expected, toxic.to_string());
     }
 
+    #[test]
+    fn test_display_tls_handshake() {
+        let toxic = Toxic {
+            kind: ToxicKind::TlsHandshake {
+                stage: TlsHandshakeStage::AfterClientHello,
+                action: TlsHandshakeAction::Stall,
+                timeout: 500,
+            },
+            name: "t8".to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Upstream,
+            ..Default::default()
+        };
+        let expected = "t8: TlsHandshake(after_client_hello, stall, 500)";
+        assert_eq!(expected, toxic.to_string());
+    }
+
+    #[test]
+    fn test_display_websocket() {
+        let toxic = Toxic {
+            kind: ToxicKind::WebSocket {
+                action: WebSocketAction::Close,
+                every: 3,
+                delay: 0,
+                code: 1011,
+            },
+            name: "t9".to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Downstream,
+            ..Default::default()
+        };
+        let expected = "t9: WebSocket(close, 3, 0, 1011)";
+        assert_eq!(expected, toxic.to_string());
+    }
+
     #[test]
     fn test_noop_serde() {
         let toxic = Toxic {
@@ -371,6 +946,7 @@ mod tests {
             name: "foo".to_owned(),
             toxicity: 0.67,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
         let serialized = to_string(&toxic).unwrap();
         let expected =
@@ -389,6 +965,7 @@ mod tests {
             name: "foo".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
 
         let deserialized = from_str(&input).unwrap();
@@ -403,6 +980,7 @@ mod tests {
             name: "foo".to_owned(),
             toxicity: 0.55,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let deserialized = from_str(&input).unwrap();
@@ -419,6 +997,7 @@ mod tests {
             name: "lat".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let serialized = to_string(&toxic).unwrap();
         let expected =
@@ -439,6 +1018,7 @@ mod tests {
             name: "foo".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
         assert_eq!(expected, deserialized);
     }
@@ -467,6 +1047,7 @@ mod tests {
             name: "noop_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -486,6 +1067,7 @@ mod tests {
             name: "latency_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -502,6 +1084,7 @@ mod tests {
             name: "timeout_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -518,6 +1101,7 @@ mod tests {
             name: "bandwidth_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -534,6 +1118,7 @@ mod tests {
             name: "slow_close_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -554,6 +1139,7 @@ mod tests {
             name: "slicer_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -570,6 +1156,7 @@ mod tests {
             name: "limit_data_downstream".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         let mut deserialized: Toxic = from_str(&input).unwrap();
@@ -577,4 +1164,291 @@ mod tests {
         deserialized.set_default_name();
         assert_eq!(expected, deserialized);
     }
+
+    #[test]
+    fn test_tls_handshake_de_without_name() {
+        let input = "{\"type\":\"tls_handshake\",\"attributes\":{\"stage\":\"before_server_hello\",\"action\":\"reset\"}}";
+        let expected = Toxic {
+            kind: ToxicKind::TlsHandshake {
+                stage: TlsHandshakeStage::BeforeServerHello,
+                action: TlsHandshakeAction::Reset,
+                timeout: 0,
+            },
+            name: "tls_handshake_downstream".to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Downstream,
+            ..Default::default()
+        };
+
+        let mut deserialized: Toxic = from_str(input).unwrap();
+        assert_eq!("", &deserialized.name);
+        deserialized.set_default_name();
+        assert_eq!(expected, deserialized);
+    }
+
+    #[test]
+    fn test_websocket_de_defaults() {
+        let input = "{\"type\":\"websocket\",\"attributes\":{\"action\":\"swallow_ping_pong\"}}";
+        let expected = Toxic {
+            kind: ToxicKind::WebSocket {
+                action: WebSocketAction::SwallowPingPong,
+                every: 1,
+                delay: 0,
+                code: 1000,
+            },
+            name: "websocket_downstream".to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Downstream,
+            ..Default::default()
+        };
+
+        let mut deserialized: Toxic = from_str(input).unwrap();
+        deserialized.set_default_name();
+        assert_eq!(expected, deserialized);
+    }
+
+    #[test]
+    fn test_match_serde() {
+        let input = "{\"type\":\"noop\",\"name\":\"n\",\"toxicity\":1.0,\"direction\":\"upstream\",\"match\":{\"sources\":[\"10.0.0.0/8\"]}}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert!(toxic.matches("10.1.2.3:5000".parse().unwrap(), 1, "127.0.0.1:5432"));
+        assert!(!toxic.matches("127.0.0.1:5000".parse().unwrap(), 1, "127.0.0.1:5432"));
+        assert_eq!(input, to_string(&toxic).unwrap());
+    }
+
+    #[test]
+    fn test_schedule_serde() {
+        let input = "{\"type\":\"noop\",\"name\":\"n\",\"toxicity\":1.0,\"direction\":\"upstream\",\"schedule\":{\"every\":300000,\"duration\":30000,\"offset\":0}}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(
+            Some(ToxicSchedule {
+                every: 300000,
+                duration: 30000,
+                offset: 0,
+            }),
+            toxic.schedule
+        );
+        assert_eq!(input, to_string(&toxic).unwrap());
+
+        let input = "{\"type\":\"noop\",\"schedule\":{\"every\":1000,\"duration\":1000}}";
+        let err = from_str::<Toxic>(input).unwrap_err();
+        assert!(err.to_string().contains("schedule duration"));
+    }
+
+    fn noop_named(name: &str, position: ToxicPosition) -> Toxic {
+        Toxic {
+            kind: ToxicKind::Noop,
+            name: name.to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Upstream,
+            position,
+            ..Default::default()
+        }
+    }
+
+    fn names(toxics: &[Toxic]) -> Vec<&str> {
+        toxics.iter().map(|toxic| toxic.get_name()).collect()
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_position_in_chain() {
+        let mut toxics = Vec::new();
+        let add = |toxics: &mut Vec<Toxic>, name: &str, position: ToxicPosition| {
+            let event = ToxicEventKind::AddToxic(noop_named(name, position));
+            update_toxic_list_in_place(toxics, event, StreamDirection::Upstream)
+        };
+        assert_eq!(Ok(()), add(&mut toxics, "a", ToxicPosition::default()));
+        assert_eq!(Ok(()), add(&mut toxics, "b", ToxicPosition::default()));
+        let before_a = ToxicPosition {
+            before: Some("a".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), add(&mut toxics, "c", before_a));
+        let at_one = ToxicPosition {
+            index: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), add(&mut toxics, "d", at_one));
+        assert_eq!(vec!["c", "d", "a", "b"], names(&toxics));
+        assert!(toxics.iter().all(|toxic| toxic.position.is_unset()));
+
+        let after_b = ToxicPosition {
+            after: Some("b".to_owned()),
+            ..Default::default()
+        };
+        let event = ToxicEventKind::UpdateToxic(noop_named("c", after_b));
+        assert_eq!(
+            Ok(()),
+            update_toxic_list_in_place(&mut toxics, event, StreamDirection::Upstream)
+        );
+        assert_eq!(vec!["d", "a", "b", "c"], names(&toxics));
+
+        let after_missing = ToxicPosition {
+            after: Some("nope".to_owned()),
+            ..Default::default()
+        };
+        let event = ToxicEventKind::UpdateToxic(noop_named("d", after_missing));
+        assert!(update_toxic_list_in_place(&mut toxics, event, StreamDirection::Upstream).is_err());
+        assert_eq!(vec!["d", "a", "b", "c"], names(&toxics));
+    }
+
+    #[test]
+    fn test_position_serde() {
+        let input = "{\"type\":\"noop\",\"before\":\"latency_upstream\"}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(Some("latency_upstream".to_owned()), toxic.position.before);
+        assert_eq!(None, toxic.position.index);
+    }
+
+    #[test]
+    fn test_validate() {
+        let slicer = |average_size, size_variation| -> Toxic {
+            from_str(&format!(
+                "{{\"type\":\"slicer\",\"attributes\":{{\"average_size\":{},\"size_variation\":{}}}}}",
+                average_size, size_variation
+            ))
+            .unwrap()
+        };
+        assert_eq!(Ok(()), slicer(10, 9).validate());
+        assert_eq!(
+            Err(ToxicValidateError::SizeVariationTooLarge),
+            slicer(10, 10).validate()
+        );
+        assert_eq!(
+            Err(ToxicValidateError::ZeroAverageSize),
+            slicer(0, 0).validate()
+        );
+
+        let mut toxic = slicer(10, 0);
+        toxic.toxicity = 1.5;
+        assert_eq!(
+            Err(ToxicValidateError::InvalidToxicity(1.5)),
+            toxic.validate()
+        );
+        toxic.toxicity = f32::NAN;
+        assert!(toxic.validate().is_err());
+
+        let input = "{\"type\":\"noop\",\"match\":{\"ports\":{\"from\":2,\"to\":1}}}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(Err(ToxicValidateError::InvalidPortRange), toxic.validate());
+
+        let input = "{\"type\":\"noop\",\"match\":{\"connections\":{\"from\":5,\"to\":4}}}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(
+            Err(ToxicValidateError::InvalidConnectionRange),
+            toxic.validate()
+        );
+
+        let input = "{\"type\":\"tls_handshake\",\"stream\":\"downstream\",\"attributes\":{\"stage\":\"after_client_hello\",\"action\":\"reset\"}}";
+        let mut toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(
+            Err(ToxicValidateError::TlsStageDirection(
+                TlsHandshakeStage::AfterClientHello,
+                StreamDirection::Upstream
+            )),
+            toxic.validate()
+        );
+        toxic.direction = StreamDirection::Both;
+        assert!(toxic.validate().is_err());
+        toxic.direction = StreamDirection::Upstream;
+        assert_eq!(Ok(()), toxic.validate());
+    }
+
+    #[test]
+    fn test_both_direction_serde() {
+        let input = "{\"type\":\"latency\",\"direction\":\"both\",\"attributes\":{\"latency\":10,\"jitter\":0}}";
+        let mut toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(StreamDirection::Both, toxic.direction);
+        toxic.set_default_name();
+        assert_eq!("latency_both", toxic.name);
+        assert!(toxic.direction.includes(StreamDirection::Upstream));
+        assert!(toxic.direction.includes(StreamDirection::Downstream));
+        assert!(!StreamDirection::Upstream.includes(StreamDirection::Downstream));
+    }
+
+    #[test]
+    fn test_enabled_serde() {
+        let input =
+            "{\"type\":\"noop\",\"name\":\"n\",\"toxicity\":1.0,\"direction\":\"upstream\"}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert!(toxic.enabled);
+        assert_eq!(input, to_string(&toxic).unwrap());
+
+        let input = "{\"type\":\"noop\",\"name\":\"n\",\"toxicity\":1.0,\"direction\":\"upstream\",\"enabled\":false}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert!(!toxic.enabled);
+        assert_eq!(input, to_string(&toxic).unwrap());
+    }
+
+    #[test]
+    fn test_sampling_serde() {
+        let input = "{\"type\":\"latency\",\"attributes\":{\"latency\":10}}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(ToxicSampling::PerConnection, toxic.sampling);
+        assert!(!to_string(&toxic).unwrap().contains("sampling"));
+
+        let input = "{\"type\":\"noop\",\"name\":\"n\",\"toxicity\":0.1,\"direction\":\"upstream\",\"sampling\":\"per_chunk\"}";
+        let toxic: Toxic = from_str(input).unwrap();
+        assert_eq!(ToxicSampling::PerChunk, toxic.sampling);
+        assert_eq!(input, to_string(&toxic).unwrap());
+    }
+
+    #[test]
+    fn test_builtin_types_match_kind_names() {
+        // The names custom toxics cannot take must be exactly the built-in kind names
+        let kinds = vec![
+            ToxicKind::Noop,
+            ToxicKind::Latency {
+                latency: 0,
+                jitter: 0,
+            },
+            ToxicKind::Timeout { timeout: 0 },
+            ToxicKind::Bandwidth { rate: 0 },
+            ToxicKind::SlowClose { delay: 0 },
+            ToxicKind::Slicer {
+                average_size: 1,
+                size_variation: 0,
+                delay: 0,
+            },
+            ToxicKind::LimitData { bytes: 0 },
+            ToxicKind::TlsHandshake {
+                stage: TlsHandshakeStage::AfterClientHello,
+                action: TlsHandshakeAction::Reset,
+                timeout: 0,
+            },
+            ToxicKind::WebSocket {
+                action: WebSocketAction::Drop,
+                every: 1,
+                delay: 0,
+                code: 1000,
+            },
+            ToxicKind::Drop,
+            ToxicKind::Duplicate { copies: 1 },
+            ToxicKind::Reorder {
+                window: 1,
+                timeout: 0,
+            },
+        ];
+        let names: Vec<&str> = kinds
+            .iter()
+            .map(|kind| match kind {
+                // No wildcard, so a new kind fails to compile until it is listed above
+                ToxicKind::Noop
+                | ToxicKind::Latency { .. }
+                | ToxicKind::Timeout { .. }
+                | ToxicKind::Bandwidth { .. }
+                | ToxicKind::SlowClose { .. }
+                | ToxicKind::Slicer { .. }
+                | ToxicKind::LimitData { .. }
+                | ToxicKind::TlsHandshake { .. }
+                | ToxicKind::WebSocket { .. }
+                | ToxicKind::Drop
+                | ToxicKind::Duplicate { .. }
+                | ToxicKind::Reorder { .. } => kind.get_name(),
+                ToxicKind::Custom(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(BUILTIN_TOXIC_TYPES, names.as_slice());
+    }
 }
@@ -23,23 +23,18 @@ pub async fn run_bandwidth(
     pin!(output);
 
     while let Some(chunk) = input.next().await {
-        let chunk_len: u64 = chunk
-            .len()
-            .try_into()
-            .expect("Could not convert chunk size from usize to u64");
+        let chunk_len: u64 = chunk.len().try_into().unwrap_or(u64::MAX);
         let mut to_sleep = Duration::from_nanos(
             (Duration::from_millis(chunk_len).as_nanos() / rate as u128)
                 .try_into()
-                .expect("chunk is too large"),
+                .unwrap_or(u64::MAX),
         );
 
         let mut chunk = chunk;
-        let rate: usize = rate
-            .try_into()
-            .expect("Could not convert bandwidth rate from u64 to usize");
+        let rate: usize = rate.try_into().unwrap_or(usize::MAX);
 
         // If the rate is low enough, split the packet up and send in 100 millisecond intervals
-        while chunk.len() > rate * UNIT {
+        while chunk.len() > rate.saturating_mul(UNIT) {
             sleep(Duration::from_millis(INTERVAL)).await;
             let to_send = chunk.split_to(UNIT);
             if output.send(to_send).await.is_err() {
@@ -48,7 +43,9 @@ pub async fn run_bandwidth(
                     "Write channel closed",
                 ));
             }
-            to_sleep -= Duration::from_millis(INTERVAL);
+            to_sleep = to_sleep
+                .checked_sub(Duration::from_millis(INTERVAL))
+                .unwrap_or_default();
         }
         // sleep's granularity is 1ms
         if to_sleep.as_millis() > 0 {
@@ -65,6 +62,30 @@ pub async fn run_bandwidth(
     Ok(())
 }
 
+/// Run the bandwidth toxic on datagrams, passing at most `rate` datagrams per second
+pub async fn run_datagram_bandwidth(
+    input: impl Stream<Item = Bytes>,
+    output: impl Sink<Bytes>,
+    rate: u64, // in datagrams per second
+) -> io::Result<()> {
+    if rate == 0 {
+        return run_noop(input, output).await;
+    }
+    pin!(input);
+    pin!(output);
+    let interval = Duration::from_nanos(1_000_000_000 / rate);
+    while let Some(chunk) = input.next().await {
+        sleep(interval).await;
+        if output.send(chunk).await.is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Write channel closed",
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,4 +109,12 @@ mod tests {
         })
         .await;
     }
+
+    #[tokio::test]
+    async fn datagram_passthrough_once() {
+        passthrough_test(
+            |stream, sink| async move { run_datagram_bandwidth(stream, sink, 1000).await },
+        )
+        .await;
+    }
 }
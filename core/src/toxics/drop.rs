@@ -0,0 +1,34 @@
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::{Sink, Stream};
+use std::io;
+
+/// Run the drop toxic, discarding every chunk until the input is closed
+pub async fn run_drop(
+    input: impl Stream<Item = Bytes>,
+    _output: impl Sink<Bytes>,
+) -> io::Result<()> {
+    input.for_each(|_| async {}).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toxics::test_utils::*;
+    use futures::SinkExt;
+    use tokio_test::assert_ok;
+
+    #[tokio::test]
+    async fn drops_everything() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_drop(in_stream, out_sink));
+
+        assert_ok!(in_sink.send(gen_random_bytes(32)).await);
+        assert_ok!(in_sink.send(gen_random_bytes(32)).await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+        assert_eq!(None, out_stream.next().await);
+    }
+}
@@ -0,0 +1,60 @@
+use super::send_chunk;
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::{Sink, Stream};
+use std::io;
+use tokio::pin;
+
+/// Run the duplicate toxic, sending every chunk and then `copies` more copies of it
+pub async fn run_duplicate(
+    input: impl Stream<Item = Bytes>,
+    output: impl Sink<Bytes>,
+    copies: u64,
+) -> io::Result<()> {
+    pin!(input);
+    pin!(output);
+    while let Some(chunk) = input.next().await {
+        for _ in 0..copies {
+            send_chunk(&mut output, chunk.clone()).await?;
+        }
+        send_chunk(&mut output, chunk).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toxics::test_utils::*;
+    use futures::SinkExt;
+    use tokio_test::assert_ok;
+
+    #[tokio::test]
+    async fn passthrough_once() {
+        passthrough_test(|stream, sink| async move { run_duplicate(stream, sink, 0).await }).await;
+    }
+
+    #[tokio::test]
+    async fn sends_copies() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_duplicate(in_stream, out_sink, 2));
+
+        let data = gen_random_bytes(32);
+        assert_ok!(in_sink.send(data.clone()).await);
+        for _ in 0..3 {
+            assert_eq!(Some(data.clone()), out_stream.next().await);
+        }
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+        assert_eq!(None, out_stream.next().await);
+    }
+
+    #[tokio::test]
+    async fn drop_out_channel_first() {
+        drop_out_channel_first_test(
+            |stream, sink| async move { run_duplicate(stream, sink, 1).await },
+        )
+        .await;
+    }
+}
@@ -31,7 +31,7 @@ pub async fn run_latency(
             .forward(output)
             .await;
     } else {
-        let range = Uniform::from(0..(jitter * 2));
+        let range = Uniform::from(0..jitter.saturating_mul(2));
         let rand_gen = if let Some(seed) = rand_seed {
             StdRng::seed_from_u64(seed)
         } else {
@@ -41,7 +41,8 @@ pub async fn run_latency(
         let _ = input
             .zip(jitter_stream)
             .then(|(chunk, add)| async move {
-                let delay = latency + add - jitter;
+                // Jitter larger than the latency can't make the delay negative
+                let delay = latency.saturating_add(add).saturating_sub(jitter);
                 tokio::time::sleep(Duration::from_millis(delay)).await;
                 chunk
             })
@@ -127,4 +128,11 @@ mod tests {
         assert_eq!(true, duration.as_millis() > latency as u128);
         resume();
     }
+
+    #[tokio::test]
+    async fn jitter_larger_than_latency() {
+        pause();
+        test_latency(10, 50, 1).await;
+        resume();
+    }
 }
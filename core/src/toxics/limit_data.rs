@@ -15,13 +15,16 @@ pub(crate) async fn run_limit_data(
     bytes: u64,
     state: Option<Arc<AsyncMutex<ToxicState>>>,
 ) -> io::Result<()> {
-    let state = state.expect("No toxic state provided to LimitData toxic.");
+    // Without shared state the count starts from zero for this link
+    let state = state.unwrap_or_else(|| {
+        Arc::new(AsyncMutex::new(ToxicState::LimitData {
+            bytes_transmitted: 0,
+        }))
+    });
     pin!(input);
     pin!(output);
     let mut state = state.lock().await;
-    let bytes: usize = bytes
-        .try_into()
-        .expect("Could not convert bytes limit from u64 to usize");
+    let bytes: usize = bytes.try_into().unwrap_or(usize::MAX);
 
     let mut bytes_transmitted: usize = get_bytes_transmitted(&state);
     let mut result = io::Result::Ok(());
@@ -63,20 +66,14 @@ pub(crate) async fn run_limit_data(
 }
 
 fn get_bytes_transmitted(state: &ToxicState) -> usize {
-    #[allow(irrefutable_let_patterns)]
-    if let ToxicState::LimitData { bytes_transmitted } = state {
-        *bytes_transmitted
-    } else {
-        panic!("Invalid ToxicState given to LimitData toxic: {:?}", state);
+    match state {
+        ToxicState::LimitData { bytes_transmitted } => *bytes_transmitted,
     }
 }
 
 fn write_bytes_transmitted(state: &mut ToxicState, value: usize) {
-    #[allow(irrefutable_let_patterns)]
-    if let ToxicState::LimitData { bytes_transmitted } = state {
-        *bytes_transmitted = value;
-    } else {
-        panic!("Invalid ToxicState given to LimitData toxic: {:?}", state);
+    match state {
+        ToxicState::LimitData { bytes_transmitted } => *bytes_transmitted = value,
     }
 }
 
@@ -87,15 +84,6 @@ mod tests {
     use futures::{SinkExt, StreamExt};
     use tokio_test::assert_ok;
 
-    #[tokio::test]
-    #[should_panic(expected = "No toxic state provided to LimitData toxic.")]
-    async fn panics_without_state() {
-        let (in_stream, _) = create_stream_sink();
-        let (_, out_sink) = create_stream_sink();
-        let (stop, _) = Stop::new();
-        let _ = run_limit_data(in_stream, out_sink, stop, 0, None).await;
-    }
-
     async fn test_limit_data(
         limit: u64,
         to_send: u64,
@@ -148,6 +136,11 @@ mod tests {
         test_limit_data(42, 500, Some(make_state()), false).await;
     }
 
+    #[tokio::test]
+    async fn send_below_limit_without_state() {
+        test_limit_data(10000, 500, None, true).await;
+    }
+
     #[tokio::test]
     async fn send_state_above_limit() {
         let state = Arc::new(AsyncMutex::new(
@@ -1,17 +1,46 @@
 mod bandwidth;
+mod drop;
+mod duplicate;
 mod latency;
 mod limit_data;
 mod noop;
+mod reorder;
 mod slicer;
 mod slow_close;
 #[cfg(test)]
 mod test_utils;
 mod timeout;
+mod tls_handshake;
+mod websocket;
 
 pub(crate) use bandwidth::*;
+pub(crate) use drop::*;
+pub(crate) use duplicate::*;
 pub(crate) use latency::*;
 pub(crate) use limit_data::*;
 pub(crate) use noop::*;
+pub(crate) use reorder::*;
 pub(crate) use slicer::*;
 pub(crate) use slow_close::*;
 pub(crate) use timeout::*;
+pub(crate) use tls_handshake::*;
+pub(crate) use websocket::*;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+use std::io;
+use std::pin::Pin;
+
+/// Send a chunk to the next toxic in the chain, skipping empty chunks
+pub(crate) async fn send_chunk<S: Sink<Bytes>>(
+    output: &mut Pin<&mut S>,
+    chunk: Bytes,
+) -> io::Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    output
+        .send(chunk)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::ConnectionReset, "Write channel closed"))
+}
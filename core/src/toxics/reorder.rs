@@ -0,0 +1,124 @@
+use super::send_chunk;
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::{Sink, Stream};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::convert::TryInto;
+use std::io;
+use std::pin::Pin;
+use tokio::pin;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Run the reorder toxic
+///
+/// Holds the chunks back until `window` chunks are waiting, then sends them in a random
+/// order. If the window doesn't fill up within `timeout` milliseconds of the first chunk,
+/// the chunks held back are sent anyway, shuffled.
+pub async fn run_reorder(
+    input: impl Stream<Item = Bytes>,
+    output: impl Sink<Bytes>,
+    window: u64,
+    timeout: u64, // in millis
+    rand_seed: Option<u64>,
+) -> io::Result<()> {
+    pin!(input);
+    pin!(output);
+    let window: usize = window.try_into().unwrap_or(usize::MAX);
+    let mut rand_gen = if let Some(seed) = rand_seed {
+        StdRng::seed_from_u64(seed)
+    } else {
+        StdRng::from_entropy()
+    };
+    let mut held: Vec<Bytes> = Vec::new();
+    // None if the timeout is too far in the future to represent
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let maybe_chunk = match deadline {
+            Some(deadline) if !held.is_empty() => {
+                tokio::select! {
+                    res = input.next() => res,
+                    _ = sleep_until(deadline) => {
+                        flush(&mut held, &mut output, &mut rand_gen).await?;
+                        continue;
+                    }
+                }
+            }
+            _ => input.next().await,
+        };
+        match maybe_chunk {
+            Some(chunk) => {
+                if held.is_empty() {
+                    deadline = Instant::now().checked_add(Duration::from_millis(timeout));
+                }
+                held.push(chunk);
+                if held.len() >= window {
+                    flush(&mut held, &mut output, &mut rand_gen).await?;
+                }
+            }
+            None => {
+                flush(&mut held, &mut output, &mut rand_gen).await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn flush<S: Sink<Bytes>>(
+    held: &mut Vec<Bytes>,
+    output: &mut Pin<&mut S>,
+    rand_gen: &mut StdRng,
+) -> io::Result<()> {
+    held.shuffle(rand_gen);
+    for chunk in held.drain(..) {
+        send_chunk(output, chunk).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toxics::test_utils::*;
+    use futures::SinkExt;
+    use tokio_test::assert_ok;
+
+    #[tokio::test]
+    async fn passthrough_once() {
+        passthrough_test(
+            |stream, sink| async move { run_reorder(stream, sink, 4, 10, None).await },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn shuffles_the_window() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_reorder(in_stream, out_sink, 8, 1000, Some(3)));
+
+        let sent: Vec<Bytes> = (0..8u8).map(|i| Bytes::from(vec![i])).collect();
+        for chunk in sent.iter() {
+            assert_ok!(in_sink.send(chunk.clone()).await);
+        }
+        let received: Vec<Bytes> = out_stream.take(8).collect().await;
+        assert_ne!(sent, received);
+        let mut sorted = received.clone();
+        sorted.sort();
+        assert_eq!(sent, sorted);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sends_held_chunks_after_timeout() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_reorder(in_stream, out_sink, 8, 5, None));
+
+        let data = gen_random_bytes(32);
+        assert_ok!(in_sink.send(data.clone()).await);
+        assert_eq!(Some(data), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+}
@@ -66,9 +66,7 @@ impl SliceIter {
                 StdRng::from_entropy()
             };
             SliceIterKind::VariableSized {
-                size_variation: size_variation
-                    .try_into()
-                    .expect("Could not convert size_variation from u64 to usize"),
+                size_variation: size_variation.try_into().unwrap_or(usize::MAX),
                 rand_gen: Box::new(rand_gen),
             }
         } else {
@@ -76,9 +74,7 @@ impl SliceIter {
         };
         SliceIter {
             data: Some(data),
-            average_size: average_size
-                .try_into()
-                .expect("Could not convert average_size from u64 to usize"),
+            average_size: average_size.try_into().unwrap_or(usize::MAX),
             kind,
         }
     }
@@ -106,15 +102,18 @@ impl Iterator for SliceIter {
     fn next(&mut self) -> Option<Self::Item> {
         if self.data.is_some() {
             match &mut self.kind {
-                SliceIterKind::ConstantSized => self.slice_data(self.average_size),
+                // Empty slices would never consume the data
+                SliceIterKind::ConstantSized => self.slice_data(self.average_size.max(1)),
                 SliceIterKind::VariableSized {
                     size_variation,
                     rand_gen,
                 } => {
                     let variation = *size_variation;
-                    let size =
-                        self.average_size + 2 * rand_gen.gen_range(1..=variation) - variation;
-                    self.slice_data(size)
+                    let size = self
+                        .average_size
+                        .saturating_add(rand_gen.gen_range(1..=variation).saturating_mul(2))
+                        .saturating_sub(variation);
+                    self.slice_data(size.max(1))
                 }
             }
         } else {
@@ -159,4 +158,16 @@ mod tests {
         )
         .await;
     }
+
+    #[test]
+    fn slices_without_panicking_on_invalid_sizes() {
+        let data = Bytes::from_static(b"hello world");
+        for (average_size, size_variation) in [(0, 0), (0, 5), (2, 10), (u64::MAX, u64::MAX)].iter()
+        {
+            let slices: Vec<Bytes> =
+                SliceIter::new(data.clone(), *average_size, *size_variation, Some(7)).collect();
+            assert!(slices.iter().all(|slice| !slice.is_empty()));
+            assert_eq!(data, slices.concat());
+        }
+    }
 }
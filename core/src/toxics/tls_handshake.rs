@@ -0,0 +1,271 @@
+use super::{run_noop, run_timeout, send_chunk};
+use crate::toxic::{TlsHandshakeAction, TlsHandshakeStage};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use futures::{Sink, Stream};
+use std::io;
+use tokio::pin;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 23;
+const CONTENT_TYPE_HEARTBEAT: u8 = 24;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    /// A complete TLS record at the start of the buffer
+    Complete {
+        len: usize,
+        handshake_type: Option<u8>,
+    },
+    /// The handshake is over, or this is not TLS at all
+    Passthrough,
+}
+
+/// Run the TLS handshake toxic
+///
+/// This toxic does not terminate TLS. It reads the TLS record headers to find the
+/// ClientHello or ServerHello, and passes everything through untouched once the
+/// handshake is over or if the data does not look like TLS.
+pub async fn run_tls_handshake(
+    input: impl Stream<Item = Bytes>,
+    output: impl Sink<Bytes>,
+    stage: TlsHandshakeStage,
+    action: TlsHandshakeAction,
+    timeout: u64, // in millis
+) -> io::Result<()> {
+    pin!(input);
+    pin!(output);
+    let target = match stage {
+        TlsHandshakeStage::AfterClientHello => HANDSHAKE_CLIENT_HELLO,
+        TlsHandshakeStage::BeforeServerHello => HANDSHAKE_SERVER_HELLO,
+    };
+    let mut buffer = BytesMut::new();
+
+    while let Some(chunk) = input.next().await {
+        buffer.extend_from_slice(&chunk);
+        while let Some(scan) = next_record(&buffer) {
+            let (len, handshake_type) = match scan {
+                Record::Complete {
+                    len,
+                    handshake_type,
+                } => (len, handshake_type),
+                Record::Passthrough => {
+                    send_chunk(&mut output, buffer.split().freeze()).await?;
+                    return run_noop(input, output).await;
+                }
+            };
+            let mut record = buffer.split_to(len);
+            if handshake_type != Some(target) {
+                send_chunk(&mut output, record.freeze()).await?;
+                continue;
+            }
+            match action {
+                TlsHandshakeAction::Stall => {
+                    if stage == TlsHandshakeStage::AfterClientHello {
+                        send_chunk(&mut output, record.freeze()).await?;
+                    }
+                    return run_timeout(input, output, timeout).await;
+                }
+                TlsHandshakeAction::Reset => {
+                    if stage == TlsHandshakeStage::AfterClientHello {
+                        send_chunk(&mut output, record.freeze()).await?;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        format!("reset at TLS handshake stage {}", stage),
+                    ));
+                }
+                TlsHandshakeAction::Corrupt => {
+                    for byte in record[RECORD_HEADER_LEN..].iter_mut() {
+                        *byte ^= 0xff;
+                    }
+                    send_chunk(&mut output, record.freeze()).await?;
+                    send_chunk(&mut output, buffer.split().freeze()).await?;
+                    return run_noop(input, output).await;
+                }
+            }
+        }
+    }
+    if !buffer.is_empty() {
+        send_chunk(&mut output, buffer.freeze()).await?;
+    }
+    Ok(())
+}
+
+/// Look at the record at the start of the buffer. Returns None if the record is not complete yet.
+fn next_record(buffer: &[u8]) -> Option<Record> {
+    let content_type = *buffer.first()?;
+    if !(CONTENT_TYPE_CHANGE_CIPHER_SPEC..=CONTENT_TYPE_HEARTBEAT).contains(&content_type)
+        || content_type == CONTENT_TYPE_APPLICATION_DATA
+    {
+        return Some(Record::Passthrough);
+    }
+    if buffer.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let len = RECORD_HEADER_LEN + u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+    if buffer.len() < len {
+        return None;
+    }
+    let handshake_type = if content_type == CONTENT_TYPE_HANDSHAKE {
+        buffer[RECORD_HEADER_LEN..len].first().copied()
+    } else {
+        None
+    };
+    Some(Record::Complete {
+        len,
+        handshake_type,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toxics::test_utils::*;
+    use futures::SinkExt;
+    use tokio::time::{pause, resume};
+    use tokio_test::{assert_err, assert_ok};
+
+    fn record(content_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut record = vec![content_type, 3, 1];
+        record.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        record.extend_from_slice(body);
+        record
+    }
+
+    fn client_hello() -> Vec<u8> {
+        record(
+            CONTENT_TYPE_HANDSHAKE,
+            &[HANDSHAKE_CLIENT_HELLO, 0, 0, 2, 3, 3],
+        )
+    }
+
+    fn server_hello() -> Vec<u8> {
+        record(
+            CONTENT_TYPE_HANDSHAKE,
+            &[HANDSHAKE_SERVER_HELLO, 0, 0, 2, 3, 3],
+        )
+    }
+
+    #[test]
+    fn finds_records() {
+        assert_eq!(None, next_record(&[]));
+        assert_eq!(None, next_record(&client_hello()[..7]));
+        assert_eq!(
+            Some(Record::Complete {
+                len: 11,
+                handshake_type: Some(HANDSHAKE_CLIENT_HELLO)
+            }),
+            next_record(&client_hello())
+        );
+        assert_eq!(
+            Some(Record::Complete {
+                len: 6,
+                handshake_type: None
+            }),
+            next_record(&record(CONTENT_TYPE_CHANGE_CIPHER_SPEC, &[1]))
+        );
+        assert_eq!(
+            Some(Record::Passthrough),
+            next_record(&record(CONTENT_TYPE_APPLICATION_DATA, &[1, 2]))
+        );
+        assert_eq!(
+            Some(Record::Passthrough),
+            next_record(b"GET / HTTP/1.1\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_through_plaintext() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_tls_handshake(
+            in_stream,
+            out_sink,
+            TlsHandshakeStage::AfterClientHello,
+            TlsHandshakeAction::Reset,
+            0,
+        ));
+
+        let data = Bytes::from_static(b"GET / HTTP/1.1\r\n\r\n");
+        assert_ok!(in_sink.send(data.clone()).await);
+        assert_eq!(Some(data), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn resets_after_client_hello() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_tls_handshake(
+            in_stream,
+            out_sink,
+            TlsHandshakeStage::AfterClientHello,
+            TlsHandshakeAction::Reset,
+            0,
+        ));
+
+        let hello = client_hello();
+        // Split the record across two chunks
+        assert_ok!(in_sink.send(Bytes::copy_from_slice(&hello[..3])).await);
+        assert_ok!(in_sink.send(Bytes::copy_from_slice(&hello[3..])).await);
+        assert_eq!(Some(Bytes::from(hello)), out_stream.next().await);
+        let res = handle.await.unwrap();
+        assert_err!(&res);
+        assert_eq!(io::ErrorKind::ConnectionReset, res.unwrap_err().kind());
+        assert_eq!(None, out_stream.next().await);
+    }
+
+    #[tokio::test]
+    async fn stalls_before_server_hello() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        pause();
+        let handle = tokio::spawn(run_tls_handshake(
+            in_stream,
+            out_sink,
+            TlsHandshakeStage::BeforeServerHello,
+            TlsHandshakeAction::Stall,
+            1000,
+        ));
+
+        assert_ok!(in_sink.send(server_hello().into()).await);
+        let res = handle.await.unwrap();
+        assert_err!(&res);
+        assert_eq!(io::ErrorKind::TimedOut, res.unwrap_err().kind());
+        assert_eq!(None, out_stream.next().await);
+        resume();
+    }
+
+    #[tokio::test]
+    async fn corrupts_server_hello() {
+        let (in_stream, mut in_sink) = create_stream_sink();
+        let (mut out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_tls_handshake(
+            in_stream,
+            out_sink,
+            TlsHandshakeStage::BeforeServerHello,
+            TlsHandshakeAction::Corrupt,
+            0,
+        ));
+
+        let hello = server_hello();
+        let app_data = record(CONTENT_TYPE_APPLICATION_DATA, &[9, 9]);
+        let mut expected = hello.clone();
+        for byte in expected[RECORD_HEADER_LEN..].iter_mut() {
+            *byte ^= 0xff;
+        }
+
+        assert_ok!(in_sink.send(hello.into()).await);
+        assert_eq!(Some(Bytes::from(expected)), out_stream.next().await);
+        assert_ok!(in_sink.send(app_data.clone().into()).await);
+        assert_eq!(Some(Bytes::from(app_data)), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+}
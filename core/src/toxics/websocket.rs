@@ -0,0 +1,370 @@
+use super::{run_noop, send_chunk};
+use crate::toxic::WebSocketAction;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::StreamExt;
+use futures::{Sink, Stream};
+use std::io;
+use tokio::pin;
+use tokio::time::{sleep, Duration};
+
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+const MASK_KEY: [u8; 4] = [0x6e, 0x6f, 0x78, 0x73];
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    /// A complete frame at the start of the buffer
+    Complete { len: usize },
+    /// The frame is too large to buffer
+    TooLarge,
+}
+
+/// Run the WebSocket toxic
+///
+/// The toxic waits for the HTTP head of the upgrade request or response, then parses the
+/// frames that follow. If the head is not a WebSocket upgrade, everything is passed through.
+/// A fragmented message counts once, and its continuation frames follow its first frame.
+pub async fn run_websocket(
+    input: impl Stream<Item = Bytes>,
+    output: impl Sink<Bytes>,
+    action: WebSocketAction,
+    every: u64,
+    delay: u64, // in millis
+    code: u16,
+) -> io::Result<()> {
+    pin!(input);
+    pin!(output);
+    let mut buffer = BytesMut::new();
+
+    // Frames sent by the client are masked, so the upgrade request tells us the side we're on
+    let masked = loop {
+        let chunk = match input.next().await {
+            Some(chunk) => chunk,
+            None => {
+                send_chunk(&mut output, buffer.freeze()).await?;
+                return Ok(());
+            }
+        };
+        buffer.extend_from_slice(&chunk);
+        if let Some(end) = find_head_end(&buffer) {
+            let head = buffer.split_to(end);
+            let is_upgrade = is_websocket_upgrade(&head);
+            let masked = head.starts_with(b"GET ");
+            send_chunk(&mut output, head.freeze()).await?;
+            if !is_upgrade {
+                send_chunk(&mut output, buffer.split().freeze()).await?;
+                return run_noop(input, output).await;
+            }
+            break masked;
+        } else if buffer.len() > MAX_HEAD_LEN {
+            send_chunk(&mut output, buffer.split().freeze()).await?;
+            return run_noop(input, output).await;
+        }
+    };
+
+    let every = every.max(1);
+    let mut messages: u64 = 0;
+    // The fragments of a message share the fate of its first frame
+    let mut dropping_message = false;
+    loop {
+        while let Some(scan) = next_frame(&buffer) {
+            let len = match scan {
+                Frame::Complete { len } => len,
+                Frame::TooLarge => {
+                    send_chunk(&mut output, buffer.split().freeze()).await?;
+                    return run_noop(input, output).await;
+                }
+            };
+            let frame = buffer.split_to(len).freeze();
+            match frame[0] & 0x0f {
+                OP_PING | OP_PONG if action == WebSocketAction::SwallowPingPong => continue,
+                OP_CONTINUATION if dropping_message => continue,
+                OP_TEXT | OP_BINARY => {
+                    messages += 1;
+                    dropping_message = false;
+                    if messages % every == 0 {
+                        match action {
+                            WebSocketAction::Drop => {
+                                dropping_message = true;
+                                continue;
+                            }
+                            WebSocketAction::Delay => sleep(Duration::from_millis(delay)).await,
+                            WebSocketAction::Close => {
+                                send_chunk(&mut output, close_frame(code, masked)).await?;
+                                return Ok(());
+                            }
+                            WebSocketAction::SwallowPingPong => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+            send_chunk(&mut output, frame).await?;
+        }
+        match input.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    send_chunk(&mut output, buffer.freeze()).await
+}
+
+/// Returns the length of the HTTP head including the blank line, if it is complete
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+fn is_websocket_upgrade(head: &[u8]) -> bool {
+    String::from_utf8_lossy(head).lines().skip(1).any(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim();
+        let value = parts.next().unwrap_or_default();
+        name.eq_ignore_ascii_case("upgrade") && value.to_ascii_lowercase().contains("websocket")
+    })
+}
+
+/// Look at the frame at the start of the buffer. Returns None if the frame is not complete yet.
+fn next_frame(buffer: &[u8]) -> Option<Frame> {
+    if buffer.len() < 2 {
+        return None;
+    }
+    let (ext_len, payload_len) = match buffer[1] & 0x7f {
+        126 => (
+            2,
+            u16::from_be_bytes([*buffer.get(2)?, *buffer.get(3)?]) as u64,
+        ),
+        127 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(buffer.get(2..10)?);
+            (8, u64::from_be_bytes(len))
+        }
+        len => (0, len as u64),
+    };
+    if payload_len > MAX_FRAME_LEN as u64 {
+        return Some(Frame::TooLarge);
+    }
+    let mask_len = if buffer[1] & MASKED != 0 { 4 } else { 0 };
+    let len = 2 + ext_len + mask_len + payload_len as usize;
+    if buffer.len() < len {
+        return None;
+    }
+    Some(Frame::Complete { len })
+}
+
+fn close_frame(code: u16, masked: bool) -> Bytes {
+    let payload = code.to_be_bytes();
+    let mut frame = BytesMut::with_capacity(2 + MASK_KEY.len() + payload.len());
+    frame.put_u8(FIN | OP_CLOSE);
+    if masked {
+        frame.put_u8(MASKED | payload.len() as u8);
+        frame.put_slice(&MASK_KEY);
+        for (i, byte) in payload.iter().enumerate() {
+            frame.put_u8(byte ^ MASK_KEY[i % MASK_KEY.len()]);
+        }
+    } else {
+        frame.put_u8(payload.len() as u8);
+        frame.put_slice(&payload);
+    }
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toxics::test_utils::*;
+    use futures::SinkExt;
+    use tokio::time::{pause, resume};
+    use tokio_test::assert_ok;
+
+    const RESPONSE_HEAD: &[u8] =
+        b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+    const REQUEST_HEAD: &[u8] =
+        b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: WebSocket\r\nConnection: Upgrade\r\n\r\n";
+
+    fn frame(opcode: u8, payload: &[u8]) -> Bytes {
+        let mut frame = vec![FIN | opcode, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        frame.into()
+    }
+
+    fn spawn_toxic(
+        action: WebSocketAction,
+        every: u64,
+        delay: u64,
+    ) -> (
+        futures::channel::mpsc::Sender<Bytes>,
+        futures::channel::mpsc::Receiver<Bytes>,
+        tokio::task::JoinHandle<io::Result<()>>,
+    ) {
+        let (in_stream, in_sink) = create_stream_sink();
+        let (out_stream, out_sink) = create_stream_sink();
+        let handle = tokio::spawn(run_websocket(
+            in_stream, out_sink, action, every, delay, 1001,
+        ));
+        (in_sink, out_stream, handle)
+    }
+
+    #[test]
+    fn finds_frames() {
+        assert_eq!(None, next_frame(&[FIN | OP_TEXT]));
+        assert_eq!(None, next_frame(&frame(OP_TEXT, b"hello")[..4]));
+        assert_eq!(
+            Some(Frame::Complete { len: 7 }),
+            next_frame(&frame(OP_TEXT, b"hello"))
+        );
+        assert_eq!(
+            Some(Frame::Complete { len: 11 }),
+            next_frame(&[FIN | OP_TEXT, MASKED | 5, 1, 2, 3, 4, 5, 6, 7, 8, 9])
+        );
+        let mut long = vec![FIN | OP_BINARY, 126, 1, 0];
+        long.extend_from_slice(&[0; 256]);
+        assert_eq!(Some(Frame::Complete { len: 260 }), next_frame(&long));
+        assert_eq!(
+            Some(Frame::TooLarge),
+            next_frame(&[FIN | OP_BINARY, 127, 0, 0, 0, 1, 0, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn detects_upgrade() {
+        assert!(is_websocket_upgrade(RESPONSE_HEAD));
+        assert!(is_websocket_upgrade(REQUEST_HEAD));
+        assert!(!is_websocket_upgrade(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn passes_through_plain_http() {
+        let (mut in_sink, mut out_stream, handle) = spawn_toxic(WebSocketAction::Drop, 1, 0);
+        let head = Bytes::from_static(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n");
+        let body = frame(OP_TEXT, b"a");
+
+        assert_ok!(in_sink.send(head.clone()).await);
+        assert_eq!(Some(head), out_stream.next().await);
+        assert_ok!(in_sink.send(body.clone()).await);
+        assert_eq!(Some(body), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn drops_every_other_data_frame() {
+        let (mut in_sink, mut out_stream, handle) = spawn_toxic(WebSocketAction::Drop, 2, 0);
+        let mut first = BytesMut::from(RESPONSE_HEAD);
+        first.extend_from_slice(&frame(OP_TEXT, b"one"));
+
+        assert_ok!(in_sink.send(first.freeze()).await);
+        assert_eq!(
+            Some(Bytes::from_static(RESPONSE_HEAD)),
+            out_stream.next().await
+        );
+        assert_eq!(Some(frame(OP_TEXT, b"one")), out_stream.next().await);
+        assert_ok!(in_sink.send(frame(OP_TEXT, b"two")).await);
+        assert_ok!(in_sink.send(frame(OP_PING, b"")).await);
+        assert_eq!(Some(frame(OP_PING, b"")), out_stream.next().await);
+        assert_ok!(in_sink.send(frame(OP_BINARY, b"three")).await);
+        assert_eq!(Some(frame(OP_BINARY, b"three")), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn treats_fragmented_message_as_one() {
+        let (mut in_sink, mut out_stream, handle) = spawn_toxic(WebSocketAction::Drop, 2, 0);
+        let first = Bytes::from_static(&[OP_TEXT, 1, b'o']);
+        let last = frame(OP_CONTINUATION, b"ne");
+        let fragmented = || {
+            let mut message = BytesMut::from(&first[..]);
+            message.extend_from_slice(&frame(OP_PING, b""));
+            message.extend_from_slice(&last);
+            message
+        };
+
+        assert_ok!(in_sink.send(Bytes::from_static(RESPONSE_HEAD)).await);
+        assert_eq!(
+            Some(Bytes::from_static(RESPONSE_HEAD)),
+            out_stream.next().await
+        );
+        assert_ok!(in_sink.send(fragmented().freeze()).await);
+        assert_eq!(Some(first.clone()), out_stream.next().await);
+        assert_eq!(Some(frame(OP_PING, b"")), out_stream.next().await);
+        assert_eq!(Some(last.clone()), out_stream.next().await);
+        // The second message is dropped whole, but not the control frame between its fragments
+        let mut data = fragmented();
+        data.extend_from_slice(&frame(OP_TEXT, b"three"));
+        assert_ok!(in_sink.send(data.freeze()).await);
+        assert_eq!(Some(frame(OP_PING, b"")), out_stream.next().await);
+        assert_eq!(Some(frame(OP_TEXT, b"three")), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn delays_data_frames() {
+        let (mut in_sink, mut out_stream, handle) = spawn_toxic(WebSocketAction::Delay, 1, 500);
+        pause();
+        assert_ok!(in_sink.send(Bytes::from_static(RESPONSE_HEAD)).await);
+        assert_eq!(
+            Some(Bytes::from_static(RESPONSE_HEAD)),
+            out_stream.next().await
+        );
+        let start = tokio::time::Instant::now();
+        assert_ok!(in_sink.send(frame(OP_TEXT, b"late")).await);
+        assert_eq!(Some(frame(OP_TEXT, b"late")), out_stream.next().await);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+        resume();
+    }
+
+    #[tokio::test]
+    async fn swallows_ping_pong() {
+        let (mut in_sink, mut out_stream, handle) =
+            spawn_toxic(WebSocketAction::SwallowPingPong, 1, 0);
+        let mut data = BytesMut::from(RESPONSE_HEAD);
+        data.extend_from_slice(&frame(OP_PING, b"p"));
+        data.extend_from_slice(&frame(OP_PONG, b"p"));
+        data.extend_from_slice(&frame(OP_TEXT, b"data"));
+
+        assert_ok!(in_sink.send(data.freeze()).await);
+        assert_eq!(
+            Some(Bytes::from_static(RESPONSE_HEAD)),
+            out_stream.next().await
+        );
+        assert_eq!(Some(frame(OP_TEXT, b"data")), out_stream.next().await);
+        drop(in_sink);
+        assert_ok!(handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sends_masked_close_from_client() {
+        let (mut in_sink, mut out_stream, handle) = spawn_toxic(WebSocketAction::Close, 1, 0);
+        let masked_text = Bytes::from_static(&[FIN | OP_TEXT, MASKED | 1, 0, 0, 0, 0, b'x']);
+
+        assert_ok!(in_sink.send(Bytes::from_static(REQUEST_HEAD)).await);
+        assert_eq!(
+            Some(Bytes::from_static(REQUEST_HEAD)),
+            out_stream.next().await
+        );
+        assert_ok!(in_sink.send(masked_text).await);
+        let close = out_stream.next().await.unwrap();
+        assert_eq!(FIN | OP_CLOSE, close[0]);
+        assert_eq!(MASKED | 2, close[1]);
+        let code = [close[6] ^ close[2], close[7] ^ close[3]];
+        assert_eq!(1001, u16::from_be_bytes(code));
+        assert_ok!(handle.await.unwrap());
+        assert_eq!(None, out_stream.next().await);
+    }
+}
@@ -0,0 +1,457 @@
+use crate::{
+    dns::resolve,
+    link::ToxicRunner,
+    proxy::{decide_bidirectional_toxics, update_toxics, ProxyConfig, Toxics},
+    signal::{Closer, Stop},
+    state::{ProxyState, SharedProxyInfo, ToxicStateHolder},
+    toxic::{StreamDirection, Toxic, ToxicEvent, ToxicEventResult},
+    upstream::{connect_with_failover, UpstreamBalancer},
+};
+use bmrng::{Payload, RequestReceiver};
+use bytes::Bytes;
+use futures::channel::mpsc as futures_mpsc;
+use futures::StreamExt;
+use rand::{distributions::Standard, rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, error, info, instrument};
+
+/// The largest possible UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// How many datagrams can wait for a session or a toxic chain before new ones are dropped
+const SESSION_BUFFER_SIZE: usize = 64;
+
+/// End a UDP session after this many milliseconds without datagrams, unless the proxy config
+/// has an idle timeout
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 60_000;
+
+/// Client address -> the running session
+type Sessions = Arc<Mutex<HashMap<SocketAddr, SessionHandle>>>;
+
+/// What the proxy keeps about a session, running or still connecting
+#[derive(Debug)]
+struct SessionHandle {
+    /// The order the proxy saw this client in, starting from 1
+    connection: u64,
+    /// The upstream address the session goes to, the picked one until it connects
+    upstream: String,
+    /// Sends the datagrams from the client to the session
+    sender: mpsc::Sender<Bytes>,
+}
+
+/// The UDP proxy runner
+///
+/// UDP has no connections, so the proxy tracks a session for every client address instead.
+/// A session has its own socket to the upstream and its own toxic chains, and ends after the
+/// idle timeout. Every datagram is a chunk for the toxics.
+#[derive(Debug, Copy, Clone)]
+pub struct UdpProxyRunner;
+
+/// One client address talking to the upstream through the proxy
+#[derive(Debug)]
+struct Session {
+    config: Arc<ProxyConfig>,
+    client_addr: SocketAddr,
+    /// The order the proxy saw this client in, starting from 1
+    connection: u64,
+    /// The upstream address the session goes to
+    upstream_addr: String,
+    /// The proxy socket, to send datagrams back to the client
+    listen: Arc<UdpSocket>,
+    /// Connected to the upstream address
+    upstream: Arc<UdpSocket>,
+}
+
+impl UdpProxyRunner {
+    /// Initialize a UDP proxy, bind to the listen address but don't start forwarding datagrams
+    #[instrument(level = "debug")]
+    pub async fn initialize_proxy(
+        config: ProxyConfig,
+        initial_toxics: Toxics,
+    ) -> io::Result<(UdpSocket, SharedProxyInfo)> {
+        let socket = UdpSocket::bind(&config.listen).await?;
+        let listen_addr = socket.local_addr()?.to_string();
+
+        info!(name = ?config.name, proxy = ?listen_addr, upstream = ?config.upstream, "Initialized UDP proxy");
+
+        let state = Arc::new(ProxyState::new(initial_toxics));
+
+        let proxy_info = SharedProxyInfo {
+            state,
+            config: Arc::new(config),
+            listen_addr: Some(listen_addr),
+        };
+
+        Ok((socket, proxy_info))
+    }
+
+    /// Run the initialized UDP proxy, start a session for every new client address
+    #[instrument(level = "debug", skip(socket, receiver, stop, closer))]
+    pub async fn run_proxy(
+        socket: UdpSocket,
+        proxy_info: SharedProxyInfo,
+        receiver: RequestReceiver<ToxicEvent, ToxicEventResult>,
+        mut stop: Stop,
+        closer: Closer,
+    ) -> io::Result<()> {
+        let state = proxy_info.state;
+        let config = proxy_info.config;
+        let socket = Arc::new(socket);
+
+        let (toxics_sender, toxics_receiver) = watch::channel(state.lock().toxics.clone());
+        tokio::spawn(listen_toxic_events(
+            state.clone(),
+            receiver,
+            stop.clone(),
+            toxics_sender,
+        ));
+
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        let mut balancer =
+            UpstreamBalancer::new(config.balance, config.upstream_list(), config.rand_seed);
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let result = loop {
+            let (len, client_addr) = tokio::select! {
+                res = socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(err) => break Err(err),
+                },
+                _ = stop.recv() => break Ok(()),
+            };
+            let datagram = Bytes::copy_from_slice(&buf[..len]);
+
+            let session_sender = sessions
+                .lock()
+                .expect("UDP sessions poisoned")
+                .get(&client_addr)
+                .map(|session| session.sender.clone());
+            let datagram = match session_sender {
+                Some(sender) => match sender.try_send(datagram) {
+                    // Like the network, drop the datagram if the session can't keep up
+                    Ok(()) | Err(TrySendError::Full(_)) => continue,
+                    // The session has just ended, start a new one
+                    Err(TrySendError::Closed(datagram)) => datagram,
+                },
+                None => datagram,
+            };
+
+            let connection = state.next_connection();
+            debug!(proxy = ?&config.name, addr = ?&client_addr, connection, "New UDP session for {}", client_addr);
+            let picked = balancer
+                .pick(client_addr.ip(), |upstream| {
+                    sessions
+                        .lock()
+                        .expect("UDP sessions poisoned")
+                        .values()
+                        .filter(|session| session.upstream == upstream)
+                        .count()
+                })
+                .to_owned();
+            let (session_sender, session_receiver) = mpsc::channel(SESSION_BUFFER_SIZE);
+            let _ = session_sender.try_send(datagram);
+            sessions.lock().expect("UDP sessions poisoned").insert(
+                client_addr,
+                SessionHandle {
+                    connection,
+                    upstream: picked.clone(),
+                    sender: session_sender,
+                },
+            );
+            // Connect in the background, so a slow or blackholed upstream doesn't hold up the
+            // other clients. The datagrams of this client wait in the session channel meanwhile
+            tokio::spawn(connect_session(
+                client_addr,
+                connection,
+                picked,
+                config.clone(),
+                socket.clone(),
+                session_receiver,
+                toxics_receiver.clone(),
+                stop.clone(),
+                sessions.clone(),
+            ));
+        };
+        let _ = closer.close();
+        debug!(proxy = ?&config.name, listen = ?&config.listen, "Shutting down UDP proxy");
+        result
+    }
+}
+
+impl Session {
+    async fn run(
+        self,
+        mut client_receiver: mpsc::Receiver<Bytes>,
+        mut toxics_receiver: watch::Receiver<Toxics>,
+        mut stop: Stop,
+        sessions: Sessions,
+    ) {
+        // Keeps the deadline representable
+        let idle_timeout = Duration::from_millis(
+            self.config
+                .idle_timeout
+                .unwrap_or(DEFAULT_IDLE_TIMEOUT)
+                .min(u64::from(u32::MAX)),
+        );
+        let mut state_holder: Option<Arc<ToxicStateHolder>> = None;
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut last_active = Instant::now();
+
+        loop {
+            let toxics = decide_bidirectional_toxics(
+                toxics_receiver.borrow().clone(),
+                self.config.rand_seed,
+            );
+            state_holder = match state_holder {
+                Some(holder) => {
+                    holder.init_missing_states(&toxics);
+                    Some(holder)
+                }
+                None => ToxicStateHolder::for_toxics(&toxics),
+            };
+            let (chain_stop, chain_stopper) = stop.fork();
+            let mut upstream_sender = self.start_chain(
+                toxics.upstream,
+                StreamDirection::Upstream,
+                &chain_stop,
+                state_holder.clone(),
+            );
+            let mut downstream_sender = self.start_chain(
+                toxics.downstream,
+                StreamDirection::Downstream,
+                &chain_stop,
+                state_holder.clone(),
+            );
+
+            // Returns true to restart the chains with new toxics, false to end the session
+            let toxics_changed = loop {
+                tokio::select! {
+                    // Apply new toxics before forwarding more datagrams
+                    biased;
+                    changed = toxics_receiver.changed() => break changed.is_ok(),
+                    _ = stop.recv() => break false,
+                    maybe_datagram = client_receiver.recv() => match maybe_datagram {
+                        Some(datagram) => {
+                            last_active = Instant::now();
+                            let _ = upstream_sender.try_send(datagram);
+                        }
+                        None => break false,
+                    },
+                    res = self.upstream.recv(&mut buf) => match res {
+                        Ok(len) => {
+                            last_active = Instant::now();
+                            let _ = downstream_sender.try_send(Bytes::copy_from_slice(&buf[..len]));
+                        }
+                        // The upstream may not be listening yet
+                        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {}
+                        Err(err) => {
+                            debug!(err = ?err, client = ?&self.client_addr, "UDP upstream receive failed");
+                            break false;
+                        }
+                    },
+                    _ = sleep_until(last_active + idle_timeout) => {
+                        debug!(client = ?&self.client_addr, "UDP session idle, ending it");
+                        break false;
+                    },
+                }
+            };
+            chain_stopper.stop();
+            if !toxics_changed {
+                break;
+            }
+        }
+
+        remove_session(&sessions, self.client_addr, self.connection);
+    }
+
+    /// Start the toxic runners for one direction, returns the sender for the first one
+    fn start_chain(
+        &self,
+        toxics: Vec<Toxic>,
+        direction: StreamDirection,
+        stop: &Stop,
+        state_holder: Option<Arc<ToxicStateHolder>>,
+    ) -> futures_mpsc::Sender<Bytes> {
+        let rand_gen = if let Some(seed) = self.config.rand_seed {
+            StdRng::seed_from_u64(seed)
+        } else {
+            StdRng::from_entropy()
+        };
+        let (client_addr, connection) = (self.client_addr, self.connection);
+        let upstream_addr = &self.upstream_addr;
+        let toxics = toxics
+            .into_iter()
+            .filter(|toxic| toxic.enabled && toxic.matches(client_addr, connection, upstream_addr))
+            .map(|mut toxic| {
+                // Toxics on both directions run with the direction of this chain
+                toxic.direction = direction;
+                toxic
+            });
+
+        let (chain_sender, mut prev_receiver) = futures_mpsc::channel::<Bytes>(SESSION_BUFFER_SIZE);
+        for (toxic, threshold) in toxics.zip(rand_gen.sample_iter(Standard)) {
+            let mut runner = ToxicRunner::new((toxic, threshold)).for_datagrams();
+            if runner.toxic_kind().has_close_logic() {
+                runner.set_override_stop(stop.clone());
+            }
+            let toxic_state = state_holder.as_ref().and_then(|holder| {
+                holder.get_state_for_toxic(runner.toxic_name(), runner.toxic_direction())
+            });
+            let (sender, receiver) =
+                futures_mpsc::channel::<Bytes>(runner.toxic_kind().chunk_buffer_capacity());
+            let mut stop = stop.clone();
+            let rand_seed = self.config.rand_seed;
+            tokio::spawn(async move {
+                let maybe_res = tokio::select! {
+                    res = runner.run(prev_receiver, sender, toxic_state, rand_seed, client_addr) => Some(res),
+                    _ = stop.recv() => None,
+                };
+                if let Some(Err(err)) = maybe_res {
+                    debug!("Got error from toxic runner {:?}", err);
+                }
+            });
+            prev_receiver = receiver;
+        }
+
+        let listen = self.listen.clone();
+        let upstream = self.upstream.clone();
+        tokio::spawn(async move {
+            while let Some(datagram) = prev_receiver.next().await {
+                let res = match direction {
+                    StreamDirection::Downstream => listen.send_to(&datagram, client_addr).await,
+                    _ => upstream.send(&datagram).await,
+                };
+                if let Err(err) = res {
+                    debug!(err = ?err, client = ?client_addr, "Failed to send datagram {}", direction);
+                }
+            }
+        });
+        chain_sender
+    }
+}
+
+/// Connect a new session to the picked upstream or one of the fallbacks, then run it
+#[allow(clippy::too_many_arguments)]
+async fn connect_session(
+    client_addr: SocketAddr,
+    connection: u64,
+    picked: String,
+    config: Arc<ProxyConfig>,
+    listen: Arc<UdpSocket>,
+    client_receiver: mpsc::Receiver<Bytes>,
+    toxics_receiver: watch::Receiver<Toxics>,
+    mut stop: Stop,
+    sessions: Sessions,
+) {
+    let connected = tokio::select! {
+        connected = connect_with_failover(
+            &picked,
+            &config.fallbacks,
+            config.connect_timeout(),
+            |upstream| {
+                let dns = config.dns.as_ref();
+                async move {
+                    let addr = resolve(dns, &upstream).await?;
+                    connect_upstream(&addr).await
+                }
+            },
+        ) => connected,
+        _ = stop.recv() => return,
+    };
+    let (upstream, upstream_addr) = match connected {
+        Ok(connected) => connected,
+        Err(err) => {
+            error!(err = ?err, proxy = ?&config.name, upstream = ?&picked, listen = ?&config.listen, "Unable to open socket to upstream");
+            // This is not a fatal error, can retry with the next datagram
+            remove_session(&sessions, client_addr, connection);
+            return;
+        }
+    };
+    if let Some(session) = sessions
+        .lock()
+        .expect("UDP sessions poisoned")
+        .get_mut(&client_addr)
+        .filter(|session| session.connection == connection)
+    {
+        session.upstream = upstream_addr.clone();
+    }
+
+    let session = Session {
+        config,
+        client_addr,
+        connection,
+        upstream_addr,
+        listen,
+        upstream: Arc::new(upstream),
+    };
+    session
+        .run(client_receiver, toxics_receiver, stop, sessions)
+        .await;
+}
+
+/// Forget a session, unless a new session for the same client has replaced it already
+fn remove_session(sessions: &Sessions, client_addr: SocketAddr, connection: u64) {
+    let mut sessions = sessions.lock().expect("UDP sessions poisoned");
+    if sessions
+        .get(&client_addr)
+        .map_or(false, |session| session.connection == connection)
+    {
+        sessions.remove(&client_addr);
+    }
+}
+
+/// Resolve the upstream address and open a socket connected to it
+async fn connect_upstream(upstream: &str) -> io::Result<UdpSocket> {
+    let addr = lookup_host(upstream).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("could not resolve {}", upstream),
+        )
+    })?;
+    let bind_addr: SocketAddr = if addr.is_ipv4() {
+        ([0u8; 4], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Apply the toxic events to the proxy state, and pass the new toxics to the sessions
+async fn listen_toxic_events(
+    state: Arc<ProxyState>,
+    mut receiver: RequestReceiver<ToxicEvent, ToxicEventResult>,
+    mut stop: Stop,
+    toxics_sender: watch::Sender<Toxics>,
+) {
+    while !stop.stop_received() {
+        let maybe_payload: Option<Payload<ToxicEvent, ToxicEventResult>> = tokio::select! {
+            res = receiver.recv() => res.ok(),
+            _ = stop.recv() => None,
+        };
+        let (request, mut responder) = match maybe_payload {
+            Some(payload) => payload,
+            None => break,
+        };
+        let result = {
+            let mut current_state = state.lock();
+            update_toxics(request, &mut current_state.toxics).map(|_| current_state.toxics.clone())
+        };
+        match result {
+            Ok(toxics) => {
+                let _ = toxics_sender.send(toxics);
+                let _ = responder.respond(Ok(()));
+            }
+            Err(err) => {
+                let _ = responder.respond(Err(err));
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::iter;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::debug;
+
+/// How a proxy with several upstreams picks one for each client connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum BalancePolicy {
+    /// Take the upstreams in turns
+    #[default]
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    /// Pick a random upstream. Reproducible when the proxy has a random seed
+    #[serde(rename = "random")]
+    Random,
+    /// Pick the upstream with the fewest open connections, the first one on a tie
+    #[serde(rename = "least_connections")]
+    LeastConnections,
+    /// Always send the same client IP address to the same upstream
+    #[serde(rename = "sticky")]
+    Sticky,
+}
+
+impl BalancePolicy {
+    pub(crate) fn is_round_robin(&self) -> bool {
+        *self == BalancePolicy::RoundRobin
+    }
+}
+
+/// Picks the upstream for each connection of a proxy
+#[derive(Debug)]
+pub(crate) struct UpstreamBalancer {
+    policy: BalancePolicy,
+    upstreams: Vec<String>,
+    next: usize,
+    rand_gen: StdRng,
+}
+
+impl UpstreamBalancer {
+    pub(crate) fn new(
+        policy: BalancePolicy,
+        upstreams: Vec<String>,
+        rand_seed: Option<u64>,
+    ) -> Self {
+        let rand_gen = if let Some(seed) = rand_seed {
+            StdRng::seed_from_u64(seed)
+        } else {
+            StdRng::from_entropy()
+        };
+        UpstreamBalancer {
+            policy,
+            upstreams,
+            next: 0,
+            rand_gen,
+        }
+    }
+
+    /// Pick the upstream for a connection from this client.
+    /// `open_connections` returns the number of open connections to an upstream
+    pub(crate) fn pick(
+        &mut self,
+        client: IpAddr,
+        open_connections: impl Fn(&str) -> usize,
+    ) -> &str {
+        let count = self.upstreams.len();
+        let index = if count <= 1 {
+            0
+        } else {
+            match self.policy {
+                BalancePolicy::RoundRobin => {
+                    let index = self.next % count;
+                    self.next = index + 1;
+                    index
+                }
+                BalancePolicy::Random => self.rand_gen.gen_range(0..count),
+                BalancePolicy::LeastConnections => (0..count)
+                    .min_by_key(|index| open_connections(&self.upstreams[*index]))
+                    .unwrap_or(0),
+                BalancePolicy::Sticky => {
+                    let mut hasher = DefaultHasher::new();
+                    client.hash(&mut hasher);
+                    (hasher.finish() % count as u64) as usize
+                }
+            }
+        };
+        &self.upstreams[index]
+    }
+}
+
+/// Connect to the picked upstream, or to the first fallback that accepts when it fails.
+/// Each attempt gives up after `connect_timeout` if set.
+/// Returns the connection and the address it was made to, or the last error
+pub(crate) async fn connect_with_failover<T, F, Fut>(
+    picked: &str,
+    fallbacks: &[String],
+    connect_timeout: Option<Duration>,
+    connect: F,
+) -> io::Result<(T, String)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut last_err = None;
+    let candidates = iter::once(picked).chain(
+        fallbacks
+            .iter()
+            .map(String::as_str)
+            .filter(|fallback| *fallback != picked),
+    );
+    for upstream in candidates {
+        let attempt = connect(upstream.to_owned());
+        let result = match connect_timeout {
+            Some(duration) => match timeout(duration, attempt).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connecting to {} timed out", upstream),
+                )),
+            },
+            None => attempt.await,
+        };
+        match result {
+            Ok(connection) => return Ok((connection, upstream.to_owned())),
+            Err(err) => {
+                debug!(err = ?err, upstream = ?upstream, "Upstream connection attempt failed");
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no upstream")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(policy: BalancePolicy) -> UpstreamBalancer {
+        let upstreams = vec!["a:1".to_owned(), "b:1".to_owned(), "c:1".to_owned()];
+        UpstreamBalancer::new(policy, upstreams, Some(7))
+    }
+
+    fn ip(input: &str) -> IpAddr {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut balancer = balancer(BalancePolicy::RoundRobin);
+        let picked: Vec<String> = (0..4)
+            .map(|_| balancer.pick(ip("127.0.0.1"), |_| 0).to_owned())
+            .collect();
+        assert_eq!(vec!["a:1", "b:1", "c:1", "a:1"], picked);
+    }
+
+    #[test]
+    fn least_connections_picks_idle_upstream() {
+        let mut balancer = balancer(BalancePolicy::LeastConnections);
+        let open = |upstream: &str| if upstream == "b:1" { 0 } else { 2 };
+        assert_eq!("b:1", balancer.pick(ip("127.0.0.1"), open));
+        assert_eq!("a:1", balancer.pick(ip("127.0.0.1"), |_| 0));
+    }
+
+    #[test]
+    fn sticky_keeps_client_on_one_upstream() {
+        let mut balancer = balancer(BalancePolicy::Sticky);
+        let first = balancer.pick(ip("10.0.0.1"), |_| 0).to_owned();
+        for _ in 0..5 {
+            assert_eq!(first, balancer.pick(ip("10.0.0.1"), |_| 0));
+        }
+    }
+
+    #[test]
+    fn seeded_random_is_reproducible() {
+        let mut first = balancer(BalancePolicy::Random);
+        let mut second = balancer(BalancePolicy::Random);
+        for _ in 0..5 {
+            assert_eq!(
+                first.pick(ip("127.0.0.1"), |_| 0).to_owned(),
+                second.pick(ip("127.0.0.1"), |_| 0)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_in_order() {
+        let fallbacks = vec!["b:1".to_owned(), "a:1".to_owned(), "c:1".to_owned()];
+        let attempts = std::sync::Mutex::new(Vec::new());
+        let (connection, upstream) = connect_with_failover("a:1", &fallbacks, None, |addr| {
+            attempts.lock().unwrap().push(addr.clone());
+            async move {
+                if addr == "c:1" {
+                    Ok(42)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(42, connection);
+        assert_eq!("c:1", upstream);
+        assert_eq!(vec!["a:1", "b:1", "c:1"], *attempts.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn times_out_each_attempt() {
+        let fallbacks = vec!["b:1".to_owned()];
+        let result = connect_with_failover(
+            "a:1",
+            &fallbacks,
+            Some(Duration::from_millis(20)),
+            |addr| async move {
+                if addr == "a:1" {
+                    futures::future::pending::<io::Result<u32>>().await
+                } else {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                }
+            },
+        )
+        .await;
+        assert_eq!(io::ErrorKind::ConnectionRefused, result.unwrap_err().kind());
+    }
+}
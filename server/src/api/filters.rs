@@ -137,6 +137,33 @@ pub fn remove_toxic(store: Store) -> impl Filter<Extract = impl Reply, Error = R
         .and(util::add_store(store))
         .and_then(handlers::remove_toxic)
 }
+
+/// POST /proxies/{proxy}/toxics/{toxic}/enable
+pub fn enable_toxic(store: Store) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path("proxies"))
+        .and(warp::path::param())
+        .and(warp::path("toxics"))
+        .and(warp::path::param())
+        .and(warp::path("enable"))
+        .and(warp::path::end())
+        .and(util::add_store(store))
+        .and_then(handlers::enable_toxic)
+}
+
+/// POST /proxies/{proxy}/toxics/{toxic}/disable
+pub fn disable_toxic(store: Store) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path("proxies"))
+        .and(warp::path::param())
+        .and(warp::path("toxics"))
+        .and(warp::path::param())
+        .and(warp::path("disable"))
+        .and(warp::path::end())
+        .and(util::add_store(store))
+        .and_then(handlers::disable_toxic)
+}
+
 /// GET /version
 
 pub fn version() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
@@ -211,7 +238,7 @@ mod tests {
     use crate::store::tests::__mock_MockNoopRunner_Runner::__run_proxy::Context as RpContext;
     use crate::store::tests::{hack_handle_id, MockNoopListener, MockNoopRunner, MOCK_LOCK};
     use noxious::{
-        proxy::{ProxyConfig, ProxyWithToxics},
+        proxy::{Framing, ProxyConfig, ProxyWithToxics},
         signal::Stop,
         state::{ProxyState, SharedProxyInfo},
         toxic::{StreamDirection, Toxic, ToxicKind},
@@ -244,6 +271,7 @@ mod tests {
             let proxy_info = SharedProxyInfo {
                 state: Arc::new(ProxyState::new(initial_toxics)),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -270,14 +298,18 @@ mod tests {
                 listen: "127.0.0.1:5431".to_owned(),
                 upstream: "127.0.0.1:5432".to_owned(),
                 enabled: true,
+                framing: Framing::Raw,
                 rand_seed: None,
+                ..Default::default()
             },
             ProxyConfig {
                 name: "server2".to_owned(),
                 listen: "127.0.0.1:27017".to_owned(),
                 upstream: "127.0.0.1:27018".to_owned(),
                 enabled: false,
+                framing: Framing::Raw,
                 rand_seed: None,
+                ..Default::default()
             },
         ];
         assert_ok!(
@@ -298,14 +330,18 @@ mod tests {
                 listen: "127.0.0.1:5431".to_owned(),
                 upstream: "127.0.0.1:5432".to_owned(),
                 enabled: true,
+                framing: Framing::Raw,
                 rand_seed: None,
+                ..Default::default()
             },
             ProxyConfig {
                 name: "server2".to_owned(),
                 listen: "127.0.0.1:27017".to_owned(),
                 upstream: "127.0.0.1:27018".to_owned(),
                 enabled: false,
+                framing: Framing::Raw,
                 rand_seed: None,
+                ..Default::default()
             },
         ];
         let body = serde_json::to_vec(&proxies).unwrap();
@@ -371,7 +407,9 @@ mod tests {
             listen: "127.0.0.1:1234".to_owned(),
             upstream: "127.0.0.1:1235".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         })
         .unwrap();
 
@@ -400,13 +438,16 @@ mod tests {
             listen: "127.0.0.1:1234".to_owned(),
             upstream: "127.0.0.1:1235".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         let toxic = Toxic {
             kind: ToxicKind::Noop,
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let payload = serde_json::to_vec(&config).unwrap();
 
@@ -451,13 +492,16 @@ mod tests {
             listen: "127.0.0.1:1234".to_owned(),
             upstream: "127.0.0.1:1235".to_owned(),
             enabled: false,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         let toxic = Toxic {
             kind: ToxicKind::Noop,
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -500,13 +544,16 @@ mod tests {
             listen: "127.0.0.1:1234".to_owned(),
             upstream: "127.0.0.1:1235".to_owned(),
             enabled: false,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         let toxic = Toxic {
             kind: ToxicKind::Noop,
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -578,6 +625,7 @@ mod tests {
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -619,6 +667,7 @@ mod tests {
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Downstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -634,6 +683,58 @@ mod tests {
         assert_eq!(&expected, &body);
     }
 
+    #[tokio::test]
+    async fn test_create_invalid_slicer() {
+        let _lock = MOCK_LOCK.lock().await;
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let filter = make_filters(store.clone());
+        let _handle = mock_proxy_runner(store.clone());
+        insert_proxies(&store).await;
+
+        let payload =
+            "{\"type\":\"slicer\",\"attributes\":{\"average_size\":10,\"size_variation\":10}}";
+        let req = warp::test::request()
+            .method("POST")
+            .path("/proxies/server1/toxics")
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload);
+        let reply = req.reply(&filter).await;
+        assert_eq!(StatusCode::BAD_REQUEST, reply.status());
+        let body = String::from_utf8_lossy(reply.body());
+        assert!(body.contains("size_variation"));
+    }
+
+    #[tokio::test]
+    async fn test_create_deferred_toxic() {
+        let _lock = MOCK_LOCK.lock().await;
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let filter = make_filters(store.clone());
+        let _handle = mock_proxy_runner(store.clone());
+        insert_proxies(&store).await;
+
+        let create = |payload: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/proxies/server1/toxics")
+                .header(CONTENT_TYPE, "application/json")
+                .body(payload)
+        };
+        let payload = "{\"name\":\"later\",\"type\":\"noop\",\"start_after\":60000}";
+        let reply = create(payload).reply(&filter).await;
+        assert_eq!(StatusCode::ACCEPTED, reply.status());
+
+        // The pending toxic holds its name
+        let reply = create(payload).reply(&filter).await;
+        assert_eq!(StatusCode::CONFLICT, reply.status());
+
+        let payload =
+            "{\"name\":\"other\",\"type\":\"noop\",\"start_after\":60000,\"before\":\"nope\"}";
+        let reply = create(payload).reply(&filter).await;
+        assert_eq!(StatusCode::BAD_REQUEST, reply.status());
+    }
+
     #[tokio::test]
     async fn test_get_toxic() {
         let _lock = MOCK_LOCK.lock().await;
@@ -648,6 +749,7 @@ mod tests {
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -685,12 +787,14 @@ mod tests {
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
         let updated_toxic = Toxic {
             kind: ToxicKind::Timeout { timeout: 500 },
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -747,6 +851,7 @@ mod tests {
             name: "stub".to_owned(),
             toxicity: 1.0,
             direction: StreamDirection::Upstream,
+            ..Default::default()
         };
 
         // Create a toxic to make sure the response body of update includes the toxic too
@@ -796,6 +901,63 @@ mod tests {
         assert_eq!(0, toxics.len());
     }
 
+    #[tokio::test]
+    async fn test_disable_and_enable_toxic() {
+        let _lock = MOCK_LOCK.lock().await;
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let filter = make_filters(store.clone());
+        let _handle = mock_proxy_runner(store.clone());
+        insert_proxies(&store).await;
+
+        let mut toxic = Toxic {
+            kind: ToxicKind::LimitData { bytes: 1000 },
+            name: "limiter".to_owned(),
+            toxicity: 1.0,
+            direction: StreamDirection::Upstream,
+            ..Default::default()
+        };
+        let payload = serde_json::to_vec(&toxic).unwrap();
+        let req = warp::test::request()
+            .method("POST")
+            .path("/proxies/server1/toxics")
+            .header(CONTENT_TYPE, "application/json")
+            .body(&payload);
+        let reply = req.reply(&filter).await;
+        assert_eq!(StatusCode::OK, reply.status());
+
+        let req = warp::test::request()
+            .method("POST")
+            .path("/proxies/server1/toxics/limiter/disable");
+        let reply = req.reply(&filter).await;
+        assert_eq!(StatusCode::OK, reply.status());
+        let body: Toxic = serde_json::from_slice(reply.body()).unwrap();
+        toxic.enabled = false;
+        assert_eq!(&toxic, &body);
+
+        let req = warp::test::request()
+            .method("GET")
+            .path("/proxies/server1/toxics/limiter");
+        let reply = req.reply(&filter).await;
+        let body: Toxic = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(&toxic, &body);
+
+        let req = warp::test::request()
+            .method("POST")
+            .path("/proxies/server1/toxics/limiter/enable");
+        let reply = req.reply(&filter).await;
+        assert_eq!(StatusCode::OK, reply.status());
+        let body: Toxic = serde_json::from_slice(reply.body()).unwrap();
+        toxic.enabled = true;
+        assert_eq!(&toxic, &body);
+
+        let req = warp::test::request()
+            .method("POST")
+            .path("/proxies/server1/toxics/nope/enable");
+        let reply = req.reply(&filter).await;
+        assert_eq!(StatusCode::NOT_FOUND, reply.status());
+    }
+
     #[tokio::test]
     async fn version_filter() {
         let filter = version();
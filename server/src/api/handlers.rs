@@ -1,7 +1,7 @@
 use crate::{error::StoreError, store::Store, util};
 use noxious::{
     proxy::{ProxyConfig, ProxyRunner},
-    socket::TcpListener,
+    socket::AnyListener,
     toxic::Toxic,
 };
 use responses::*;
@@ -23,7 +23,7 @@ pub async fn reset_state(store: Store) -> Result<impl Reply, Infallible> {
 #[instrument(level = "info", skip(store))]
 pub async fn populate(configs: Vec<ProxyConfig>, store: Store) -> Result<impl Reply, Infallible> {
     wrap_store_result_with_status(
-        async move { store.populate::<TcpListener, ProxyRunner>(configs).await },
+        async move { store.populate::<AnyListener, ProxyRunner>(configs).await },
         StatusCode::CREATED,
     )
     .await
@@ -50,7 +50,7 @@ pub async fn get_proxies(store: Store) -> Result<impl Reply, Infallible> {
 #[instrument(level = "info", skip(store))]
 pub async fn create_proxy(proxy: ProxyConfig, store: Store) -> Result<impl Reply, Infallible> {
     wrap_store_result_with_status(
-        async move { store.create_proxy::<TcpListener, ProxyRunner>(proxy).await },
+        async move { store.create_proxy::<AnyListener, ProxyRunner>(proxy).await },
         StatusCode::CREATED,
     )
     .await
@@ -69,7 +69,7 @@ pub async fn update_proxy(
 ) -> Result<impl Reply, Infallible> {
     wrap_store_result(async move {
         store
-            .update_proxy::<TcpListener, ProxyRunner>(&name, new_proxy)
+            .update_proxy::<AnyListener, ProxyRunner>(&name, new_proxy)
             .await
     })
     .await
@@ -91,7 +91,17 @@ pub async fn create_toxic(
     toxic: Toxic,
     store: Store,
 ) -> Result<impl Reply, Infallible> {
-    wrap_store_result(async move { store.create_toxic(proxy_name, toxic).await }).await
+    // Deferred toxics are only checked now, and added to the proxy later
+    let status_code = if toxic.is_deferred() {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    wrap_store_result_with_status(
+        async move { store.create_toxic(proxy_name, toxic).await },
+        status_code,
+    )
+    .await
 }
 
 #[instrument(level = "info", skip(store))]
@@ -124,6 +134,26 @@ pub async fn remove_toxic(
         .await
 }
 
+#[instrument(level = "info", skip(store))]
+pub async fn enable_toxic(
+    proxy_name: String,
+    toxic_name: String,
+    store: Store,
+) -> Result<impl Reply, Infallible> {
+    wrap_store_result(async move { store.set_toxic_enabled(proxy_name, toxic_name, true).await })
+        .await
+}
+
+#[instrument(level = "info", skip(store))]
+pub async fn disable_toxic(
+    proxy_name: String,
+    toxic_name: String,
+    store: Store,
+) -> Result<impl Reply, Infallible> {
+    wrap_store_result(async move { store.set_toxic_enabled(proxy_name, toxic_name, false).await })
+        .await
+}
+
 #[instrument(level = "info")]
 pub async fn get_version() -> Result<impl Reply, Infallible> {
     Ok(warp::reply::with_status(
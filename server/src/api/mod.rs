@@ -20,6 +20,7 @@ fn make_filters(store: Store) -> impl Filter<Extract = impl Reply, Error = Infal
             .or(update_toxic(store.clone()))
             .or(create_toxic(store.clone())))
         .or(remove_toxic(store.clone()).or(get_toxics(store.clone())))
+        .or(enable_toxic(store.clone()).or(disable_toxic(store.clone())))
         .or(update_proxy(store.clone()).or(get_proxy(store.clone())))
         .or(create_proxy(store.clone())
             .or(remove_proxy(store.clone()))
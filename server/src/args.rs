@@ -16,6 +16,9 @@ pub struct Args {
     /// Seed for randomizing toxics with
     #[clap(long)]
     pub seed: Option<u64>,
+    /// Directory to load WebAssembly toxics from, one <type>.wasm file per toxic type
+    #[clap(long)]
+    pub plugin_dir: Option<String>,
 }
 
 impl Args {
@@ -44,6 +47,7 @@ mod tests {
             port: "5555".to_owned(),
             config: None,
             seed: None,
+            plugin_dir: None,
         };
         let addr = input.get_ip_addr();
         let expected = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
@@ -57,6 +61,7 @@ mod tests {
             port: "5555".to_owned(),
             config: None,
             seed: None,
+            plugin_dir: None,
         };
         let addr = input.get_ip_addr();
         let expected = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
@@ -70,6 +75,7 @@ mod tests {
             port: "5555".to_owned(),
             config: None,
             seed: None,
+            plugin_dir: None,
         };
         let port = input.get_port_number();
         let expected = 5555u16;
@@ -84,6 +90,7 @@ mod tests {
             port: "5555".to_owned(),
             config: None,
             seed: None,
+            plugin_dir: None,
         };
         let _addr = input.get_ip_addr();
     }
@@ -96,6 +103,7 @@ mod tests {
             port: "555511111".to_owned(),
             config: None,
             seed: None,
+            plugin_dir: None,
         };
         let _port = input.get_port_number();
     }
@@ -1,4 +1,4 @@
-use noxious::{error::ToxicUpdateError, proxy::ProxyValidateError};
+use noxious::{error::ToxicUpdateError, proxy::ProxyValidateError, toxic::ToxicValidateError};
 use serde::Serialize;
 use std::io;
 use thiserror::Error;
@@ -18,6 +18,10 @@ pub enum ResourceKind {
 pub enum StoreError {
     #[error("Missing required field")]
     InvalidProxyConfig(ProxyValidateError),
+    #[error("Invalid toxic: {0}")]
+    InvalidToxic(ToxicValidateError),
+    #[error("Invalid toxic: the toxic named in before or after does not exist")]
+    InvalidToxicPosition,
     #[error("An item with this name already exists")]
     AlreadyExists,
     #[error("{0} not found")]
@@ -43,7 +47,9 @@ pub struct ApiErrorResponse {
 impl From<StoreError> for StatusCode {
     fn from(err: StoreError) -> Self {
         match err {
-            StoreError::InvalidProxyConfig(..) => StatusCode::BAD_REQUEST,
+            StoreError::InvalidProxyConfig(..)
+            | StoreError::InvalidToxic(..)
+            | StoreError::InvalidToxicPosition => StatusCode::BAD_REQUEST,
             StoreError::AlreadyExists => StatusCode::CONFLICT,
             StoreError::NotFound(..) => StatusCode::NOT_FOUND,
             StoreError::ProxyClosed | StoreError::IoError(..) | StoreError::Other => {
@@ -65,10 +71,17 @@ impl From<ProxyValidateError> for StoreError {
     }
 }
 
+impl From<ToxicValidateError> for StoreError {
+    fn from(err: ToxicValidateError) -> Self {
+        StoreError::InvalidToxic(err)
+    }
+}
+
 impl From<ToxicUpdateError> for StoreError {
     fn from(err: ToxicUpdateError) -> Self {
         match err {
             ToxicUpdateError::NotFound => StoreError::NotFound(ResourceKind::Toxic),
+            ToxicUpdateError::PositionNotFound => StoreError::InvalidToxicPosition,
             ToxicUpdateError::Other => StoreError::Other,
         }
     }
@@ -124,6 +137,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn toxic_validate_error_into_bad_request() {
+        let err: StoreError = ToxicValidateError::SizeVariationTooLarge.into();
+        assert_eq!(
+            "Invalid toxic: attributes.size_variation must be less than attributes.average_size",
+            err.to_string()
+        );
+        let code: StatusCode = err.into();
+        assert_eq!(StatusCode::BAD_REQUEST, code);
+    }
+
     #[test]
     fn toxic_update_error_into_store_error() {
         let err: StoreError = ToxicUpdateError::NotFound.into();
@@ -131,6 +155,10 @@ mod tests {
 
         let err: StoreError = ToxicUpdateError::Other.into();
         assert_eq!(StoreError::Other, err);
+
+        let err: StoreError = ToxicUpdateError::PositionNotFound.into();
+        let code: StatusCode = err.into();
+        assert_eq!(StatusCode::BAD_REQUEST, code);
     }
 
     #[test]
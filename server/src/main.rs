@@ -1,8 +1,10 @@
 use crate::args::Args;
 use clap::Parser;
-use noxious::{proxy::ProxyRunner, signal::Stop, socket::TcpListener};
+use noxious::{proxy::ProxyRunner, signal::Stop, socket::AnyListener};
 use std::net::SocketAddr;
 use tokio::signal;
+#[cfg(any(feature = "script", feature = "wasm"))]
+use tracing::error;
 use tracing::{debug, info};
 
 use crate::{file::populate_initial_proxy_configs, store::Store};
@@ -11,6 +13,10 @@ mod api;
 mod args;
 mod error;
 mod file;
+#[cfg(feature = "wasm")]
+mod plugin;
+#[cfg(feature = "script")]
+mod script;
 mod store;
 mod util;
 
@@ -22,10 +28,19 @@ async fn main() {
 
     let (stop, stopper) = Stop::new();
 
+    #[cfg(feature = "script")]
+    if let Err(err) = script::register() {
+        error!(err = ?err, "Failed to register the script toxic");
+    }
+
+    if let Some(plugin_dir) = &args.plugin_dir {
+        load_plugins(plugin_dir);
+    }
+
     let store = Store::new(stop.clone(), args.seed);
 
     if let Some(config_file_path) = &args.config {
-        populate_initial_proxy_configs::<TcpListener, ProxyRunner>(config_file_path, store.clone());
+        populate_initial_proxy_configs::<AnyListener, ProxyRunner>(config_file_path, store.clone());
     } else {
         debug!("No config file path provided");
     }
@@ -43,3 +58,20 @@ async fn main() {
     )
     .await;
 }
+
+#[cfg(feature = "wasm")]
+fn load_plugins(plugin_dir: &str) {
+    match plugin::load_plugins(std::path::Path::new(plugin_dir)) {
+        Ok(toxics) => info!(plugin_dir, toxics = ?toxics, "Loaded plugins"),
+        Err(err) => error!(plugin_dir, err = ?err, "Error reading plugin directory"),
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+fn load_plugins(plugin_dir: &str) {
+    use tracing::warn;
+    warn!(
+        plugin_dir,
+        "Built without the wasm feature, ignoring the plugin directory"
+    );
+}
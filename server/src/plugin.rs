@@ -0,0 +1,426 @@
+//! Toxics compiled to WebAssembly, loaded from a plugin directory
+//!
+//! Every `<type>.wasm` file in the directory is registered as a custom toxic named `<type>`.
+//! A module must not import anything, and must export:
+//!
+//! - `memory`: the linear memory
+//! - `alloc(len: i32) -> i32`: returns a pointer to `len` bytes the host can write to
+//! - `transform(ptr: i32, len: i32, direction: i32) -> i32`: called for every chunk, with
+//!   direction 0 for upstream and 1 for downstream. Returns a pointer to the 16 byte decision,
+//!   four little-endian u32 values: action (0 forward, 1 drop, 2 close), delay in milliseconds,
+//!   output pointer and output length.
+//!
+//! Optionally, it may export:
+//!
+//! - `init(ptr: i32, len: i32) -> i32`: called with the toxic attributes as JSON before the
+//!   first chunk. A non-zero result rejects the attributes.
+//! - `dealloc(ptr: i32, len: i32)`: called to release the chunk after `transform` returns.
+//!
+//! Each direction of each connection gets its own instance, so the module may keep state.
+//! Plugin code runs on the blocking thread pool, and a call that runs more than
+//! `FUEL_PER_CALL` instructions is aborted.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use noxious::{
+    custom::{
+        register_custom_toxic_factory, CustomToxic, RegisterError, ToxicContext, ToxicInput,
+        ToxicOutput,
+    },
+    toxic::StreamDirection,
+};
+use serde_json::Value;
+use std::convert::TryInto;
+use std::{fs, io, path::Path, sync::Arc, time::Duration};
+use tokio::{task::spawn_blocking, time::sleep};
+use tracing::{error, info};
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, TypedFunc};
+
+const ACTION_FORWARD: u32 = 0;
+const ACTION_DROP: u32 = 1;
+const ACTION_CLOSE: u32 = 2;
+/// Roughly the number of wasm instructions a plugin may run for one chunk
+const FUEL_PER_CALL: u64 = 100_000_000;
+
+/// A toxic backed by a WebAssembly module
+struct WasmToxic {
+    engine: Engine,
+    module: Module,
+    attributes: String,
+}
+
+/// What the plugin decided to do with a chunk
+#[derive(Debug, PartialEq)]
+struct Decision {
+    action: u32,
+    delay: u64,
+    data: Bytes,
+}
+
+struct PluginInstance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+    transform: TypedFunc<(i32, i32, i32), i32>,
+}
+
+/// Compile and register every `.wasm` file in the directory, returns the registered type names
+pub fn load_plugins(dir: &Path) -> io::Result<Vec<String>> {
+    let engine = plugin_engine().map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let mut registered = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("wasm") {
+            continue;
+        }
+        let type_name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(type_name) => type_name.to_owned(),
+            None => continue,
+        };
+        let module = match Module::from_file(&engine, &path) {
+            Ok(module) => module,
+            Err(err) => {
+                error!(err = ?err, plugin = ?&path, "Failed to compile plugin");
+                continue;
+            }
+        };
+        match register_plugin(&type_name, engine.clone(), module) {
+            Ok(()) => {
+                info!(plugin = ?&path, toxic = ?&type_name, "Loaded plugin toxic");
+                registered.push(type_name);
+            }
+            Err(err) => error!(err = ?err, plugin = ?&path, "Failed to register plugin"),
+        }
+    }
+    Ok(registered)
+}
+
+/// An engine that meters the instructions plugins run
+fn plugin_engine() -> Result<Engine, String> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).map_err(|err| err.to_string())
+}
+
+fn register_plugin(type_name: &str, engine: Engine, module: Module) -> Result<(), RegisterError> {
+    register_custom_toxic_factory(type_name, move |attributes: &Value| {
+        let toxic = WasmToxic {
+            engine: engine.clone(),
+            module: module.clone(),
+            attributes: attributes.to_string(),
+        };
+        // Instantiate once so missing exports and rejected attributes fail the API request
+        toxic.instantiate()?;
+        Ok(Arc::new(toxic) as Arc<dyn CustomToxic>)
+    })
+}
+
+impl WasmToxic {
+    fn instantiate(&self) -> Result<PluginInstance, String> {
+        PluginInstance::new(&self.engine, &self.module, &self.attributes)
+    }
+}
+
+#[async_trait]
+impl CustomToxic for WasmToxic {
+    async fn run(
+        &self,
+        mut input: ToxicInput<'_>,
+        mut output: ToxicOutput<'_>,
+        ctx: ToxicContext,
+    ) -> io::Result<()> {
+        let (engine, module, attributes) = (
+            self.engine.clone(),
+            self.module.clone(),
+            self.attributes.clone(),
+        );
+        let mut plugin = spawn_blocking(move || PluginInstance::new(&engine, &module, &attributes))
+            .await?
+            .map_err(plugin_error)?;
+        while let Some(chunk) = input.next().await {
+            let direction = ctx.direction;
+            let (returned, decision) = spawn_blocking(move || {
+                let decision = plugin.transform(&chunk, direction);
+                (plugin, decision)
+            })
+            .await?;
+            plugin = returned;
+            let decision = decision.map_err(plugin_error)?;
+            if decision.delay > 0 {
+                sleep(Duration::from_millis(decision.delay)).await;
+            }
+            match decision.action {
+                ACTION_FORWARD => output.send(decision.data).await?,
+                ACTION_DROP => {}
+                ACTION_CLOSE => return Ok(()),
+                action => return Err(plugin_error(format!("unknown action {}", action))),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PluginInstance {
+    fn new(engine: &Engine, module: &Module, attributes: &str) -> Result<Self, String> {
+        let mut store = Store::new(engine, ());
+        refuel(&mut store)?;
+        let instance = Instance::new(&mut store, module, &[]).map_err(|err| err.to_string())?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| "plugin does not export memory".to_owned())?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(|err| err.to_string())?;
+        let transform = instance
+            .get_typed_func::<(i32, i32, i32), i32>(&mut store, "transform")
+            .map_err(|err| err.to_string())?;
+        let dealloc = instance
+            .get_typed_func::<(i32, i32), ()>(&mut store, "dealloc")
+            .ok();
+        let init = instance
+            .get_typed_func::<(i32, i32), i32>(&mut store, "init")
+            .ok();
+
+        let mut plugin = PluginInstance {
+            store,
+            memory,
+            alloc,
+            dealloc,
+            transform,
+        };
+        if let Some(init) = init {
+            let (ptr, len) = plugin.write(attributes.as_bytes())?;
+            let code = init
+                .call(&mut plugin.store, (ptr, len))
+                .map_err(|err| err.to_string())?;
+            plugin.release(ptr, len)?;
+            if code != 0 {
+                return Err(format!("plugin rejected the attributes with code {}", code));
+            }
+        }
+        Ok(plugin)
+    }
+
+    /// Copy the data into the plugin memory
+    fn write(&mut self, data: &[u8]) -> Result<(i32, i32), String> {
+        let len: i32 = data
+            .len()
+            .try_into()
+            .map_err(|_| "chunk too large for the plugin".to_owned())?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|err| err.to_string())?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, data)
+            .map_err(|err| err.to_string())?;
+        Ok((ptr, len))
+    }
+
+    fn release(&mut self, ptr: i32, len: i32) -> Result<(), String> {
+        if let Some(dealloc) = &self.dealloc {
+            dealloc
+                .call(&mut self.store, (ptr, len))
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn transform(&mut self, chunk: &[u8], direction: StreamDirection) -> Result<Decision, String> {
+        let direction = match direction {
+            // Links run toxics on both directions with their own direction
+            StreamDirection::Upstream | StreamDirection::Both => 0,
+            StreamDirection::Downstream => 1,
+        };
+        refuel(&mut self.store)?;
+        let (ptr, len) = self.write(chunk)?;
+        let result_ptr = self
+            .transform
+            .call(&mut self.store, (ptr, len, direction))
+            .map_err(|err| err.to_string())?;
+
+        let mut result = [0u8; 16];
+        self.memory
+            .read(&self.store, result_ptr as u32 as usize, &mut result)
+            .map_err(|err| err.to_string())?;
+        let field = |index: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&result[index * 4..index * 4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        // Check the output is in the plugin memory before allocating room for it
+        let (out_ptr, out_len) = (field(2) as usize, field(3) as usize);
+        if out_ptr
+            .checked_add(out_len)
+            .map_or(true, |end| end > self.memory.data_size(&self.store))
+        {
+            return Err("plugin output is out of bounds".to_owned());
+        }
+        let mut data = vec![0u8; out_len];
+        self.memory
+            .read(&self.store, out_ptr, &mut data)
+            .map_err(|err| err.to_string())?;
+        self.release(ptr, len)?;
+
+        Ok(Decision {
+            action: field(0),
+            delay: field(1) as u64,
+            data: data.into(),
+        })
+    }
+}
+
+/// Reset the fuel of the store to the budget of one call
+fn refuel(store: &mut Store<()>) -> Result<(), String> {
+    let remaining = store.fuel_remaining().unwrap_or(0);
+    store
+        .add_fuel(FUEL_PER_CALL.saturating_sub(remaining))
+        .map_err(|err| err.to_string())
+}
+
+fn plugin_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("plugin error: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::channel;
+    use tokio_test::assert_ok;
+
+    /// Drops chunks starting with "x", uppercases the first byte of the others.
+    /// Rejects attributes that don't start with "{".
+    const PLUGIN: &str = r#"
+    (module
+      (memory (export "memory") 1)
+      (global $next (mut i32) (i32.const 1024))
+      (func (export "alloc") (param $len i32) (result i32)
+        (local $ptr i32)
+        (local.set $ptr (global.get $next))
+        (global.set $next (i32.add (global.get $next) (local.get $len)))
+        (local.get $ptr))
+      (func (export "init") (param $ptr i32) (param $len i32) (result i32)
+        (i32.ne (i32.load8_u (local.get $ptr)) (i32.const 123)))
+      (func (export "transform") (param $ptr i32) (param $len i32) (param $dir i32) (result i32)
+        (if (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 120))
+          (then (i32.store (i32.const 0) (i32.const 1)))
+          (else (i32.store (i32.const 0) (i32.const 0))))
+        (i32.store8 (local.get $ptr) (i32.sub (i32.load8_u (local.get $ptr)) (i32.const 32)))
+        (i32.store (i32.const 4) (local.get $dir))
+        (i32.store (i32.const 8) (local.get $ptr))
+        (i32.store (i32.const 12) (local.get $len))
+        (i32.const 0)))
+    "#;
+
+    fn toxic(attributes: &str) -> WasmToxic {
+        let engine = plugin_engine().unwrap();
+        let module = Module::new(&engine, PLUGIN).unwrap();
+        WasmToxic {
+            engine,
+            module,
+            attributes: attributes.to_owned(),
+        }
+    }
+
+    #[test]
+    fn transforms_chunks() {
+        let mut plugin = toxic("{}").instantiate().unwrap();
+        assert_eq!(
+            Decision {
+                action: ACTION_FORWARD,
+                delay: 1,
+                data: Bytes::from_static(b"Hello"),
+            },
+            plugin
+                .transform(b"hello", StreamDirection::Downstream)
+                .unwrap()
+        );
+        let decision = plugin.transform(b"xyz", StreamDirection::Upstream).unwrap();
+        assert_eq!(ACTION_DROP, decision.action);
+        assert_eq!(0, decision.delay);
+    }
+
+    #[test]
+    fn rejects_attributes() {
+        let err = toxic("null").instantiate().err().unwrap();
+        assert_eq!("plugin rejected the attributes with code 1", err);
+    }
+
+    #[test]
+    fn requires_exports() {
+        let engine = plugin_engine().unwrap();
+        let module = Module::new(&engine, "(module (memory (export \"memory\") 1))").unwrap();
+        assert!(PluginInstance::new(&engine, &module, "{}").is_err());
+    }
+
+    #[test]
+    fn stops_runaway_plugins() {
+        let engine = plugin_engine().unwrap();
+        let module = Module::new(
+            &engine,
+            r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "transform") (param i32 i32 i32) (result i32)
+                (loop $forever (br $forever))
+                (i32.const 0)))
+            "#,
+        )
+        .unwrap();
+        let mut plugin = PluginInstance::new(&engine, &module, "{}").unwrap();
+        assert!(plugin
+            .transform(b"spin", StreamDirection::Upstream)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_output_outside_memory() {
+        let engine = plugin_engine().unwrap();
+        let module = Module::new(
+            &engine,
+            r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+              (func (export "transform") (param i32 i32 i32) (result i32)
+                (i32.store (i32.const 8) (i32.const 16))
+                (i32.store (i32.const 12) (i32.const -1))
+                (i32.const 0)))
+            "#,
+        )
+        .unwrap();
+        let mut plugin = PluginInstance::new(&engine, &module, "{}").unwrap();
+        let err = plugin
+            .transform(b"huge", StreamDirection::Upstream)
+            .err()
+            .unwrap();
+        assert_eq!("plugin output is out of bounds", err);
+    }
+
+    #[tokio::test]
+    async fn runs_as_custom_toxic() {
+        let toxic = toxic("{}");
+        let (mut in_tx, in_rx) = channel::<Bytes>(1);
+        let (out_tx, mut out_rx) = channel::<Bytes>(1);
+        let ctx = ToxicContext {
+            name: "wasm".to_owned(),
+            direction: StreamDirection::Upstream,
+            client: "127.0.0.1:29992".parse().unwrap(),
+            rand_seed: None,
+        };
+        let handle = tokio::spawn(async move {
+            futures::pin_mut!(in_rx);
+            let out_tx = out_tx.sink_map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset));
+            futures::pin_mut!(out_tx);
+            toxic.run(in_rx, out_tx, ctx).await
+        });
+        assert_ok!(in_tx.send(Bytes::from_static(b"xdrop")).await);
+        assert_ok!(in_tx.send(Bytes::from_static(b"keep")).await);
+        assert_eq!(Some(Bytes::from_static(b"Keep")), out_rx.next().await);
+        drop(in_tx);
+        assert_ok!(handle.await.unwrap());
+    }
+}
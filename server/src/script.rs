@@ -0,0 +1,317 @@
+//! The `script` toxic, running a small Rhai script for every chunk
+//!
+//! The script sees these variables:
+//!
+//! - `chunk`: the chunk as a blob, can be modified in place
+//! - `index`: the number of chunks seen before this one on this connection and direction
+//! - `direction`: `"upstream"` or `"downstream"`
+//! - `client`: the client address, like `"127.0.0.1:51234"`
+//! - `toxic`: the toxic name
+//! - `state`: a map kept between the chunks of a connection and direction, like
+//!   `state.seen = if "seen" in state { state.seen + 1 } else { 1 };`
+//!
+//! and the functions `random()`, returning a float in [0, 1), and `random_int(low, high)`,
+//! returning an integer in [low, high). Both use the proxy random seed when it is set.
+//! Variables declared with `let` start over on every chunk, keep what you need in `state`.
+//!
+//! The script returns the data to forward as a blob or a string, or nothing to forward `chunk`.
+//! It can also return a map like `#{ data: chunk, delay: 100, close: true }`, where every field
+//! is optional: `delay` holds the chunk back in milliseconds, and `close` closes the connection
+//! after forwarding `data`. Return an empty blob to drop the chunk.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use noxious::custom::{
+    register_custom_toxic_factory, CustomToxic, RegisterError, ToxicContext, ToxicInput,
+    ToxicOutput,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rhai::{Blob, Dynamic, Engine, Map, Scope, AST};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::{io, time::Duration};
+use tokio::{task::spawn_blocking, time::sleep};
+
+/// The toxic type name used in the API
+pub const SCRIPT_TOXIC_TYPE: &str = "script";
+
+/// Stops a runaway script, so it can only hold up its own connection for so long
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug, Deserialize)]
+struct ScriptAttributes {
+    source: String,
+}
+
+struct ScriptToxic {
+    ast: AST,
+}
+
+struct ScriptRunner {
+    engine: Engine,
+    scope: Scope<'static>,
+    /// The scope length before the script runs, to drop its `let` variables after every chunk
+    base_len: usize,
+    ast: AST,
+}
+
+/// What to do with a chunk, decided by the script
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    data: Bytes,
+    delay: u64,
+    close: bool,
+}
+
+/// Register the script toxic type
+pub fn register() -> Result<(), RegisterError> {
+    register_custom_toxic_factory(SCRIPT_TOXIC_TYPE, |attributes: &Value| {
+        let attributes: ScriptAttributes =
+            serde_json::from_value(attributes.clone()).map_err(|err| err.to_string())?;
+        let ast = new_engine()
+            .compile(&attributes.source)
+            .map_err(|err| err.to_string())?;
+        Ok(Arc::new(ScriptToxic { ast }) as Arc<dyn CustomToxic>)
+    })
+}
+
+fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+}
+
+#[async_trait]
+impl CustomToxic for ScriptToxic {
+    async fn run(
+        &self,
+        mut input: ToxicInput<'_>,
+        mut output: ToxicOutput<'_>,
+        ctx: ToxicContext,
+    ) -> io::Result<()> {
+        let mut runner = ScriptRunner::new(self.ast.clone(), &ctx);
+        while let Some(chunk) = input.next().await {
+            // The interpreter runs up to MAX_OPERATIONS for a chunk, off the runtime threads
+            let (returned, outcome) = spawn_blocking(move || {
+                let outcome = runner.eval(&chunk);
+                (runner, outcome)
+            })
+            .await?;
+            runner = returned;
+            let outcome = outcome.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            if outcome.delay > 0 {
+                sleep(Duration::from_millis(outcome.delay)).await;
+            }
+            if !outcome.data.is_empty() {
+                output.send(outcome.data).await?;
+            }
+            if outcome.close {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ScriptRunner {
+    fn new(ast: AST, ctx: &ToxicContext) -> Self {
+        let rng = Arc::new(Mutex::new(match ctx.rand_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }));
+        let float_rng = rng.clone();
+
+        let mut engine = new_engine();
+        engine.register_fn("random", move || -> f64 {
+            float_rng.lock().expect("Script rng poisoned").gen()
+        });
+        engine.register_fn("random_int", move |low: i64, high: i64| -> i64 {
+            if high <= low {
+                low
+            } else {
+                rng.lock()
+                    .expect("Script rng poisoned")
+                    .gen_range(low..high)
+            }
+        });
+
+        let mut scope = Scope::new();
+        scope.push_constant("direction", ctx.direction.to_string());
+        scope.push_constant("client", ctx.client.to_string());
+        scope.push_constant("toxic", ctx.name.clone());
+        scope.push("index", -1_i64);
+        scope.push("chunk", Blob::new());
+        scope.push("state", Map::new());
+        let base_len = scope.len();
+
+        ScriptRunner {
+            engine,
+            scope,
+            base_len,
+            ast,
+        }
+    }
+
+    fn eval(&mut self, chunk: &[u8]) -> Result<Outcome, String> {
+        let index = self.scope.get_value::<i64>("index").unwrap_or(-1) + 1;
+        self.scope.set_value("index", index);
+        self.scope.set_value("chunk", chunk.to_vec());
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut self.scope, &self.ast);
+        self.scope.rewind(self.base_len);
+        let result = result.map_err(|err| format!("script error: {}", err))?;
+
+        let chunk = self.scope.get_value::<Blob>("chunk").unwrap_or_default();
+        if result.is::<Map>() {
+            let map = result.cast::<Map>();
+            let data = match map.get("data") {
+                Some(data) => to_bytes(data.clone())?,
+                None => chunk.into(),
+            };
+            let delay = match map.get("delay") {
+                Some(delay) => delay.as_int().map_err(type_error("delay"))?.max(0) as u64,
+                None => 0,
+            };
+            let close = match map.get("close") {
+                Some(close) => close.as_bool().map_err(type_error("close"))?,
+                None => false,
+            };
+            Ok(Outcome { data, delay, close })
+        } else {
+            let data = if result.is_unit() {
+                chunk.into()
+            } else {
+                to_bytes(result)?
+            };
+            Ok(Outcome {
+                data,
+                delay: 0,
+                close: false,
+            })
+        }
+    }
+}
+
+fn to_bytes(value: Dynamic) -> Result<Bytes, String> {
+    if value.is::<Blob>() {
+        Ok(value.cast::<Blob>().into())
+    } else if value.is_string() {
+        Ok(value.into_string().unwrap_or_default().into())
+    } else {
+        Err(format!(
+            "script returned {}, expected a blob, a string or a map",
+            value.type_name()
+        ))
+    }
+}
+
+fn type_error(field: &'static str) -> impl Fn(&str) -> String {
+    move |type_name| format!("script returned {} for {}", type_name, field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noxious::toxic::StreamDirection;
+
+    fn runner(source: &str, rand_seed: Option<u64>) -> ScriptRunner {
+        let ctx = ToxicContext {
+            name: "script_upstream".to_owned(),
+            direction: StreamDirection::Upstream,
+            client: "127.0.0.1:29993".parse().unwrap(),
+            rand_seed,
+        };
+        ScriptRunner::new(new_engine().compile(source).unwrap(), &ctx)
+    }
+
+    fn forward(data: &'static [u8]) -> Outcome {
+        Outcome {
+            data: Bytes::from_static(data),
+            delay: 0,
+            close: false,
+        }
+    }
+
+    #[test]
+    fn mangles_every_third_chunk() {
+        let mut runner = runner("if index % 3 == 2 { chunk[1] = 0x21; }", None);
+        assert_eq!(Ok(forward(b"abc")), runner.eval(b"abc"));
+        assert_eq!(Ok(forward(b"abc")), runner.eval(b"abc"));
+        assert_eq!(Ok(forward(b"a!c")), runner.eval(b"abc"));
+        assert_eq!(Ok(forward(b"abc")), runner.eval(b"abc"));
+    }
+
+    #[test]
+    fn sees_connection_metadata() {
+        let mut runner = runner("`${toxic} ${direction} ${client}`", None);
+        assert_eq!(
+            Ok(forward(b"script_upstream upstream 127.0.0.1:29993")),
+            runner.eval(b"")
+        );
+    }
+
+    #[test]
+    fn returns_delay_and_close() {
+        let mut runner = runner("#{ delay: 250, close: index > 0 }", None);
+        assert_eq!(
+            Ok(Outcome {
+                data: Bytes::from_static(b"one"),
+                delay: 250,
+                close: false,
+            }),
+            runner.eval(b"one")
+        );
+        assert_eq!(
+            Ok(Outcome {
+                data: Bytes::from_static(b"two"),
+                delay: 250,
+                close: true,
+            }),
+            runner.eval(b"two")
+        );
+    }
+
+    #[test]
+    fn seeded_random_is_reproducible() {
+        let source = "random_int(0, 1000).to_string()";
+        let first = runner(source, Some(7)).eval(b"").unwrap();
+        let second = runner(source, Some(7)).eval(b"").unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn keeps_state_but_not_let_variables() {
+        let mut counter = runner("let n = 0; n += 1; n.to_string()", None);
+        assert_eq!(Ok(forward(b"1")), counter.eval(b""));
+        assert_eq!(Ok(forward(b"1")), counter.eval(b""));
+        assert_eq!(counter.base_len, counter.scope.len());
+
+        let source = "state.seen = if \"seen\" in state { state.seen + 1 } else { 1 }; \
+                      state.seen.to_string()";
+        let mut seen = runner(source, None);
+        assert_eq!(Ok(forward(b"1")), seen.eval(b""));
+        assert_eq!(Ok(forward(b"2")), seen.eval(b""));
+    }
+
+    #[test]
+    fn rejects_bad_return_values() {
+        let mut runner = runner("42", None);
+        assert!(runner.eval(b"x").is_err());
+    }
+
+    #[test]
+    fn registers_and_rejects_invalid_scripts() {
+        assert_eq!(Ok(()), register());
+        let toxic = serde_json::from_str::<noxious::toxic::Toxic>(
+            "{\"type\":\"script\",\"attributes\":{\"source\":\"chunk\"}}",
+        );
+        assert!(toxic.is_ok());
+        let toxic = serde_json::from_str::<noxious::toxic::Toxic>(
+            "{\"type\":\"script\",\"attributes\":{\"source\":\"if {\"}}",
+        );
+        assert!(toxic.is_err());
+    }
+}
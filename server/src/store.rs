@@ -1,21 +1,24 @@
 use crate::error::{ResourceKind, StoreError};
-use bmrng::RequestSender;
-use futures::{stream, StreamExt};
+use bmrng::{RequestReceiver, RequestSender};
+use futures::{stream, Future, StreamExt};
 use noxious::{
-    proxy::{ProxyConfig, ProxyWithToxics, Runner, Toxics},
-    signal::{Close, Stop, Stopper},
+    proxy::{Protocol, ProxyConfig, ProxyWithToxics, Runner, Toxics},
+    signal::{Close, Closer, Stop, Stopper},
     socket::SocketListener,
     state::SharedProxyInfo,
-    toxic::{Toxic, ToxicEvent, ToxicEventKind, ToxicEventResult},
+    toxic::{Toxic, ToxicEvent, ToxicEventKind, ToxicEventResult, ToxicPosition, ToxicSchedule},
 };
 use std::{
     collections::HashMap,
+    io,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc, Mutex, MutexGuard,
     },
+    time::Duration,
 };
-use tracing::{debug, info, instrument};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info, instrument, warn};
 
 const TOXIC_EVENT_BUFFER_SIZE: usize = 2;
 
@@ -37,6 +40,7 @@ pub struct ProxyHandle {
 pub struct Shared {
     state: Mutex<State>,
     next_proxy_id: AtomicUsize,
+    next_timer_id: AtomicUsize,
     stop: Stop,
     rand_seed: Option<u64>,
 }
@@ -44,6 +48,19 @@ pub struct Shared {
 #[derive(Debug, Clone)]
 pub struct State {
     proxies: HashMap<String, ProxyHandle>,
+    /// (Proxy name, toxic name) -> the pending timers and schedules
+    toxic_timers: HashMap<(String, String), ToxicTimer>,
+}
+
+/// A task that adds or removes a toxic later
+#[derive(Debug, Clone)]
+struct ToxicTimer {
+    /// Internal: used to check if two timers are equal
+    id: usize,
+    stopper: Stopper,
+    /// The toxic the timer adds. Enabling or disabling a toxic that is not in the proxy
+    /// right now changes this copy
+    toxic: Arc<Mutex<Toxic>>,
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +80,7 @@ impl Store {
     /// Remove all toxics from all proxies
     #[instrument(skip(self))]
     pub async fn reset_state(&self) {
+        self.shared.get_state().cancel_toxic_timers(|_| true);
         let pairs: Vec<(String, RequestSender<ToxicEvent, ToxicEventResult>)> = self
             .shared
             .get_state()
@@ -95,6 +113,9 @@ impl Store {
         // Stop and remove the existing proxies with the same name
         let close_signals: Vec<Close> = {
             let mut state = self.shared.get_state();
+            state.cancel_toxic_timers(|(proxy_name, _)| {
+                input.iter().any(|config| &config.name == proxy_name)
+            });
             input
                 .iter()
                 .filter_map(|config| state.proxies.remove(&config.name))
@@ -133,7 +154,7 @@ impl Store {
         // acquire any more locks to retrieve the toxic lists.
         let proxies_with_toxics: Vec<ProxyWithToxics> = created_proxies
             .into_iter()
-            .map(|info| ProxyWithToxics::from_proxy_config(info.clone_config()))
+            .map(|info| ProxyWithToxics::from_proxy_config(info.resolved_config()))
             .collect();
         Ok(proxies_with_toxics)
     }
@@ -202,33 +223,50 @@ impl Store {
     #[instrument(level = "trace", skip(self))]
     pub async fn remove_proxy(&self, proxy_name: &str) -> Result<()> {
         self.shared.remove_proxy(proxy_name).await?;
+        self.shared
+            .get_state()
+            .cancel_toxic_timers(|(name, _)| name == proxy_name);
         Ok(())
     }
 
     #[instrument(level = "trace", skip(self))]
     pub async fn create_toxic(&self, proxy_name: String, mut toxic: Toxic) -> Result<Toxic> {
         toxic.set_default_name();
+        toxic.validate()?;
         let sender = self.shared.get_event_sender_for_proxy(&proxy_name)?;
+        self.shared.check_new_toxic(&proxy_name, &toxic)?;
+        if toxic.is_deferred() {
+            self.shared
+                .schedule_toxic(proxy_name, toxic.clone(), Some(ToxicEventKind::AddToxic));
+            return Ok(toxic);
+        }
 
+        // The proxy rejects the toxic if its before/after target is not in the chain
         let result = sender
             .send_receive(ToxicEvent::new(
-                proxy_name,
+                proxy_name.clone(),
                 ToxicEventKind::AddToxic(toxic.clone()),
             ))
             .await
-            .map_err(|_| StoreError::ProxyClosed);
+            .map_err(|_| StoreError::ProxyClosed)
+            .and_then(|result| result.map_err(StoreError::from));
 
         match result {
-            Ok(_) => Ok(toxic),
+            Ok(_) => {
+                toxic.position = ToxicPosition::default();
+                self.shared.schedule_toxic(proxy_name, toxic.clone(), None);
+                Ok(toxic)
+            }
             Err(err) => Err(err),
         }
     }
 
+    /// The toxics in the proxy, followed by the deferred and scheduled toxics that are not
+    /// in it right now
     #[instrument(level = "trace", skip(self))]
     pub async fn get_toxics(&self, proxy_name: &str) -> Result<Vec<Toxic>> {
-        Ok(self
-            .shared
-            .get_state()
+        let state = self.shared.get_state();
+        let mut toxics = state
             .proxies
             .get(proxy_name)
             .ok_or(StoreError::NotFound(ResourceKind::Proxy))?
@@ -237,13 +275,21 @@ impl Store {
             .lock()
             .toxics
             .to_owned()
-            .into_vec())
+            .into_vec();
+        let pending: Vec<Toxic> = state
+            .pending_toxics(proxy_name)
+            .into_iter()
+            .filter(|pending| toxics.iter().all(|toxic| toxic.name != pending.name))
+            .collect();
+        toxics.extend(pending);
+        Ok(toxics)
     }
 
+    /// The toxic in the proxy, or the deferred or scheduled toxic that is not in it right now
     #[instrument(level = "trace", skip(self))]
     pub async fn get_toxic(&self, proxy_name: &str, toxic_name: &str) -> Result<Toxic> {
-        self.shared
-            .get_state()
+        let state = self.shared.get_state();
+        let active = state
             .proxies
             .get(proxy_name)
             .ok_or(StoreError::NotFound(ResourceKind::Proxy))?
@@ -251,7 +297,15 @@ impl Store {
             .state
             .lock()
             .toxics
-            .find_by_name(toxic_name)
+            .find_by_name(toxic_name);
+        active
+            .or_else(|| {
+                let key = (proxy_name.to_owned(), toxic_name.to_owned());
+                state
+                    .toxic_timers
+                    .get(&key)
+                    .map(|timer| current_copy(&timer.toxic))
+            })
             .ok_or(StoreError::NotFound(ResourceKind::Toxic))
     }
 
@@ -265,25 +319,84 @@ impl Store {
         if toxic.name.is_empty() {
             toxic.name = toxic_name
         }
+        toxic.validate()?;
         let sender = self.shared.get_event_sender_for_proxy(&proxy_name)?;
+        if toxic.schedule.is_some() {
+            // The schedule adds the toxic back in its next active window
+            let _ = sender
+                .send_receive(ToxicEvent::new(
+                    proxy_name.clone(),
+                    ToxicEventKind::RemoveToxic(toxic.name.clone()),
+                ))
+                .await;
+            self.shared
+                .schedule_toxic(proxy_name, toxic.clone(), Some(ToxicEventKind::AddToxic));
+            return Ok(toxic);
+        }
+        if toxic.start_after.is_some() {
+            self.shared.schedule_toxic(
+                proxy_name,
+                toxic.clone(),
+                Some(ToxicEventKind::UpdateToxic),
+            );
+            return Ok(toxic);
+        }
 
+        // An update for a missing toxic or position target changes nothing, so it's an error
         let result = sender
             .send_receive(ToxicEvent::new(
-                proxy_name,
+                proxy_name.clone(),
                 ToxicEventKind::UpdateToxic(toxic.clone()),
             ))
             .await
-            .map_err(|_| StoreError::ProxyClosed);
+            .map_err(|_| StoreError::ProxyClosed)
+            .and_then(|result| result.map_err(StoreError::from));
 
         match result {
-            Ok(_) => Ok(toxic),
+            Ok(_) => {
+                toxic.position = ToxicPosition::default();
+                self.shared.schedule_toxic(proxy_name, toxic.clone(), None);
+                Ok(toxic)
+            }
             Err(err) => Err(err),
         }
     }
 
+    /// Switch a toxic on or off, keeping its place in the chain and its state
+    #[instrument(level = "trace", skip(self))]
+    pub async fn set_toxic_enabled(
+        &self,
+        proxy_name: String,
+        toxic_name: String,
+        enabled: bool,
+    ) -> Result<Toxic> {
+        let sender = self.shared.get_event_sender_for_proxy(&proxy_name)?;
+        // A deferred or scheduled toxic keeps the flag for the next time it is added
+        let pending = self
+            .shared
+            .set_pending_toxic_enabled(&proxy_name, &toxic_name, enabled);
+
+        let result = sender
+            .send_receive(ToxicEvent::new(
+                proxy_name.clone(),
+                ToxicEventKind::SetToxicEnabled(toxic_name.clone(), enabled),
+            ))
+            .await
+            .map_err(|_| StoreError::ProxyClosed)
+            .and_then(|result| result.map_err(StoreError::from));
+
+        match (result, pending) {
+            (Ok(_), _) => self.get_toxic(&proxy_name, &toxic_name).await,
+            // Not in the proxy, between two active windows or before start_after
+            (Err(StoreError::NotFound(ResourceKind::Toxic)), Some(toxic)) => Ok(toxic),
+            (Err(err), _) => Err(err),
+        }
+    }
+
     #[instrument(level = "trace", skip(self))]
     pub async fn remove_toxic(&self, proxy_name: String, toxic_name: String) -> Result<()> {
         let sender = self.shared.get_event_sender_for_proxy(&proxy_name)?;
+        self.shared.cancel_toxic_timer(&proxy_name, &toxic_name);
 
         let result = sender
             .send_receive(ToxicEvent::new(
@@ -305,6 +418,7 @@ impl Shared {
         Shared {
             state: Mutex::new(State::new()),
             next_proxy_id: AtomicUsize::new(0),
+            next_timer_id: AtomicUsize::new(0),
             stop,
             rand_seed,
         }
@@ -334,8 +448,42 @@ impl Shared {
         if let Some(rand_seed) = self.rand_seed {
             config.rand_seed = Some(rand_seed);
         }
-        let proxy_name = config.name.clone();
-        let (listener, proxy_info) = R::initialize_proxy::<L>(config, toxics).await?;
+        let proxy_info = match config.protocol {
+            Protocol::Tcp => {
+                let (listener, proxy_info) = R::initialize_proxy::<L>(config, toxics).await?;
+                self.launch_proxy(proxy_info, move |info, event_receiver, stop, closer| {
+                    R::run_proxy(listener, info, event_receiver, stop, closer)
+                })
+            }
+            Protocol::Udp => {
+                let (socket, proxy_info) = R::initialize_udp_proxy(config, toxics).await?;
+                self.launch_proxy(proxy_info, move |info, event_receiver, stop, closer| {
+                    R::run_udp_proxy(socket, info, event_receiver, stop, closer)
+                })
+            }
+        };
+
+        Ok(proxy_info)
+    }
+
+    /// Add the proxy handle to the state and spawn the proxy task, unless the proxy is disabled
+    fn launch_proxy<F, Fut>(
+        self: &Arc<Self>,
+        proxy_info: SharedProxyInfo,
+        run: F,
+    ) -> SharedProxyInfo
+    where
+        F: FnOnce(
+                SharedProxyInfo,
+                RequestReceiver<ToxicEvent, ToxicEventResult>,
+                Stop,
+                Closer,
+            ) -> Fut
+            + Send
+            + 'static,
+        Fut: Future<Output = io::Result<()>> + Send,
+    {
+        let proxy_name = proxy_info.config.name.clone();
         let info = proxy_info.clone();
         let shared = self.clone();
         let (stop, proxy_stopper) = self.stop.fork();
@@ -357,7 +505,7 @@ impl Shared {
         if info.config.enabled {
             tokio::spawn(async move {
                 debug!(proxy = ?&info.config, "Starting proxy");
-                let _ = R::run_proxy(listener, info, event_receiver, stop, closer).await;
+                let _ = run(info, event_receiver, stop, closer).await;
                 // Proxy task ended because of the stop signal, or an I/O error on accept.
                 // So we should self-clean by removing the proxy from the state, if
                 // the proxy in the state with the same name also has the same launch ID.
@@ -374,7 +522,7 @@ impl Shared {
             let _ = closer.close();
         }
 
-        Ok(proxy_info)
+        proxy_info
     }
 
     #[instrument(level = "debug", skip(self))]
@@ -391,6 +539,168 @@ impl Shared {
         }
     }
 
+    /// Sends the delayed event for the toxic after `start_after`, if given, then removes the toxic
+    /// after `expires_after`, if set. Toxics with a schedule are added and removed in every
+    /// active window instead. Replaces the pending timer for a toxic with the same name.
+    fn schedule_toxic(
+        self: &Arc<Self>,
+        proxy_name: String,
+        toxic: Toxic,
+        delayed: Option<fn(Toxic) -> ToxicEventKind>,
+    ) {
+        let key = (proxy_name, toxic.name.clone());
+        self.cancel_toxic_timer(&key.0, &key.1);
+        if delayed.is_none() && toxic.expires_after.is_none() && toxic.schedule.is_none() {
+            return;
+        }
+        let (mut stop, stopper) = self.stop.fork();
+        let timer_id = self.next_timer_id.fetch_add(1, Ordering::Relaxed);
+        let timer_toxic = Arc::new(Mutex::new(toxic.clone()));
+        self.get_state().toxic_timers.insert(
+            key.clone(),
+            ToxicTimer {
+                id: timer_id,
+                stopper,
+                toxic: timer_toxic.clone(),
+            },
+        );
+
+        let shared = self.clone();
+        tokio::spawn(async move {
+            let (proxy_name, toxic_name) = &key;
+            if let Some(schedule) = toxic.schedule {
+                if sleep_unless_stopped(toxic.start_after.unwrap_or(0), &mut stop).await {
+                    shared
+                        .run_toxic_schedule(proxy_name, &timer_toxic, schedule, &mut stop)
+                        .await;
+                }
+            } else {
+                let mut active = true;
+                if let Some(event_kind) = delayed {
+                    active = sleep_unless_stopped(toxic.start_after.unwrap_or(0), &mut stop).await
+                        && shared
+                            .send_toxic_event(proxy_name, event_kind(current_copy(&timer_toxic)))
+                            .await;
+                }
+                if let Some(expires_after) = toxic.expires_after {
+                    if active && sleep_unless_stopped(expires_after, &mut stop).await {
+                        let kind = ToxicEventKind::RemoveToxic(toxic_name.to_owned());
+                        shared.send_toxic_event(proxy_name, kind).await;
+                    }
+                }
+            }
+            let mut state = shared.get_state();
+            if matches!(state.toxic_timers.get(&key), Some(timer) if timer.id == timer_id) {
+                state.toxic_timers.remove(&key);
+            }
+        });
+    }
+
+    /// Adds the toxic at the start of every active window and removes it at the end,
+    /// until the schedule expires or the stop signal arrives
+    async fn run_toxic_schedule(
+        &self,
+        proxy_name: &str,
+        toxic: &Mutex<Toxic>,
+        schedule: ToxicSchedule,
+        stop: &mut Stop,
+    ) {
+        let mut window_start = Instant::now() + Duration::from_millis(schedule.offset);
+        let (toxic_name, expires_after) = {
+            let toxic = current_copy(toxic);
+            (toxic.name, toxic.expires_after)
+        };
+        let expiry =
+            expires_after.map(|expires_after| window_start + Duration::from_millis(expires_after));
+        while expiry.map_or(true, |expiry| window_start < expiry) {
+            if !sleep_until_unless_stopped(window_start, stop).await {
+                return;
+            }
+            let kind = ToxicEventKind::AddToxic(current_copy(toxic));
+            self.send_toxic_event(proxy_name, kind).await;
+
+            let mut window_end = window_start + Duration::from_millis(schedule.duration);
+            if let Some(expiry) = expiry {
+                window_end = window_end.min(expiry);
+            }
+            if !sleep_until_unless_stopped(window_end, stop).await {
+                return;
+            }
+            let kind = ToxicEventKind::RemoveToxic(toxic_name.clone());
+            self.send_toxic_event(proxy_name, kind).await;
+            window_start += Duration::from_millis(schedule.every);
+        }
+    }
+
+    /// Reject a toxic whose name is taken by an active or a pending toxic, or whose position
+    /// target is not in the chain. Deferred toxics reach the proxy later, so this is the
+    /// only chance to report these errors for them
+    fn check_new_toxic(&self, proxy_name: &str, toxic: &Toxic) -> Result<()> {
+        let state = self.get_state();
+        let handle = state
+            .proxies
+            .get(proxy_name)
+            .ok_or(StoreError::NotFound(ResourceKind::Proxy))?;
+        let key = (proxy_name.to_owned(), toxic.name.clone());
+        let proxy_state = handle.info.state.lock();
+        if proxy_state.toxics.find_by_name(&toxic.name).is_some()
+            || state.toxic_timers.contains_key(&key)
+        {
+            return Err(StoreError::AlreadyExists);
+        }
+        Ok(proxy_state.toxics.check_position(toxic)?)
+    }
+
+    /// Set the enabled flag of the toxic a pending timer or schedule adds, returns that toxic
+    fn set_pending_toxic_enabled(
+        &self,
+        proxy_name: &str,
+        toxic_name: &str,
+        enabled: bool,
+    ) -> Option<Toxic> {
+        let state = self.get_state();
+        let key = (proxy_name.to_owned(), toxic_name.to_owned());
+        let mut toxic = state
+            .toxic_timers
+            .get(&key)?
+            .toxic
+            .lock()
+            .expect("Failed to lock timer toxic, poison error");
+        toxic.enabled = enabled;
+        Some(toxic.clone())
+    }
+
+    fn cancel_toxic_timer(&self, proxy_name: &str, toxic_name: &str) {
+        self.get_state()
+            .cancel_toxic_timers(|(proxy, toxic)| proxy == proxy_name && toxic == toxic_name);
+    }
+
+    /// Sends a scheduled toxic event to the proxy, returns false if it failed
+    async fn send_toxic_event(&self, proxy_name: &str, kind: ToxicEventKind) -> bool {
+        let sender = match self.get_event_sender_for_proxy(proxy_name) {
+            Ok(sender) => sender,
+            Err(err) => {
+                warn!(proxy = ?proxy_name, event = ?&kind, err = ?err, "Scheduled toxic event failed");
+                return false;
+            }
+        };
+        let event = ToxicEvent::new(proxy_name.to_owned(), kind.clone());
+        match sender.send_receive(event).await {
+            Ok(Ok(())) => {
+                debug!(proxy = ?proxy_name, event = ?&kind, "Sent scheduled toxic event");
+                true
+            }
+            Ok(Err(err)) => {
+                warn!(proxy = ?proxy_name, event = ?&kind, err = ?err, "Scheduled toxic event failed");
+                false
+            }
+            Err(_) => {
+                warn!(proxy = ?proxy_name, event = ?&kind, "Proxy closed before the scheduled toxic event");
+                false
+            }
+        }
+    }
+
     fn get_event_sender_for_proxy(
         &self,
         proxy_name: &str,
@@ -409,14 +719,66 @@ impl State {
     fn new() -> Self {
         State {
             proxies: HashMap::new(),
+            toxic_timers: HashMap::new(),
+        }
+    }
+
+    /// Stop the pending timers and schedules for the matching (proxy name, toxic name) keys
+    fn cancel_toxic_timers<F>(&mut self, predicate: F)
+    where
+        F: Fn(&(String, String)) -> bool,
+    {
+        let keys: Vec<(String, String)> = self
+            .toxic_timers
+            .keys()
+            .filter(|key| predicate(key))
+            .cloned()
+            .collect();
+        for key in keys {
+            if let Some(timer) = self.toxic_timers.remove(&key) {
+                timer.stopper.stop();
+            }
         }
     }
 
+    /// The toxics the pending timers and schedules of the proxy add, sorted by name
+    fn pending_toxics(&self, proxy_name: &str) -> Vec<Toxic> {
+        let mut toxics: Vec<Toxic> = self
+            .toxic_timers
+            .iter()
+            .filter(|((proxy, _), _)| proxy == proxy_name)
+            .map(|(_, timer)| current_copy(&timer.toxic))
+            .collect();
+        toxics.sort_by(|a, b| a.name.cmp(&b.name));
+        toxics
+    }
+
     fn proxy_exists(&self, name: &str) -> bool {
         self.proxies.contains_key(name)
     }
 }
 
+/// The toxic a timer adds, as it is now
+fn current_copy(toxic: &Mutex<Toxic>) -> Toxic {
+    toxic
+        .lock()
+        .expect("Failed to lock timer toxic, poison error")
+        .clone()
+}
+
+/// Returns false if the stop signal arrives before the delay is over
+async fn sleep_unless_stopped(delay: u64, stop: &mut Stop) -> bool {
+    sleep_until_unless_stopped(Instant::now() + Duration::from_millis(delay), stop).await
+}
+
+/// Returns false if the stop signal arrives before the deadline
+async fn sleep_until_unless_stopped(deadline: Instant, stop: &mut Stop) -> bool {
+    tokio::select! {
+        _ = sleep_until(deadline) => true,
+        _ = stop.recv() => false,
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -424,12 +786,15 @@ pub mod tests {
     use bmrng::RequestReceiver;
     use lazy_static::lazy_static;
     use mockall::{mock, predicate::*};
-    use noxious::{signal::Closer, state::ProxyState};
     use noxious::{
+        error::ToxicUpdateError,
+        proxy::Framing,
         socket::{ReadStream, SocketListener, SocketStream, WriteStream},
         toxic::{StreamDirection, ToxicKind},
     };
+    use noxious::{signal::Closer, state::ProxyState};
     use std::{io, net::SocketAddr};
+    use tokio::net::UdpSocket;
     use tokio::sync::Mutex as AsyncMutex;
     use tokio_test::assert_ok;
 
@@ -445,6 +810,8 @@ pub mod tests {
                 Self: Sized;
 
             async fn accept(&self) -> io::Result<(MockNoopStream, SocketAddr)>;
+
+            fn local_addr(&self) -> io::Result<String>;
         }
     }
 
@@ -458,6 +825,8 @@ pub mod tests {
                 Self: Sized + 'static;
 
             fn into_split(self) -> (ReadStream, WriteStream);
+
+            fn local_addr(&self) -> Option<SocketAddr>;
         }
     }
 
@@ -486,6 +855,17 @@ pub mod tests {
                 Listener: SocketListener + 'static,
             {
             }
+            async fn initialize_udp_proxy(
+                config: ProxyConfig,
+                initial_toxics: Toxics,
+            ) -> io::Result<(UdpSocket, SharedProxyInfo)>;
+            async fn run_udp_proxy(
+                socket: UdpSocket,
+                proxy_info: SharedProxyInfo,
+                receiver: RequestReceiver<ToxicEvent, ToxicEventResult>,
+                stop: Stop,
+                closer: Closer,
+            ) -> io::Result<()>;
         }
     }
 
@@ -508,21 +888,27 @@ pub mod tests {
             listen: "127.0.0.1:5431".to_owned(),
             upstream: "127.0.0.1:5432".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: Some(3),
+            ..Default::default()
         };
         let config2 = ProxyConfig {
             name: "bar".to_owned(),
             listen: "127.0.0.1:27018".to_owned(),
             upstream: "127.0.0.1:27017".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: Some(3),
+            ..Default::default()
         };
         let config3 = ProxyConfig {
             name: "baz".to_owned(),
             listen: "127.0.0.1:8081".to_owned(),
             upstream: "127.0.0.1:8080".to_owned(),
             enabled: false,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         let configs = vec![config1, config2, config3];
         let _ = store
@@ -542,6 +928,7 @@ pub mod tests {
             let proxy_info = SharedProxyInfo {
                 state: Arc::new(ProxyState::new(initial_toxics)),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -572,7 +959,9 @@ pub mod tests {
                     listen: "127.0.0.1:5431".to_owned(),
                     upstream: "127.0.0.1:5432".to_owned(),
                     enabled: true,
+                    framing: Framing::Raw,
                     rand_seed: Some(3),
+                    ..Default::default()
                 })
                 .await
         );
@@ -585,6 +974,7 @@ pub mod tests {
                         name: "t1".to_owned(),
                         toxicity: 0.5,
                         direction: StreamDirection::Upstream,
+                        ..Default::default()
                     }
                 )
                 .await
@@ -594,6 +984,397 @@ pub mod tests {
         assert_ok!(done.recv().await);
     }
 
+    #[tokio::test]
+    async fn create_toxic_schedules_start_and_expiry() {
+        let _lock = MOCK_LOCK.lock().await;
+        tokio::time::pause();
+        let start = Instant::now();
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let init_ctx = MockNoopRunner::initialize_proxy_context();
+        let run_ctx = MockNoopRunner::run_proxy_context();
+        init_ctx.expect().returning(|config, initial_toxics| {
+            let listener = MockNoopListener::default();
+            let proxy_info = SharedProxyInfo {
+                state: Arc::new(ProxyState::new(initial_toxics)),
+                config: Arc::new(config),
+                listen_addr: None,
+            };
+            Ok((listener, proxy_info))
+        });
+
+        let (done, mark_done) = Close::new();
+        let st2 = store.clone();
+        run_ctx.expect().return_once_st(
+            move |_listener: MockNoopListener, info, mut event_receiver, _stop, closer| {
+                hack_handle_id(st2, &info);
+                tokio::spawn(async move {
+                    let (event, mut responder) = event_receiver.recv().await.expect("closed 1");
+                    assert!(matches!(event.kind, ToxicEventKind::AddToxic(ref toxic) if toxic.name == "t1"));
+                    assert!(start.elapsed() >= Duration::from_millis(20));
+                    assert_ok!(responder.respond(Ok(())));
+                    let (event, mut responder) = event_receiver.recv().await.expect("closed 2");
+                    assert_eq!(event.kind, ToxicEventKind::RemoveToxic("t1".to_owned()));
+                    assert!(start.elapsed() >= Duration::from_millis(40));
+                    assert_ok!(responder.respond(Ok(())));
+                    assert_ok!(mark_done.close());
+                    assert_ok!(closer.close());
+                });
+                Ok(())
+            },
+        );
+
+        assert_ok!(
+            store
+                .create_proxy::<MockNoopListener, MockNoopRunner>(ProxyConfig {
+                    name: "foo".to_owned(),
+                    listen: "127.0.0.1:5431".to_owned(),
+                    upstream: "127.0.0.1:5432".to_owned(),
+                    enabled: true,
+                    framing: Framing::Raw,
+                    rand_seed: Some(3),
+                    ..Default::default()
+                })
+                .await
+        );
+        assert_ok!(
+            store
+                .create_toxic(
+                    "foo".into(),
+                    Toxic {
+                        kind: ToxicKind::Latency {
+                            latency: 100,
+                            jitter: 0,
+                        },
+                        name: "t1".to_owned(),
+                        toxicity: 1.0,
+                        direction: StreamDirection::Upstream,
+                        start_after: Some(20),
+                        expires_after: Some(20),
+                        ..Default::default()
+                    }
+                )
+                .await
+        );
+        assert_eq!(1, store.shared.get_state().toxic_timers.len());
+        // The deferred toxic is listed before the proxy has it
+        let pending = store.get_toxic("foo", "t1").await.unwrap();
+        assert_eq!(Some(20), pending.start_after);
+        assert_eq!(vec![pending], store.get_toxics("foo").await.unwrap());
+
+        assert_ok!(done.recv().await);
+        tokio::time::resume();
+    }
+
+    #[tokio::test]
+    async fn create_toxic_runs_schedule_until_expiry() {
+        let _lock = MOCK_LOCK.lock().await;
+        tokio::time::pause();
+        let start = Instant::now();
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let init_ctx = MockNoopRunner::initialize_proxy_context();
+        let run_ctx = MockNoopRunner::run_proxy_context();
+        init_ctx.expect().returning(|config, initial_toxics| {
+            let listener = MockNoopListener::default();
+            let proxy_info = SharedProxyInfo {
+                state: Arc::new(ProxyState::new(initial_toxics)),
+                config: Arc::new(config),
+                listen_addr: None,
+            };
+            Ok((listener, proxy_info))
+        });
+
+        let (done, mark_done) = Close::new();
+        let st2 = store.clone();
+        run_ctx.expect().return_once_st(
+            move |_listener: MockNoopListener, info, mut event_receiver, _stop, closer| {
+                hack_handle_id(st2, &info);
+                tokio::spawn(async move {
+                    // Two windows, then the schedule expires
+                    for window in 0..2 {
+                        let window_start = Duration::from_millis(40 * window);
+                        let (event, mut responder) = event_receiver.recv().await.expect("closed");
+                        assert!(matches!(event.kind, ToxicEventKind::AddToxic(_)));
+                        assert!(start.elapsed() >= window_start);
+                        assert_ok!(responder.respond(Ok(())));
+                        let (event, mut responder) = event_receiver.recv().await.expect("closed");
+                        assert_eq!(event.kind, ToxicEventKind::RemoveToxic("t1".to_owned()));
+                        assert!(start.elapsed() >= window_start + Duration::from_millis(10));
+                        assert_ok!(responder.respond(Ok(())));
+                    }
+                    assert_ok!(mark_done.close());
+                    assert_ok!(closer.close());
+                });
+                Ok(())
+            },
+        );
+
+        assert_ok!(
+            store
+                .create_proxy::<MockNoopListener, MockNoopRunner>(ProxyConfig {
+                    name: "foo".to_owned(),
+                    listen: "127.0.0.1:5431".to_owned(),
+                    upstream: "127.0.0.1:5432".to_owned(),
+                    enabled: true,
+                    framing: Framing::Raw,
+                    rand_seed: Some(3),
+                    ..Default::default()
+                })
+                .await
+        );
+        assert_ok!(
+            store
+                .create_toxic(
+                    "foo".into(),
+                    Toxic {
+                        kind: ToxicKind::Noop,
+                        name: "t1".to_owned(),
+                        toxicity: 1.0,
+                        direction: StreamDirection::Upstream,
+                        expires_after: Some(50),
+                        schedule: Some(ToxicSchedule {
+                            every: 40,
+                            duration: 10,
+                            offset: 0,
+                        }),
+                        ..Default::default()
+                    }
+                )
+                .await
+        );
+
+        assert_ok!(done.recv().await);
+        // Give the schedule task time to clean up after itself
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(store.shared.get_state().toxic_timers.is_empty());
+        tokio::time::resume();
+    }
+
+    #[tokio::test]
+    async fn create_toxic_rejects_missing_position_target() {
+        let _lock = MOCK_LOCK.lock().await;
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let init_ctx = MockNoopRunner::initialize_proxy_context();
+        let run_ctx = MockNoopRunner::run_proxy_context();
+        init_ctx.expect().returning(|config, initial_toxics| {
+            let listener = MockNoopListener::default();
+            let proxy_info = SharedProxyInfo {
+                state: Arc::new(ProxyState::new(initial_toxics)),
+                config: Arc::new(config),
+                listen_addr: None,
+            };
+            Ok((listener, proxy_info))
+        });
+
+        let st2 = store.clone();
+        run_ctx.expect().return_once_st(
+            move |_listener: MockNoopListener, info, mut event_receiver, _stop, closer| {
+                hack_handle_id(st2, &info);
+                tokio::spawn(async move {
+                    let (_event, mut responder) = event_receiver.recv().await.expect("closed");
+                    assert_ok!(responder.respond(Err(ToxicUpdateError::PositionNotFound)));
+                    assert_ok!(closer.close());
+                });
+                Ok(())
+            },
+        );
+
+        assert_ok!(
+            store
+                .create_proxy::<MockNoopListener, MockNoopRunner>(ProxyConfig {
+                    name: "foo".to_owned(),
+                    listen: "127.0.0.1:5431".to_owned(),
+                    upstream: "127.0.0.1:5432".to_owned(),
+                    ..Default::default()
+                })
+                .await
+        );
+        let mut toxic = Toxic {
+            kind: ToxicKind::Noop,
+            name: "t1".to_owned(),
+            ..Default::default()
+        };
+        toxic.position.before = Some("missing".to_owned());
+        assert_eq!(
+            Err(StoreError::InvalidToxicPosition),
+            store.create_toxic("foo".into(), toxic).await
+        );
+    }
+
+    #[tokio::test]
+    async fn disable_keeps_scheduled_toxic_disabled() {
+        let _lock = MOCK_LOCK.lock().await;
+        tokio::time::pause();
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let init_ctx = MockNoopRunner::initialize_proxy_context();
+        let run_ctx = MockNoopRunner::run_proxy_context();
+        init_ctx.expect().returning(|config, initial_toxics| {
+            let listener = MockNoopListener::default();
+            let proxy_info = SharedProxyInfo {
+                state: Arc::new(ProxyState::new(initial_toxics)),
+                config: Arc::new(config),
+                listen_addr: None,
+            };
+            Ok((listener, proxy_info))
+        });
+
+        let (done, mark_done) = Close::new();
+        let st2 = store.clone();
+        run_ctx.expect().return_once_st(
+            move |_listener: MockNoopListener, info, mut event_receiver, _stop, closer| {
+                hack_handle_id(st2, &info);
+                tokio::spawn(async move {
+                    // The toxic is not in the proxy before its first window
+                    let (event, mut responder) = event_receiver.recv().await.expect("closed");
+                    assert_eq!(
+                        event.kind,
+                        ToxicEventKind::SetToxicEnabled("t1".to_owned(), false)
+                    );
+                    assert_ok!(responder.respond(Err(ToxicUpdateError::NotFound)));
+                    let (event, mut responder) = event_receiver.recv().await.expect("closed");
+                    assert!(
+                        matches!(event.kind, ToxicEventKind::AddToxic(ref toxic) if !toxic.enabled)
+                    );
+                    assert_ok!(responder.respond(Ok(())));
+                    assert_ok!(mark_done.close());
+                    assert_ok!(closer.close());
+                });
+                Ok(())
+            },
+        );
+
+        assert_ok!(
+            store
+                .create_proxy::<MockNoopListener, MockNoopRunner>(ProxyConfig {
+                    name: "foo".to_owned(),
+                    listen: "127.0.0.1:5431".to_owned(),
+                    upstream: "127.0.0.1:5432".to_owned(),
+                    ..Default::default()
+                })
+                .await
+        );
+        assert_ok!(
+            store
+                .create_toxic(
+                    "foo".into(),
+                    Toxic {
+                        kind: ToxicKind::Noop,
+                        name: "t1".to_owned(),
+                        schedule: Some(ToxicSchedule {
+                            every: 40,
+                            duration: 10,
+                            offset: 100,
+                        }),
+                        ..Default::default()
+                    }
+                )
+                .await
+        );
+        let toxic = store
+            .set_toxic_enabled("foo".into(), "t1".into(), false)
+            .await
+            .unwrap();
+        assert!(!toxic.enabled);
+        assert_eq!(toxic, store.get_toxic("foo", "t1").await.unwrap());
+        assert_eq!(vec![toxic], store.get_toxics("foo").await.unwrap());
+
+        assert_ok!(done.recv().await);
+        tokio::time::resume();
+    }
+
+    #[tokio::test]
+    async fn create_proxy_reports_bound_address() {
+        let _lock = MOCK_LOCK.lock().await;
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let init_ctx = MockNoopRunner::initialize_proxy_context();
+        let run_ctx = MockNoopRunner::run_proxy_context();
+        init_ctx.expect().returning(|config, initial_toxics| {
+            let mut listener = MockNoopListener::default();
+            listener
+                .expect_local_addr()
+                .returning(|| Ok("127.0.0.1:40123".to_owned()));
+            // Like ProxyRunner, report the address the listener is bound to
+            let proxy_info = SharedProxyInfo {
+                state: Arc::new(ProxyState::new(initial_toxics)),
+                config: Arc::new(config),
+                listen_addr: Some(listener.local_addr()?),
+            };
+            Ok((listener, proxy_info))
+        });
+        let st2 = store.clone();
+        run_ctx.expect().return_once_st(
+            move |_listener: MockNoopListener, info, _event_receiver, _stop, _closer| {
+                hack_handle_id(st2, &info);
+                Ok(())
+            },
+        );
+
+        let created = store
+            .create_proxy::<MockNoopListener, MockNoopRunner>(ProxyConfig {
+                name: "eph".to_owned(),
+                listen: "127.0.0.1:0".to_owned(),
+                upstream: "127.0.0.1:5432".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!("127.0.0.1:40123", created.proxy.listen);
+        let body = serde_json::to_value(&created).unwrap();
+        assert_eq!("127.0.0.1:40123", body["listen"]);
+        let proxy = store.get_proxy("eph").await.unwrap();
+        assert_eq!("127.0.0.1:40123", proxy.proxy.listen);
+    }
+
+    #[tokio::test]
+    async fn create_udp_proxy_with_runner() {
+        let _lock = MOCK_LOCK.lock().await;
+        let (stop, _stopper) = Stop::new();
+        let store = Store::new(stop, None);
+        let init_ctx = MockNoopRunner::initialize_proxy_context();
+        let init_udp_ctx = MockNoopRunner::initialize_udp_proxy_context();
+        let run_udp_ctx = MockNoopRunner::run_udp_proxy_context();
+        init_ctx.expect::<MockNoopListener>().never();
+        init_udp_ctx.expect().returning(|config, initial_toxics| {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+            socket.set_nonblocking(true)?;
+            let socket = UdpSocket::from_std(socket)?;
+            let proxy_info = SharedProxyInfo {
+                state: Arc::new(ProxyState::new(initial_toxics)),
+                config: Arc::new(config),
+                listen_addr: Some(socket.local_addr()?.to_string()),
+            };
+            Ok((socket, proxy_info))
+        });
+        let (done, mark_done) = Close::new();
+        let st2 = store.clone();
+        run_udp_ctx.expect().return_once_st(
+            move |_socket, info, _event_receiver, _stop, _closer| {
+                hack_handle_id(st2, &info);
+                assert_ok!(mark_done.close());
+                Ok(())
+            },
+        );
+
+        let created = store
+            .create_proxy::<MockNoopListener, MockNoopRunner>(ProxyConfig {
+                name: "dns".to_owned(),
+                listen: "127.0.0.1:0".to_owned(),
+                upstream: "127.0.0.1:53".to_owned(),
+                protocol: Protocol::Udp,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(Protocol::Udp, created.proxy.protocol);
+        assert_ne!("127.0.0.1:0", created.proxy.listen);
+        assert_ok!(done.recv().await);
+        assert!(store.get_proxy("dns").await.is_ok());
+    }
+
     #[tokio::test]
     async fn populate_proxies() {
         let _lock = MOCK_LOCK.lock().await;
@@ -604,21 +1385,27 @@ pub mod tests {
             listen: "127.0.0.1:5431".to_owned(),
             upstream: "127.0.0.1:5432".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: Some(3),
+            ..Default::default()
         };
         let config2 = ProxyConfig {
             name: "bar".to_owned(),
             listen: "127.0.0.1:27018".to_owned(),
             upstream: "127.0.0.1:27017".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: Some(3),
+            ..Default::default()
         };
         let config3 = ProxyConfig {
             name: "baz".to_owned(),
             listen: "127.0.0.1:8081".to_owned(),
             upstream: "127.0.0.1:8080".to_owned(),
             enabled: false,
+            framing: Framing::Raw,
             rand_seed: None,
+            ..Default::default()
         };
         let init_ctx = MockNoopRunner::initialize_proxy_context();
         let run_ctx = MockNoopRunner::run_proxy_context();
@@ -627,6 +1414,7 @@ pub mod tests {
             let proxy_info = SharedProxyInfo {
                 state: Arc::new(ProxyState::new(initial_toxics)),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -661,7 +1449,9 @@ pub mod tests {
             listen: "127.0.0.1:5431".to_owned(),
             upstream: "127.0.0.1:5432".to_owned(),
             enabled: true,
+            framing: Framing::Raw,
             rand_seed: Some(3),
+            ..Default::default()
         };
         let init_ctx = MockNoopRunner::initialize_proxy_context();
         let run_ctx = MockNoopRunner::run_proxy_context();
@@ -670,6 +1460,7 @@ pub mod tests {
             let proxy_info = SharedProxyInfo {
                 state: Arc::new(ProxyState::new(initial_toxics)),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -710,10 +1501,12 @@ pub mod tests {
                         name: format!("{}tox1", config.name),
                         toxicity: 0.67,
                         direction: StreamDirection::Upstream,
+                        ..Default::default()
                     }],
                     downstream: Vec::new(),
                 })),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -751,10 +1544,12 @@ pub mod tests {
                         name: format!("{}tox1", config.name),
                         toxicity: 0.5,
                         direction: StreamDirection::Upstream,
+                        ..Default::default()
                     }],
                     downstream: Vec::new(),
                 })),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -790,7 +1585,9 @@ pub mod tests {
                         listen: "127.0.0.1:27018".to_owned(),
                         upstream: "127.0.0.1:27017".to_owned(),
                         enabled: true,
+                        framing: Framing::Raw,
                         rand_seed: None,
+                        ..Default::default()
                     }
                 )
                 .await
@@ -814,10 +1611,12 @@ pub mod tests {
                         name: format!("{}tox!", config.name),
                         toxicity: 0.5,
                         direction: StreamDirection::Upstream,
+                        ..Default::default()
                     }],
                     downstream: Vec::new(),
                 })),
                 config: Arc::new(config),
+                listen_addr: None,
             };
             Ok((listener, proxy_info))
         });
@@ -840,7 +1639,9 @@ pub mod tests {
                     listen: "127.0.0.1:5431".to_owned(),
                     upstream: "127.0.0.1:5432".to_owned(),
                     enabled: true,
+                    framing: Framing::Raw,
                     rand_seed: Some(5),
+                    ..Default::default()
                 })
                 .await
         );
@@ -855,7 +1656,9 @@ pub mod tests {
                     listen: "127.0.0.1:5431".to_owned(),
                     upstream: "127.0.0.1:5432".to_owned(),
                     enabled: false,
+                    framing: Framing::Raw,
                     rand_seed: None,
+                    ..Default::default()
                 },
             )
             .await